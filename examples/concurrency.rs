@@ -1,9 +1,8 @@
-use std::{net::ToSocketAddrs, sync::Arc, time::SystemTime};
+use std::{sync::Arc, time::SystemTime};
 
 use rustls::{
     client::ServerCertVerified, client::ServerCertVerifier, Certificate, ClientConfig, ServerName,
 };
-use tokio_uring::net::TcpStream;
 use tokio_uring_rustls::{TlsConnector, split};
 pub struct NoCertificateVerification {}
 
@@ -34,18 +33,8 @@ fn main() {
     let connector = TlsConnector::from(std::sync::Arc::new(config));
 
     tokio_uring::start(async move {
-        let socket = TcpStream::connect(
-            ("www.google.com", 443)
-                .to_socket_addrs()
-                .unwrap()
-                .next()
-                .unwrap(),
-        )
-        .await
-        .unwrap();
-
         let stream = connector
-            .connect("www.google.com".try_into().unwrap(), socket)
+            .connect_to("www.google.com", 443)
             .await
             .unwrap();
 
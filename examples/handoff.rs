@@ -0,0 +1,91 @@
+use std::{os::unix::net::UnixStream, sync::Arc, thread};
+
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+use tokio_uring::net::TcpStream;
+use tokio_uring_rustls::{ExportedStream, ResumedStream, TlsAcceptor, TlsConnector};
+
+// Migrates a live TLS connection from one tokio-uring runtime to another. The two server threads stand in for
+// the old and the new process of a hot restart, connected through a Unix socket.
+fn main() {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert_der = Certificate(cert.serialize_der().unwrap());
+    let key = PrivateKey(cert.serialize_private_key_der());
+
+    let mut server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert_der.clone()], key)
+        .unwrap();
+    server_config.enable_secret_extraction = true;
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+    let mut roots = RootCertStore::empty();
+    roots.add(&cert_der).unwrap();
+    let client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(client_config));
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (old_process, new_process) = UnixStream::pair().unwrap();
+
+    // The old process accepts the connection, serves one request, and hands the connection over.
+    let old = thread::spawn(move || {
+        tokio_uring::start(async move {
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = acceptor.accept(TcpStream::from_std(socket)).await.unwrap();
+
+            let (res, buf) = stream.read(vec![0u8; 1024]).await;
+            let n = res.unwrap();
+            println!("old process read: {:?}", std::str::from_utf8(&buf[..n]));
+
+            let (res, _) = stream.write_all("served by the old process").await;
+            res.unwrap();
+
+            let exported = stream.export().await.unwrap();
+            println!(
+                "handing off a {:?} connection",
+                exported.state.protocol_version()
+            );
+            exported.send(&old_process).unwrap();
+        })
+    });
+
+    // The new process picks the connection up and continues where the old one stopped.
+    let new = thread::spawn(move || {
+        let exported = ExportedStream::recv(&new_process).unwrap();
+        tokio_uring::start(async move {
+            let mut stream = ResumedStream::new(exported);
+
+            let (res, buf) = stream.read(vec![0u8; 1024]).await;
+            let n = res.unwrap();
+            println!("new process read: {:?}", std::str::from_utf8(&buf[..n]));
+
+            let (res, _) = stream.write_all("served by the new process").await;
+            res.unwrap();
+            stream.shutdown().await.unwrap();
+        })
+    });
+
+    tokio_uring::start(async move {
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector
+            .connect("localhost".try_into().unwrap(), socket)
+            .await
+            .unwrap();
+
+        for request in ["first request", "second request"] {
+            let (res, _) = stream.write_all(request).await;
+            res.unwrap();
+
+            let (res, buf) = stream.read(vec![0u8; 1024]).await;
+            let n = res.unwrap();
+            println!("client read: {:?}", std::str::from_utf8(&buf[..n]));
+        }
+    });
+
+    old.join().unwrap();
+    new.join().unwrap();
+}
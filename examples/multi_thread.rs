@@ -0,0 +1,69 @@
+use std::{sync::Arc, time::Duration};
+
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_uring_rustls::{Drain, TlsAcceptor, TlsServer};
+
+// Serves a self signed hello world on every core until enter is pressed.
+fn main() {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![Certificate(cert.serialize_der().unwrap())],
+            PrivateKey(cert.serialize_private_key_der()),
+        )
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    // Connections served through the drain get 5 seconds to finish on shutdown, then they are closed
+    let drain = Drain::new();
+    let handler_drain = drain.clone();
+
+    let server = TlsServer::new("0.0.0.0:8443".parse().unwrap(), acceptor)
+        .on_error(|addr, e| println!("Handshake with {} failed: {}", addr, e))
+        .drain(&drain, Duration::from_secs(5))
+        .start(move |stream, addr| {
+            let drain = handler_drain.clone();
+            async move {
+                println!(
+                    "Serving {} on {}",
+                    addr,
+                    std::thread::current().name().unwrap_or_default()
+                );
+
+                let serving = drain.clone();
+                let forced = drain
+                    .serve(stream, move |stream| {
+                        Box::pin(async move {
+                            // Serve requests until the client goes away or the server shuts down
+                            while !serving.is_draining() {
+                                let (res, _) = stream.read(vec![0u8; 1024]).await;
+                                if !matches!(res, Ok(n) if n > 0) {
+                                    return;
+                                }
+
+                                let data =
+                                    "HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello world";
+                                let (res, _) = stream.write_all(data.as_bytes()).await;
+                                if res.is_err() {
+                                    return;
+                                }
+                            }
+                        })
+                    })
+                    .await;
+
+                if forced {
+                    println!("Closed {} after the grace period", addr);
+                }
+            }
+        })
+        .unwrap();
+
+    println!("Listening on {}, press enter to stop", server.local_addr());
+    std::io::stdin().read_line(&mut String::new()).unwrap();
+
+    server.shutdown();
+    server.join();
+}
@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+use tokio_uring::net::{TcpListener, TcpStream};
+use tokio_uring_rustls::{starttls, TlsAcceptor, TlsConnector};
+
+// Upgrades a connection with each supported protocol, the client and the server side both run locally.
+fn main() {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let der = cert.serialize_der().unwrap();
+
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![Certificate(der.clone())],
+            PrivateKey(cert.serialize_private_key_der()),
+        )
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+    let mut roots = RootCertStore::empty();
+    roots.add(&Certificate(der)).unwrap();
+    let client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(client_config));
+
+    tokio_uring::start(async move {
+        for protocol in ["smtp", "imap", "pop3", "postgres", "ldap"] {
+            let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let acceptor = acceptor.clone();
+            let server = tokio_uring::spawn(async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                let stream = match protocol {
+                    "smtp" => starttls::smtp::accept(&acceptor, socket, "localhost").await,
+                    "imap" => starttls::imap::accept(&acceptor, socket).await,
+                    "pop3" => starttls::pop3::accept(&acceptor, socket).await,
+                    "postgres" => starttls::postgres::accept(&acceptor, socket).await,
+                    _ => starttls::ldap::accept(&acceptor, socket).await,
+                };
+
+                let mut stream = stream.unwrap();
+                let (res, buf) = stream.read(vec![0u8; 64]).await;
+                let n = res.unwrap();
+                stream.write_all(buf[..n].to_vec()).await.0.unwrap();
+            });
+
+            let socket = TcpStream::connect(addr).await.unwrap();
+            let domain = "localhost".try_into().unwrap();
+            let stream = match protocol {
+                "smtp" => starttls::smtp::connect(&connector, domain, socket, "localhost").await,
+                "imap" => starttls::imap::connect(&connector, domain, socket).await,
+                "pop3" => starttls::pop3::connect(&connector, domain, socket).await,
+                "postgres" => starttls::postgres::connect(&connector, domain, socket).await,
+                _ => starttls::ldap::connect(&connector, domain, socket).await,
+            };
+
+            let mut stream = stream.unwrap();
+            stream.write_all(b"ping".to_vec()).await.0.unwrap();
+            let (res, buf) = stream.read(vec![0u8; 64]).await;
+            let n = res.unwrap();
+            println!(
+                "{}: upgraded, echoed {:?}",
+                protocol,
+                String::from_utf8_lossy(&buf[..n])
+            );
+
+            server.await.unwrap();
+        }
+    });
+}
@@ -0,0 +1,136 @@
+use crate::{server::TlsAcceptor, stream::TlsStream};
+
+use rustls::{server::ClientHello, ServerConfig, ServerConnection};
+use std::{
+    future::{self, Future},
+    io::{self, Error, ErrorKind},
+    pin::Pin,
+    sync::Arc,
+};
+use tokio_uring::net::TcpStream;
+
+/// A fatal `no_application_protocol` alert, sent in plaintext as it goes out before the handshake.
+const NO_APPLICATION_PROTOCOL: [u8; 7] = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x78];
+
+type Handler = Box<dyn Fn(TlsStream<ServerConnection>) -> Pin<Box<dyn Future<Output = ()>>>>;
+
+/// Hands accepted connections to a handler chosen by the protocol negotiated through ALPN.
+///
+/// Protocols are preferred in the order they are added. Clients that offer none of them, or don't use ALPN at
+/// all, are rejected with a `no_application_protocol` alert before the handshake.
+///
+/// ```ignore
+/// let dispatcher = AlpnDispatcher::new()
+///     .protocol(b"h2", |stream| async move { /* ... */ })
+///     .protocol(b"http/1.1", |stream| async move { /* ... */ });
+///
+/// dispatcher.serve(&acceptor, socket).await?;
+/// ```
+#[derive(Default)]
+pub struct AlpnDispatcher {
+    protocols: Vec<Vec<u8>>,
+    handlers: Vec<Handler>,
+}
+
+impl AlpnDispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a handler for the connections that negotiate `protocol`.
+    pub fn protocol<H, F>(mut self, protocol: &[u8], handler: H) -> Self
+    where
+        H: Fn(TlsStream<ServerConnection>) -> F + 'static,
+        F: Future<Output = ()> + 'static,
+    {
+        self.protocols.push(protocol.to_vec());
+        self.handlers
+            .push(Box::new(move |stream| Box::pin(handler(stream))));
+        self
+    }
+
+    /// The protocols with a handler, in order of preference, as they go in
+    /// [`ServerConfig::alpn_protocols`].
+    pub fn alpn_protocols(&self) -> &[Vec<u8>] {
+        &self.protocols
+    }
+
+    /// Accepts a connection with the acceptor and runs the handler for the negotiated protocol.
+    ///
+    /// Returns once the handler is done, or with an error if the client was rejected or the handshake failed.
+    pub async fn serve(&self, acceptor: &TlsAcceptor, socket: TcpStream) -> io::Result<()> {
+        let config = acceptor.config();
+        self.serve_with(acceptor, socket, move |_| future::ready(Ok(config)))
+            .await
+    }
+
+    /// Like [`AlpnDispatcher::serve`], with the config chosen based on the `ClientHello`, see
+    /// [`TlsAcceptor::accept_lazy`]. The settings of the acceptor, such as its ticket keys, apply to the chosen
+    /// config.
+    ///
+    /// `select` looks at the `ClientHello` and returns a future resolving to the config, so it can be fetched
+    /// from elsewhere, e.g. by server name. The future can't borrow the `ClientHello`, copy out what it needs.
+    /// An error from it fails the accept.
+    ///
+    /// The protocols of the dispatcher replace those of the config. Setting them on the config up front, through
+    /// [`AlpnDispatcher::alpn_protocols`], saves a copy of it for every connection.
+    pub async fn serve_with<F, S>(
+        &self,
+        acceptor: &TlsAcceptor,
+        socket: TcpStream,
+        select: F,
+    ) -> io::Result<()>
+    where
+        F: FnOnce(&ClientHello<'_>) -> S,
+        S: Future<Output = io::Result<Arc<ServerConfig>>>,
+    {
+        let start = acceptor.accept_lazy(socket).await?;
+
+        let hello = start.client_hello();
+        let offered = hello
+            .alpn()
+            .is_some_and(|mut offered| offered.any(|p| self.protocols.iter().any(|q| q == p)));
+        if !offered {
+            let socket = start.into_inner();
+            let _ = socket.write_all(NO_APPLICATION_PROTOCOL.to_vec()).await;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "client offered no supported application protocol",
+            ));
+        }
+
+        let mut config = select(&hello).await?;
+        if config.alpn_protocols != self.protocols {
+            let mut copy = (*config).clone();
+            copy.alpn_protocols = self.protocols.clone();
+            config = Arc::new(copy);
+        }
+
+        let stream = start.into_stream(config).await?;
+        self.dispatch(stream).await
+    }
+
+    /// Runs the handler for the protocol negotiated on an established connection.
+    ///
+    /// This is for connections accepted by other means, e.g. a [`TlsListener`](crate::TlsListener), whose
+    /// config has to carry [`AlpnDispatcher::alpn_protocols`]. Rustls rejects clients that offer none of them
+    /// with the alert, but clients that don't use ALPN can only be disconnected here, with an error.
+    pub async fn dispatch(&self, stream: TlsStream<ServerConnection>) -> io::Result<()> {
+        let handler = stream
+            .session
+            .alpn_protocol()
+            .and_then(|p| self.protocols.iter().position(|q| q == p))
+            .map(|i| &self.handlers[i]);
+
+        match handler {
+            Some(handler) => {
+                handler(stream).await;
+                Ok(())
+            }
+            None => Err(Error::new(
+                ErrorKind::InvalidData,
+                "no application protocol negotiated",
+            )),
+        }
+    }
+}
@@ -7,6 +7,48 @@ use tokio_uring::{
 
 const BUFFER_SIZE: usize = 8 * 1024;
 
+/// Size of a TLS record header.
+const RECORD_HEADER_SIZE: usize = 5;
+
+/// The read buffer must be able to hold the largest record a peer may send, header plus 2^14 bytes of data
+/// plus the maximum expansion TLS 1.2 allows.
+const READ_BUFFER_SIZE: usize = RECORD_HEADER_SIZE + (1 << 14) + 2048;
+
+/// The error for an adaptor whose buffer went away with a read or write operation that was cancelled, e.g. by
+/// dropping the future driving it.
+///
+/// The operation still completes in the background with the buffer, so there is no telling which bytes made it
+/// through, and nothing more can safely be sent or received on the stream.
+fn lost_buffer() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "stream unusable after a cancelled read or write",
+    )
+}
+
+/// Returns the length of the complete TLS records at the start of `data`.
+///
+/// Anything that does not look like a TLS record, e.g. a plaintext request sent to a TLS port, is treated as
+/// complete, so that rustls rejects it right away instead of waiting for a record that will never arrive.
+fn complete_records(data: &[u8]) -> usize {
+    let mut n = 0;
+    while data.len() - n >= RECORD_HEADER_SIZE {
+        let header = &data[n..n + RECORD_HEADER_SIZE];
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        if !(20..=24).contains(&header[0])
+            || header[1] != 3
+            || len > READ_BUFFER_SIZE - RECORD_HEADER_SIZE
+        {
+            return data.len();
+        }
+        if data.len() - n < RECORD_HEADER_SIZE + len {
+            break;
+        }
+        n += RECORD_HEADER_SIZE + len;
+    }
+    n
+}
+
 /// The following snippet of codes is directly copied from:
 /// https://github.com/monoio-rs/monoio-tls/blob/master/monoio-rustls/src/safe_io.rs#L10
 ///
@@ -25,21 +67,27 @@ struct RingBuffer {
 
 impl RingBuffer {
     fn with_capacity(size: usize) -> Self {
-        let mut buf = Vec::<u8>::with_capacity(size);
-        
-        // Safety: size always equals the capacity
-        unsafe {
-            buf.set_len(size);
-        }
-
         Self {
             read: 0,
             write: 0,
-            buf: buf.into_boxed_slice(),
+            buf: vec![0u8; size].into_boxed_slice(),
             capacity: size,
         }
     }
 
+    /// Creates a buffer holding a copy of `data`, growing past `size` if needed.
+    fn from_slice(size: usize, data: &[u8]) -> Self {
+        let mut buffer = Self::with_capacity(size.max(data.len()));
+        buffer.buf[..data.len()].copy_from_slice(data);
+        buffer.write = data.len();
+        buffer
+    }
+
+    #[inline]
+    fn as_slice(&self) -> &[u8] {
+        &self.buf[self.read..self.write]
+    }
+
     #[inline]
     fn len(&self) -> usize {
         self.write - self.read
@@ -60,6 +108,16 @@ impl RingBuffer {
         self.available() == 0
     }
 
+    /// Moves the unread data to the front of the buffer, making room for more data behind it.
+    fn compact(&mut self) {
+        if self.read == 0 {
+            return;
+        }
+        self.buf.copy_within(self.read..self.write, 0);
+        self.write -= self.read;
+        self.read = 0;
+    }
+
     fn advance(&mut self, n: usize) {
         assert!(self.write - self.read >= n);
         self.read += n;
@@ -104,30 +162,71 @@ enum ReadStatus {
 pub(crate) struct SyncReadAdaptor {
     buffer: Option<RingBuffer>,
     status: ReadStatus,
+    recorded: Option<Vec<u8>>,
 }
 
 impl Default for SyncReadAdaptor {
     fn default() -> Self {
         Self {
-            buffer: Some(RingBuffer::with_capacity(BUFFER_SIZE)),
+            buffer: Some(RingBuffer::with_capacity(READ_BUFFER_SIZE)),
             status: ReadStatus::Ok,
+            recorded: None,
         }
     }
 }
 
 impl SyncReadAdaptor {
-    pub(crate) async fn do_io(&mut self, io: &mut TcpStream) -> io::Result<usize> {
-        // Take the reference of the buffer. We already expect the buffer to be present instead of None
-        let buffer = self.buffer.as_ref().expect("bug: buffer ref expected");
+    /// Creates a read adaptor that hands out `data` before reading anything from the socket.
+    pub(crate) fn with_buffered(data: &[u8]) -> Self {
+        Self {
+            buffer: Some(RingBuffer::from_slice(READ_BUFFER_SIZE, data)),
+            status: ReadStatus::Ok,
+            recorded: None,
+        }
+    }
 
-        // If there are some data inside the buffer, just return.
-        if !buffer.is_empty() {
+    /// Returns the bytes read from the socket that have not been consumed yet.
+    pub(crate) fn buffered(&self) -> io::Result<&[u8]> {
+        self.buffer
+            .as_ref()
+            .map(RingBuffer::as_slice)
+            .ok_or_else(lost_buffer)
+    }
+
+    /// Starts keeping a copy of every byte read from the socket, starting with the bytes already buffered.
+    pub(crate) fn start_recording(&mut self) {
+        // A lost buffer fails the next read, there is nothing to record then.
+        self.recorded = Some(self.buffered().unwrap_or_default().to_vec());
+    }
+
+    /// The bytes recorded so far, `None` if not recording.
+    pub(crate) fn recorded(&self) -> Option<&[u8]> {
+        self.recorded.as_deref()
+    }
+
+    /// Stops recording and returns the bytes recorded since [`SyncReadAdaptor::start_recording`].
+    pub(crate) fn take_recorded(&mut self) -> Vec<u8> {
+        self.recorded.take().unwrap_or_default()
+    }
+
+    pub(crate) async fn do_io(&mut self, io: &TcpStream) -> io::Result<usize> {
+        // The buffer is only missing if a previous read was cancelled while in flight.
+        let buffer = self.buffer.as_mut().ok_or_else(lost_buffer)?;
+
+        // If there is a complete record inside the buffer, just return. The same goes for a buffer that is full
+        // without holding a complete record, which can only happen with a misbehaving peer, we let rustls
+        // deal with it instead of waiting for more data forever.
+        if complete_records(buffer.as_slice()) > 0 {
+            return Ok(buffer.len());
+        }
+        buffer.compact();
+        if buffer.is_full() {
             return Ok(buffer.len());
         }
 
-        // Unwrap buffer such that we can read from it. We always expect the buffer to be present.
-        // So if the buffer is None, we definitely have a bug somewhere when we forgot to return the buffer.
-        let buffer = self.buffer.take().expect("buffer ownership expected");
+        // Move the buffer into the read operation. If the operation is cancelled, it never comes back, and every
+        // later use of the adaptor fails with the error of `lost_buffer`.
+        let buffer = self.buffer.take().ok_or_else(lost_buffer)?;
 
         // Call undelying read operation to fetch more data from IO
         let (result, buf) = io.read(buffer).await;
@@ -138,12 +237,17 @@ impl SyncReadAdaptor {
         self.buffer = Some(buf);
 
         // Properly set the status of the read operation and return result
-        return match result {
+        match result {
             Ok(0) => {
                 self.status = ReadStatus::Eof;
                 result
             }
-            Ok(_) => {
+            Ok(n) => {
+                // The bytes that were just read are the last n bytes of the buffer.
+                if let Some(recorded) = self.recorded.as_mut() {
+                    let data = self.buffer.as_ref().unwrap().as_slice();
+                    recorded.extend_from_slice(&data[data.len() - n..]);
+                }
                 self.status = ReadStatus::Ok;
                 result
             }
@@ -152,20 +256,19 @@ impl SyncReadAdaptor {
                 self.status = ReadStatus::Err(e);
                 Err(rerr)
             }
-        };
+        }
     }
 }
 
 impl io::Read for SyncReadAdaptor {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         // Can't read anything if the reader buffer is empty.
-        if buf.len() == 0 {
+        if buf.is_empty() {
             return Ok(0);
         }
 
-        // Unwrap buffer such that we can read from it. We always expect the buffer to be present.
-        // So if the buffer is None, we definitely have a bug somewhere when we forgot to return the buffer.
-        let buffer = self.buffer.as_mut().expect("buffer mut expected");
+        // The buffer is only missing if a previous read was cancelled while in flight.
+        let buffer = self.buffer.as_mut().ok_or_else(lost_buffer)?;
 
         // If buffer is empty, we need to check for 2 cases:
         //   1. Buffer empty due to previous read operation failure: broken pipe, EOF etc.
@@ -186,8 +289,19 @@ impl io::Read for SyncReadAdaptor {
             return Err(io::ErrorKind::WouldBlock.into());
         }
 
+        // Only complete records are handed out, such that rustls never holds on to a partial record in between
+        // reads. This keeps the state of the session exportable at any time. A partial record is only handed out
+        // when it can't be completed anymore, because the read operation failed or the buffer is full.
+        let stalled =
+            !matches!(self.status, ReadStatus::Ok) || (buffer.read == 0 && buffer.is_full());
+        let ready = match complete_records(buffer.as_slice()) {
+            0 if !stalled => return Err(io::ErrorKind::WouldBlock.into()),
+            0 => buffer.len(),
+            n => n,
+        };
+
         // Since the buffer is not empty, we should have some data to return to the caller
-        let copy_size = buffer.len().min(buf.len());
+        let copy_size = ready.min(buf.len());
 
         // Safety: in the above line, we have checked length of both buffers, and we taken the min of them
         unsafe { std::ptr::copy_nonoverlapping(buffer.stable_ptr(), buf.as_mut_ptr(), copy_size) };
@@ -220,15 +334,31 @@ impl Default for SyncWriteAdaptor {
 }
 
 impl SyncWriteAdaptor {
-    pub(crate) async fn do_io(&mut self, io: &mut TcpStream) -> io::Result<usize> {
+    /// Creates a write adaptor that writes `data` to the socket before anything else.
+    pub(crate) fn with_buffered(data: &[u8]) -> Self {
+        Self {
+            buffer: Some(RingBuffer::from_slice(BUFFER_SIZE, data)),
+            status: WriteStatus::Ok,
+        }
+    }
+
+    /// Returns the bytes that have not been written to the socket yet.
+    pub(crate) fn buffered(&self) -> io::Result<&[u8]> {
+        self.buffer
+            .as_ref()
+            .map(RingBuffer::as_slice)
+            .ok_or_else(lost_buffer)
+    }
+
+    pub(crate) async fn do_io(&mut self, io: &TcpStream) -> io::Result<usize> {
         // If buffer is empty, we don't have any additional data to write
-        if self.buffer.as_ref().unwrap().is_empty() {
+        if self.buffered()?.is_empty() {
             return Ok(0);
         }
 
-        // Unwrap buffer such that we can read from it. We always expect the buffer to be present.
-        // So if the buffer is None, we definitely have a bug somewhere when we forgot to return the buffer.
-        let buffer = self.buffer.take().expect("bug: buffer ownership expected");
+        // Move the buffer into the write operation. If the operation is cancelled, it never comes back, and
+        // every later use of the adaptor fails with the error of `lost_buffer`.
+        let buffer = self.buffer.take().ok_or_else(lost_buffer)?;
 
         // Call write operation on io to flush the data in the buffer
         let (result, buffer) = io.write(buffer).await;
@@ -260,9 +390,8 @@ impl io::Write for SyncWriteAdaptor {
             return Ok(0);
         }
 
-        // Unwrap buffer such that we can read from it. We always expect the buffer to be present.
-        // So if the buffer is None, we definitely have a bug somewhere when we forgot to return the buffer.
-        let buffer = self.buffer.as_mut().expect("bug: buffer mut expected");
+        // The buffer is only missing if a previous write was cancelled while in flight.
+        let buffer = self.buffer.as_mut().ok_or_else(lost_buffer)?;
 
         // We need to check if previous write operation is successful or not. If previous write operation errored
         // out, we should catch and raise exception.
@@ -288,9 +417,8 @@ impl io::Write for SyncWriteAdaptor {
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        // Unwrap buffer such that we can read from it. We always expect the buffer to be present.
-        // So if the buffer is None, we definitely have a bug somewhere when we forgot to return the buffer.
-        let buffer = self.buffer.as_mut().expect("buffer mut expected");
+        // The buffer is only missing if a previous write was cancelled while in flight.
+        let buffer = self.buffer.as_mut().ok_or_else(lost_buffer)?;
 
         // We need to check if previous write operation is successful or not. If previous write operation errored
         // out, we should catch and raise exception.
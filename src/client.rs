@@ -1,6 +1,10 @@
-use crate::stream::TlsStream;
+use crate::{
+    happy_eyeballs,
+    resumption::{self, ClientSessionCache},
+    stream::{HandshakeError, TlsStream},
+};
 
-use rustls::{ClientConfig, ClientConnection};
+use rustls::{client::Resumption, ClientConfig, ClientConnection};
 use std::{
     io::{self, Error, ErrorKind},
     sync::Arc,
@@ -10,27 +14,142 @@ use tokio_uring::net::TcpStream;
 #[derive(Clone)]
 pub struct TlsConnector {
     inner: Arc<ClientConfig>,
+    sessions: Option<Arc<ClientSessionCache>>,
 }
 
 impl From<Arc<ClientConfig>> for TlsConnector {
     #[inline]
     fn from(inner: Arc<ClientConfig>) -> TlsConnector {
-        TlsConnector { inner }
+        TlsConnector {
+            inner,
+            sessions: None,
+        }
     }
 }
 
 impl TlsConnector {
+    /// Keeps the sessions of the connections in `cache`, which can be shared with other connectors, e.g. on
+    /// other threads.
+    ///
+    /// The cache replaces the resumption store of the config, and counts how many handshakes were resumed, see
+    /// [`ClientSessionCache::stats`]. TLS 1.2 resumption is set back to the rustls default of using session ids
+    /// or tickets.
+    pub fn session_cache(self, cache: Arc<ClientSessionCache>) -> Self {
+        let mut config = (*self.inner).clone();
+        config.resumption = Resumption::store(cache.clone());
+        TlsConnector {
+            inner: Arc::new(config),
+            sessions: Some(cache),
+        }
+    }
+
     pub async fn connect(
         &self,
         domain: rustls::ServerName,
         socket: TcpStream,
     ) -> io::Result<TlsStream<ClientConnection>> {
-        let session = match ClientConnection::new(self.inner.clone(), domain) {
+        self.connect_with(domain, socket, |_| ()).await
+    }
+
+    /// Resolves `host`, connects to it, and performs the TLS handshake.
+    ///
+    /// Name resolution runs off the ring thread, and the resolved addresses are tried following RFC 8305 Happy
+    /// Eyeballs, alternating between IPv6 and IPv4. The server name is derived from `host`, which may also be an
+    /// IP address. If no address can be connected to, the error carries a [`ConnectError`](crate::ConnectError)
+    /// listing every address that was tried.
+    pub async fn connect_to(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> io::Result<TlsStream<ClientConnection>> {
+        let name = host.trim_start_matches('[').trim_end_matches(']');
+        let domain = match rustls::ServerName::try_from(name) {
+            Ok(domain) => domain,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidInput, e)),
+        };
+        let socket = happy_eyeballs::connect(name, port).await?;
+        self.connect(domain, socket).await
+    }
+
+    /// Like [`TlsConnector::connect`], but calls `f` on the new session before the handshake starts.
+    ///
+    /// This allows per connection settings such as buffer limits, or queueing early data through
+    /// `ClientConnection::early_data`. Note that the ALPN protocols are fixed by the `ClientConfig`, as rustls
+    /// builds the `ClientHello` when the session is created.
+    pub async fn connect_with<F>(
+        &self,
+        domain: rustls::ServerName,
+        socket: TcpStream,
+        f: F,
+    ) -> io::Result<TlsStream<ClientConnection>>
+    where
+        F: FnOnce(&mut ClientConnection),
+    {
+        let mut session = match ClientConnection::new(self.inner.clone(), domain) {
             Ok(c) => c,
-            Err(e) => return Err(Error::new(ErrorKind::Other, e)),
+            Err(e) => return Err(Error::other(e)),
         };
+        f(&mut session);
         let mut stream = TlsStream::new(socket, session);
-        stream.handshake().await?;
+        self.handshake(&mut stream).await?;
         Ok(stream)
     }
+
+    /// Like [`TlsConnector::connect`], for a connection where data following the upgrade to TLS has already been
+    /// read from the socket, e.g. along with the server's response to a STARTTLS command.
+    ///
+    /// The handshake processes `prefix` first, then continues with data from the socket.
+    pub async fn connect_with_prefix(
+        &self,
+        domain: rustls::ServerName,
+        socket: TcpStream,
+        prefix: &[u8],
+    ) -> io::Result<TlsStream<ClientConnection>> {
+        let session = match ClientConnection::new(self.inner.clone(), domain) {
+            Ok(c) => c,
+            Err(e) => return Err(Error::other(e)),
+        };
+        let mut stream = TlsStream::with_prefix(socket, session, prefix);
+        self.handshake(&mut stream).await?;
+        Ok(stream)
+    }
+
+    /// Like [`TlsConnector::connect`], but hands the socket back if the handshake fails.
+    ///
+    /// See [`HandshakeError`] for what is returned on failure.
+    pub async fn try_connect(
+        &self,
+        domain: rustls::ServerName,
+        socket: TcpStream,
+    ) -> Result<TlsStream<ClientConnection>, HandshakeError> {
+        let session = match ClientConnection::new(self.inner.clone(), domain) {
+            Ok(c) => c,
+            Err(e) => return Err(HandshakeError::new(socket, Vec::new(), Error::other(e))),
+        };
+        let (stream, received) = TlsStream::new(socket, session).try_handshake().await?;
+        if let Some(cache) = &self.sessions {
+            cache.record_handshake(resumption::server_resumed(&received).unwrap_or(false));
+        }
+        Ok(stream)
+    }
+
+    async fn handshake(&self, stream: &mut TlsStream<ClientConnection>) -> io::Result<()> {
+        match &self.sessions {
+            Some(cache) => {
+                // Only the first flight of the server is recorded, it tells whether the session was resumed.
+                let mut resumed = None;
+                stream
+                    .observed_handshake(|received| {
+                        resumed = resumption::server_resumed(received);
+                        resumed.is_some()
+                    })
+                    .await?;
+                cache.record_handshake(resumed.unwrap_or(false));
+            }
+            None => {
+                stream.handshake().await?;
+            }
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,286 @@
+use crate::{
+    fs::read_file,
+    pem::{self, with_path},
+};
+
+use rustls::{
+    server::{AllowAnyAuthenticatedClient, ClientCertVerified, ClientCertVerifier},
+    Certificate, DistinguishedName, RootCertStore,
+};
+use std::{
+    io::{self, Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, RwLockReadGuard},
+    time::{Duration, SystemTime},
+};
+use x509_parser::{prelude::FromDer, revocation_list::CertificateRevocationList};
+
+/// How often the directory is checked for changes by default.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A certificate revocation list in use by a [`CrlVerifier`].
+#[derive(Clone, Debug)]
+pub struct CrlStatus {
+    path: PathBuf,
+    issuer: String,
+    this_update: SystemTime,
+    next_update: Option<SystemTime>,
+    revoked: usize,
+}
+
+impl CrlStatus {
+    fn parse(der: &[u8], path: &Path) -> io::Result<Self> {
+        let (_, crl) = CertificateRevocationList::from_der(der).map_err(|e| {
+            with_path(
+                Error::new(ErrorKind::InvalidData, format!("invalid CRL: {}", e)),
+                path,
+            )
+        })?;
+        Ok(CrlStatus {
+            path: path.to_path_buf(),
+            issuer: crl.issuer().to_string(),
+            this_update: system_time(crl.last_update().timestamp()),
+            next_update: crl.next_update().map(|t| system_time(t.timestamp())),
+            revoked: crl.iter_revoked_certificates().count(),
+        })
+    }
+
+    /// The file the list was loaded from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The distinguished name of the CA that issued the list, e.g. `CN=Example CA, O=Example`.
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// When the list was issued.
+    pub fn this_update(&self) -> SystemTime {
+        self.this_update
+    }
+
+    /// When the CA publishes the next list, if it says so.
+    ///
+    /// rustls keeps using a list past that time, it is up to the caller to make sure it gets replaced.
+    pub fn next_update(&self) -> Option<SystemTime> {
+        self.next_update
+    }
+
+    /// The number of certificates the list revokes.
+    pub fn revoked_count(&self) -> usize {
+        self.revoked
+    }
+}
+
+fn system_time(timestamp: i64) -> SystemTime {
+    match u64::try_from(timestamp) {
+        Ok(secs) => SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+        Err(_) => SystemTime::UNIX_EPOCH - Duration::from_secs(timestamp.unsigned_abs()),
+    }
+}
+
+/// A client certificate verifier that checks certificates against a CA bundle and rejects those revoked by the
+/// certificate revocation lists (CRLs) of a directory, which can be reloaded while it is in use.
+///
+/// Every file of the directory is loaded, except hidden ones, each holding one or more PEM encoded CRLs or a
+/// single DER encoded one. A client certificate whose issuer has no CRL in the directory is not checked for
+/// revocation. The CA bundle is loaded once.
+///
+/// Set it with `ServerConfig::builder().with_safe_defaults().with_client_cert_verifier(verifier)`. Unlike the
+/// certificate, the verifier of a config can't be replaced afterwards, which is why this one swaps its lists in
+/// place.
+pub struct CrlVerifier {
+    roots: RootCertStore,
+    subjects: Vec<DistinguishedName>,
+    crl_dir: PathBuf,
+    interval: Duration,
+    mandatory: bool,
+    on_reload: Option<Box<dyn Fn(io::Result<()>) + Send + Sync>>,
+    current: RwLock<Current>,
+}
+
+struct Current {
+    verifier: Arc<AllowAnyAuthenticatedClient>,
+    crls: Vec<CrlStatus>,
+    files: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl CrlVerifier {
+    /// Loads the CA bundle from `ca_path` and the CRLs from the files in `crl_dir`, of which there must be at
+    /// least one, so that a wrong directory doesn't silently turn off revocation checks.
+    pub async fn new(ca_path: impl AsRef<Path>, crl_dir: impl AsRef<Path>) -> io::Result<Self> {
+        let roots = pem::load_root_store(ca_path).await?;
+        let crl_dir = crl_dir.as_ref().to_path_buf();
+
+        let files = read_dir(&crl_dir).await?;
+        let (verifier, crls) = load(&roots, &crl_dir, &files)?;
+
+        Ok(CrlVerifier {
+            subjects: roots.roots.iter().map(|r| r.subject().clone()).collect(),
+            roots,
+            crl_dir,
+            interval: DEFAULT_INTERVAL,
+            mandatory: true,
+            on_reload: None,
+            current: RwLock::new(Current {
+                verifier: Arc::new(verifier),
+                crls,
+                files,
+            }),
+        })
+    }
+
+    /// Sets how often [`CrlVerifier::run`] checks the directory for changes, a minute by default.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Lets clients connect without a certificate, those that send one are still verified.
+    pub fn allow_unauthenticated(mut self) -> Self {
+        self.mandatory = false;
+        self
+    }
+
+    /// Sets a callback that is told the outcome of every reload attempted by [`CrlVerifier::run`].
+    pub fn on_reload(mut self, f: impl Fn(io::Result<()>) + Send + Sync + 'static) -> Self {
+        self.on_reload = Some(Box::new(f));
+        self
+    }
+
+    /// The CRLs in use, in the order of their file names.
+    pub fn crls(&self) -> Vec<CrlStatus> {
+        self.read().crls.clone()
+    }
+
+    /// Reads the directory and swaps the new CRLs in. If any of them can't be loaded, the current ones are kept.
+    pub async fn reload(&self) -> io::Result<()> {
+        let files = read_dir(&self.crl_dir).await?;
+        self.apply(files)
+    }
+
+    /// Checks the directory for changes at every interval, swapping in the new CRLs.
+    ///
+    /// Never returns, it is meant to be spawned on the runtime.
+    pub async fn run(&self) {
+        loop {
+            tokio::time::sleep(self.interval).await;
+
+            let result = match read_dir(&self.crl_dir).await {
+                Ok(files) if self.read().files != files => Some(self.apply(files)),
+                Ok(_) => None,
+                // A file that can't be read is likely in the middle of being replaced, the next check will pick
+                // it up.
+                Err(_) => None,
+            };
+
+            if let (Some(result), Some(on_reload)) = (result, &self.on_reload) {
+                on_reload(result);
+            }
+        }
+    }
+
+    fn apply(&self, files: Vec<(PathBuf, Vec<u8>)>) -> io::Result<()> {
+        let loaded = load(&self.roots, &self.crl_dir, &files);
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        // Remember the contents even if they were rejected, so a broken file is reported once.
+        current.files = files;
+        let (verifier, crls) = loaded?;
+        current.verifier = Arc::new(verifier);
+        current.crls = crls;
+        Ok(())
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Current> {
+        // The lock is never held across anything that can panic, poisoning can be ignored.
+        self.current.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl ClientCertVerifier for CrlVerifier {
+    fn client_auth_mandatory(&self) -> bool {
+        self.mandatory
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &self.subjects
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let verifier = self.read().verifier.clone();
+        verifier.verify_client_cert(end_entity, intermediates, now)
+    }
+}
+
+/// Reads the files of `dir` that aren't hidden, sorted by name.
+///
+/// The ring has no operation to list a directory, the listing runs on the blocking pool so that a slow file
+/// system, e.g. a network mount, doesn't stall the ring thread. The files themselves are read through the ring.
+async fn read_dir(dir: &Path) -> io::Result<Vec<(PathBuf, Vec<u8>)>> {
+    let listed = dir.to_path_buf();
+    let paths = tokio::task::spawn_blocking(move || list_dir(&listed))
+        .await
+        .map_err(|e| with_path(Error::other(e), dir))??;
+
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        let data = read_file(&path).await.map_err(|e| with_path(e, &path))?;
+        files.push((path, data));
+    }
+    Ok(files)
+}
+
+/// Lists the files of `dir` that aren't hidden, sorted by name, with blocking calls.
+fn list_dir(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(|e| with_path(e, dir))? {
+        let path = entry.map_err(|e| with_path(e, dir))?.path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."));
+        // Follows symlinks, as mounted secrets usually are.
+        if !hidden && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Parses the CRL files, building a verifier that checks against them.
+fn load(
+    roots: &RootCertStore,
+    dir: &Path,
+    files: &[(PathBuf, Vec<u8>)],
+) -> io::Result<(AllowAnyAuthenticatedClient, Vec<CrlStatus>)> {
+    let mut crls = Vec::new();
+    let mut statuses = Vec::new();
+    for (path, data) in files {
+        for crl in pem::parse_crls(data).map_err(|e| with_path(e, path))? {
+            statuses.push(CrlStatus::parse(&crl.0, path)?);
+            crls.push(crl);
+        }
+    }
+    if crls.is_empty() {
+        return Err(with_path(
+            Error::new(ErrorKind::NotFound, "no CRL in directory"),
+            dir,
+        ));
+    }
+
+    let verifier = AllowAnyAuthenticatedClient::new(roots.clone())
+        .with_crls(crls)
+        .map_err(|e| {
+            with_path(
+                Error::new(ErrorKind::InvalidData, rustls::Error::from(e)),
+                dir,
+            )
+        })?;
+    Ok((verifier, statuses))
+}
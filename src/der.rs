@@ -0,0 +1,43 @@
+//! Just enough BER to walk the structures this crate reads, LDAP messages and OCSP responses. Only definite
+//! lengths are supported, which DER requires and LDAP mandates.
+
+/// Splits the next element off `data`, returning its tag and content, or `None` if `data` doesn't start with a
+/// complete element.
+pub(crate) fn take<'a>(data: &mut &'a [u8]) -> Option<(u8, &'a [u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+
+    let (len, rest) = match first {
+        n if n < 0x80 => (n as usize, rest),
+        n if (0x81..=0x84).contains(&n) => {
+            let size = (n & 0x7f) as usize;
+            if rest.len() < size {
+                return None;
+            }
+            let len = rest[..size]
+                .iter()
+                .fold(0usize, |len, b| len << 8 | *b as usize);
+            (len, &rest[size..])
+        }
+        // Indefinite lengths and lengths beyond 32 bits
+        _ => return None,
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+    let (content, rest) = rest.split_at(len);
+    *data = rest;
+    Some((tag, content))
+}
+
+/// Appends an element to `out`, its content being at most 64 KiB.
+pub(crate) fn put(out: &mut Vec<u8>, tag: u8, content: &[u8]) {
+    out.push(tag);
+    match content.len() {
+        n if n < 0x80 => out.push(n as u8),
+        n if n <= 0xff => out.extend_from_slice(&[0x81, n as u8]),
+        n => out.extend_from_slice(&[0x82, (n >> 8) as u8, n as u8]),
+    }
+    out.extend_from_slice(content);
+}
@@ -0,0 +1,143 @@
+use crate::stream::TlsStream;
+
+use rustls::{ConnectionCommon, SideData};
+use std::{
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::watch;
+
+/// Time a force closed connection gets to take the close_notify alert.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Phase {
+    Running,
+    Draining,
+    Closing,
+}
+
+#[derive(Clone, Copy)]
+struct State {
+    phase: Phase,
+    active: usize,
+}
+
+/// Coordinates the graceful shutdown of the connections of a server.
+///
+/// Connections are served through [`Drain::serve`], which keeps track of them. [`Drain::shutdown`] then lets
+/// handlers know that the server is going away, waits for them to finish for a grace period, and closes the
+/// connections that are still open after it with a close_notify alert.
+///
+/// Clones share the same state, and can be used from any thread.
+#[derive(Clone)]
+pub struct Drain {
+    state: Arc<watch::Sender<State>>,
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        let (state, _) = watch::channel(State {
+            phase: Phase::Running,
+            active: 0,
+        });
+        Drain {
+            state: Arc::new(state),
+        }
+    }
+}
+
+/// Counts a connection as active for as long as it is alive.
+struct Active(Arc<watch::Sender<State>>);
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.send_modify(|s| s.active -= 1);
+    }
+}
+
+impl Drain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks if shutdown has started, handlers should stop taking new requests once it has.
+    pub fn is_draining(&self) -> bool {
+        self.state.borrow().phase >= Phase::Draining
+    }
+
+    /// Waits for shutdown to start, see [`Drain::is_draining`].
+    pub async fn draining(&self) {
+        self.wait_until(|s| s.phase >= Phase::Draining).await
+    }
+
+    /// The number of connections currently being served.
+    pub fn active(&self) -> usize {
+        self.state.borrow().active
+    }
+
+    /// Runs `handler` on `stream` until it returns, or until the grace period of a shutdown is over.
+    ///
+    /// In the latter case the handler is dropped, and a close_notify alert is sent before the stream is
+    /// closed. Returns whether that happened. A handler dropped in the middle of a write may have left part of
+    /// a record behind, the stream is closed without the alert then. The handler receives the stream by
+    /// reference so that it stays available for closing, which calls for a boxed future:
+    ///
+    /// ```ignore
+    /// drain.serve(stream, |stream| Box::pin(async move {
+    ///     let (res, buf) = stream.read(vec![0u8; 1024]).await;
+    ///     // ...
+    /// })).await;
+    /// ```
+    pub async fn serve<C, SD, F>(&self, mut stream: TlsStream<C>, handler: F) -> bool
+    where
+        C: DerefMut + Deref<Target = ConnectionCommon<SD>>,
+        SD: SideData,
+        F: for<'a> FnOnce(&'a mut TlsStream<C>) -> Pin<Box<dyn Future<Output = ()> + 'a>>,
+    {
+        self.state.send_modify(|s| s.active += 1);
+        let _active = Active(self.state.clone());
+
+        let forced = tokio::select! {
+            _ = handler(&mut stream) => false,
+            _ = self.wait_until(|s| s.phase == Phase::Closing) => true,
+        };
+
+        if forced {
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, stream.shutdown()).await;
+        }
+        forced
+    }
+
+    /// Starts the shutdown, and waits for every connection served through [`Drain::serve`] to be done.
+    ///
+    /// Handlers are given `grace` to return on their own, the connections that are still open after it are
+    /// closed. Calling this from several threads, e.g. from every thread of a [`TlsServer`](crate::TlsServer),
+    /// is fine, the grace period is not extended by later calls.
+    pub async fn shutdown(&self, grace: Duration) {
+        self.state
+            .send_modify(|s| s.phase = s.phase.max(Phase::Draining));
+
+        let idle = self.wait_until(|s| s.active == 0);
+        if tokio::time::timeout(grace, idle).await.is_err() {
+            self.state.send_modify(|s| s.phase = Phase::Closing);
+            self.wait_until(|s| s.active == 0).await;
+        }
+    }
+
+    async fn wait_until(&self, f: impl Fn(&State) -> bool) {
+        let mut state = self.state.subscribe();
+        loop {
+            if f(&state.borrow_and_update()) {
+                return;
+            }
+            // The sender is owned by self, it can't be dropped while waiting.
+            if state.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    io::{self, Error, ErrorKind},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio_uring::{
+    buf::IoBuf,
+    fs::{self, File, OpenOptions},
+};
+
+/// Numbers the temporary files of [`write_file`], so concurrent writes of the same file don't share one.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Reads a whole file through the ring.
+pub(crate) async fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let file = File::open(path).await?;
+    let mut data = Vec::new();
+    let mut buf = Vec::with_capacity(4096);
+
+    let result = loop {
+        let (res, b) = file.read_at(buf, data.len() as u64).await;
+        buf = b;
+        match res {
+            Ok(0) => break Ok(()),
+            Ok(n) => {
+                data.extend_from_slice(&buf[..n]);
+                buf.clear();
+            }
+            Err(e) => break Err(e),
+        }
+    };
+
+    file.close().await?;
+    result.map(|_| data)
+}
+
+/// Replaces the contents of a file through the ring, readable and writable by the owner only.
+///
+/// The data is written to a temporary file next to it first, which is then renamed over it, so readers never
+/// see a partially written file. The temporary file is named after the process and a counter, and created
+/// exclusively, so concurrent writers each get their own and the last rename wins.
+pub(crate) async fn write_file(path: &Path, mut data: Vec<u8>) -> io::Result<()> {
+    let (tmp, file) = loop {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(
+            ".{}.{}.tmp",
+            process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let opened = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)
+            .await;
+        match opened {
+            Ok(file) => break (tmp, file),
+            // Left behind by an earlier process with the same pid, try the next name.
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    };
+
+    let mut written = 0;
+    let result = loop {
+        if written == data.len() {
+            break file.sync_all().await;
+        }
+        let (res, slice) = file.write_at(data.slice(written..), written as u64).await;
+        data = slice.into_inner();
+        match res {
+            Ok(0) => {
+                break Err(Error::new(
+                    ErrorKind::WriteZero,
+                    "failed to write whole file",
+                ))
+            }
+            Ok(n) => written += n,
+            Err(e) => break Err(e),
+        }
+    };
+    file.close().await?;
+
+    match result {
+        Ok(()) => fs::rename(&tmp, path).await,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp).await;
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,689 @@
+use crate::{stream, TlsStream};
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use rustls::{ConnectionCommon, ConnectionTrafficSecrets, ProtocolVersion, SideData};
+use std::{
+    io::{self, Error, ErrorKind, Read, Write},
+    mem,
+    ops::{Deref, DerefMut},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+};
+use tokio_uring::{
+    buf::{IoBuf, IoBufMut},
+    net::TcpStream,
+    BufResult,
+};
+
+/// Magic bytes and format version of a serialized [`SessionState`].
+const STATE_MAGIC: &[u8; 4] = b"TURS";
+const STATE_FORMAT: u8 = 1;
+
+const CONTENT_CHANGE_CIPHER_SPEC: u8 = 20;
+const CONTENT_ALERT: u8 = 21;
+const CONTENT_HANDSHAKE: u8 = 22;
+const CONTENT_APPLICATION_DATA: u8 = 23;
+
+const HANDSHAKE_HELLO_REQUEST: u8 = 0;
+const HANDSHAKE_NEW_SESSION_TICKET: u8 = 4;
+
+const RECORD_HEADER_SIZE: usize = 5;
+const MAX_FRAGMENT_SIZE: usize = 1 << 14;
+const GCM_EXPLICIT_NONCE_SIZE: usize = 8;
+const READ_SIZE: usize = 16 * 1024;
+
+/// Upper bound on the size of a serialized [`SessionState`] accepted by [`ExportedStream::recv`]. A state holds
+/// the keys and at most a few records worth of buffered data, anything larger comes from a broken sender.
+const MAX_STATE_SIZE: u64 = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Cipher {
+    Aes128Gcm = 1,
+    Aes256Gcm = 2,
+    Chacha20Poly1305 = 3,
+}
+
+impl Cipher {
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Cipher::Aes128Gcm => &aead::AES_128_GCM,
+            Cipher::Aes256Gcm => &aead::AES_256_GCM,
+            Cipher::Chacha20Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+}
+
+/// Traffic secrets and sequence number for one direction of a connection.
+struct DirectionState {
+    seq: u64,
+    cipher: Cipher,
+    key: Vec<u8>,
+    iv: [u8; 12],
+}
+
+impl DirectionState {
+    fn new(seq: u64, secrets: ConnectionTrafficSecrets) -> io::Result<Self> {
+        let (cipher, key, iv) = match secrets {
+            ConnectionTrafficSecrets::Aes128Gcm { key, salt, iv } => {
+                (Cipher::Aes128Gcm, key.to_vec(), gcm_iv(salt, iv))
+            }
+            ConnectionTrafficSecrets::Aes256Gcm { key, salt, iv } => {
+                (Cipher::Aes256Gcm, key.to_vec(), gcm_iv(salt, iv))
+            }
+            ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => {
+                (Cipher::Chacha20Poly1305, key.to_vec(), iv)
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "cipher suite not supported for handoff",
+                ))
+            }
+        };
+        Ok(DirectionState {
+            seq,
+            cipher,
+            key,
+            iv,
+        })
+    }
+
+    fn nonce(&self) -> [u8; 12] {
+        let mut nonce = self.iv;
+        for (n, s) in nonce[4..].iter_mut().zip(self.seq.to_be_bytes()) {
+            *n ^= s;
+        }
+        nonce
+    }
+}
+
+impl Drop for DirectionState {
+    fn drop(&mut self) {
+        // Best effort to not leave key material behind in memory.
+        for b in self.key.iter_mut().chain(self.iv.iter_mut()) {
+            unsafe { std::ptr::write_volatile(b, 0) };
+        }
+    }
+}
+
+fn gcm_iv(salt: [u8; 4], explicit: [u8; 8]) -> [u8; 12] {
+    let mut iv = [0u8; 12];
+    iv[..4].copy_from_slice(&salt);
+    iv[4..].copy_from_slice(&explicit);
+    iv
+}
+
+/// Everything needed to continue a TLS connection without the rustls session that established it.
+///
+/// The state holds live traffic secrets. Anyone who gets hold of it can decrypt and forge traffic on the
+/// connection, so it must only travel over trusted channels.
+pub struct SessionState {
+    version: u16,
+    tx: DirectionState,
+    rx: DirectionState,
+    plaintext: Vec<u8>,
+    read_buffer: Vec<u8>,
+}
+
+impl SessionState {
+    /// The negotiated protocol version of the connection.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        ProtocolVersion::from(self.version)
+    }
+
+    /// Serializes the state, see [`SessionState::from_bytes`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(128 + self.plaintext.len() + self.read_buffer.len());
+        out.extend_from_slice(STATE_MAGIC);
+        out.push(STATE_FORMAT);
+        out.extend_from_slice(&self.version.to_be_bytes());
+        for direction in [&self.tx, &self.rx] {
+            out.extend_from_slice(&direction.seq.to_be_bytes());
+            out.push(direction.cipher as u8);
+            out.push(direction.key.len() as u8);
+            out.extend_from_slice(&direction.key);
+            out.extend_from_slice(&direction.iv);
+        }
+        for buffer in [&self.plaintext, &self.read_buffer] {
+            out.extend_from_slice(&(buffer.len() as u32).to_be_bytes());
+            out.extend_from_slice(buffer);
+        }
+        out
+    }
+
+    /// Deserializes a state produced by [`SessionState::to_bytes`].
+    pub fn from_bytes(mut bytes: &[u8]) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        bytes.read_exact(&mut magic)?;
+        if &magic != STATE_MAGIC || take::<1>(&mut bytes)? != [STATE_FORMAT] {
+            return Err(Error::new(ErrorKind::InvalidData, "not a session state"));
+        }
+
+        let version = u16::from_be_bytes(take(&mut bytes)?);
+        if version != ProtocolVersion::TLSv1_2.get_u16()
+            && version != ProtocolVersion::TLSv1_3.get_u16()
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "unsupported protocol version",
+            ));
+        }
+
+        let direction = |bytes: &mut &[u8]| -> io::Result<DirectionState> {
+            let seq = u64::from_be_bytes(take(bytes)?);
+            let cipher = match take::<1>(bytes)? {
+                [1] => Cipher::Aes128Gcm,
+                [2] => Cipher::Aes256Gcm,
+                [3] => Cipher::Chacha20Poly1305,
+                _ => return Err(Error::new(ErrorKind::InvalidData, "unknown cipher")),
+            };
+            let [len] = take::<1>(bytes)?;
+            if usize::from(len) != cipher.algorithm().key_len() {
+                return Err(Error::new(ErrorKind::InvalidData, "bad key length"));
+            }
+            let mut key = vec![0u8; usize::from(len)];
+            bytes.read_exact(&mut key)?;
+            let iv = take(bytes)?;
+            Ok(DirectionState {
+                seq,
+                cipher,
+                key,
+                iv,
+            })
+        };
+        let tx = direction(&mut bytes)?;
+        let rx = direction(&mut bytes)?;
+
+        let buffer = |bytes: &mut &[u8]| -> io::Result<Vec<u8>> {
+            let len = u32::from_be_bytes(take(bytes)?) as usize;
+            if bytes.len() < len {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            let (buffer, rest) = bytes.split_at(len);
+            *bytes = rest;
+            Ok(buffer.to_vec())
+        };
+        let plaintext = buffer(&mut bytes)?;
+        let read_buffer = buffer(&mut bytes)?;
+
+        Ok(SessionState {
+            version,
+            tx,
+            rx,
+            plaintext,
+            read_buffer,
+        })
+    }
+}
+
+fn take<const N: usize>(bytes: &mut &[u8]) -> io::Result<[u8; N]> {
+    let mut out = [0u8; N];
+    bytes.read_exact(&mut out)?;
+    Ok(out)
+}
+
+/// A TLS connection taken out of its [`TlsStream`], ready to be handed to another process.
+pub struct ExportedStream {
+    /// The underlying socket.
+    pub socket: std::net::TcpStream,
+    /// The state of the TLS session on top of the socket.
+    pub state: SessionState,
+}
+
+impl ExportedStream {
+    /// Sends the connection to another process over a Unix socket, passing the socket with `SCM_RIGHTS`.
+    ///
+    /// This is a blocking call.
+    pub fn send(self, channel: &UnixStream) -> io::Result<()> {
+        let state = self.state.to_bytes();
+        send_fd(
+            channel,
+            self.socket.as_raw_fd(),
+            &(state.len() as u64).to_be_bytes(),
+        )?;
+        (&*channel).write_all(&state)
+    }
+
+    /// Receives a connection sent with [`ExportedStream::send`].
+    ///
+    /// This is a blocking call.
+    pub fn recv(channel: &UnixStream) -> io::Result<Self> {
+        let mut len = [0u8; 8];
+        let (n, fd) = recv_fd(channel, &mut len)?;
+        let socket = std::net::TcpStream::from(fd);
+        (&*channel).read_exact(&mut len[n..])?;
+
+        let len = u64::from_be_bytes(len);
+        if len > MAX_STATE_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("session state of {} bytes is too large", len),
+            ));
+        }
+        let mut state = vec![0u8; len as usize];
+        (&*channel).read_exact(&mut state)?;
+
+        Ok(ExportedStream {
+            socket,
+            state: SessionState::from_bytes(&state)?,
+        })
+    }
+}
+
+fn send_fd(channel: &UnixStream, fd: RawFd, data: &[u8]) -> io::Result<()> {
+    unsafe {
+        let mut cmsg_buffer = [0u8; 64];
+        let cmsg_space = libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as usize;
+        assert!(cmsg_space <= cmsg_buffer.len());
+
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buffer.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsg_space as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+
+        let n = libc::sendmsg(channel.as_raw_fd(), &msg, libc::MSG_NOSIGNAL);
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        // The descriptor went out with the first byte, the rest is plain data.
+        (&*channel).write_all(&data[n as usize..])
+    }
+}
+
+fn recv_fd(channel: &UnixStream, data: &mut [u8]) -> io::Result<(usize, OwnedFd)> {
+    unsafe {
+        let mut cmsg_buffer = [0u8; 64];
+        let cmsg_space = libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as usize;
+
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buffer.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsg_space as _;
+
+        let n = libc::recvmsg(channel.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        // Take ownership of every descriptor that came in, so that they are closed if the message is rejected.
+        let mut fds = Vec::new();
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - (data as usize - cmsg as usize);
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    let fd = std::ptr::read_unaligned(data.add(i));
+                    fds.push(OwnedFd::from_raw_fd(fd));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        // The kernel drops the descriptors that didn't fit, the connection can't be trusted to be complete.
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "control message truncated, descriptors were lost",
+            ));
+        }
+        let fd = match <[OwnedFd; 1]>::try_from(fds) {
+            Ok([fd]) => fd,
+            Err(fds) if fds.is_empty() => {
+                return Err(Error::new(ErrorKind::InvalidData, "no socket received"))
+            }
+            Err(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "more than one descriptor received",
+                ))
+            }
+        };
+
+        Ok((n as usize, fd))
+    }
+}
+
+impl<C, SD: SideData> TlsStream<C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<SD>> + Into<rustls::Connection>,
+{
+    /// Takes the connection out of the stream so that another process can continue it.
+    ///
+    /// All records rustls has queued are written out first, and the plaintext rustls has decrypted but not handed
+    /// out yet becomes part of the exported state. Secret extraction must be enabled on the `ClientConfig` or
+    /// `ServerConfig` the session was created from.
+    pub async fn export(mut self) -> io::Result<ExportedStream> {
+        if self.session.is_handshaking() {
+            return Err(Error::other("tls handshake not complete"));
+        }
+
+        stream::flush(&self.io, &mut &mut self.session, &mut self.wbuffer).await?;
+        while !self.wbuffer.buffered()?.is_empty() {
+            if self.wbuffer.do_io(&self.io).await? == 0 {
+                return Err(ErrorKind::WriteZero.into());
+            }
+        }
+
+        let mut plaintext = Vec::new();
+        match self.session.reader().read_to_end(&mut plaintext) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
+            Err(err) => return Err(err),
+        }
+
+        let version = match self.session.protocol_version() {
+            Some(version) => version.get_u16(),
+            None => return Err(Error::other("tls handshake not complete")),
+        };
+
+        let parts = self.into_parts()?;
+        let secrets = parts
+            .session
+            .into()
+            .extract_secrets()
+            .map_err(Error::other)?;
+
+        Ok(ExportedStream {
+            socket: parts.socket,
+            state: SessionState {
+                version,
+                tx: DirectionState::new(secrets.tx.0, secrets.tx.1)?,
+                rx: DirectionState::new(secrets.rx.0, secrets.rx.1)?,
+                plaintext,
+                read_buffer: parts.read_buffer,
+            },
+        })
+    }
+}
+
+/// A TLS connection continued from an [`ExportedStream`], using a userspace record layer.
+///
+/// The stream only carries application data. Post-handshake messages that need the session, such as a TLS 1.3
+/// KeyUpdate, are rejected with an error.
+pub struct ResumedStream {
+    io: TcpStream,
+    state: SessionState,
+    plaintext_pos: usize,
+    closed: bool,
+}
+
+impl ResumedStream {
+    /// Continues the exported connection on the runtime of the calling thread.
+    pub fn new(exported: ExportedStream) -> Self {
+        ResumedStream {
+            io: TcpStream::from_std(exported.socket),
+            state: exported.state,
+            plaintext_pos: 0,
+            closed: false,
+        }
+    }
+
+    fn is_tls13(&self) -> bool {
+        self.state.version == ProtocolVersion::TLSv1_3.get_u16()
+    }
+
+    pub async fn read<B: IoBufMut>(&mut self, mut buf: B) -> BufResult<usize, B> {
+        loop {
+            let plaintext = &self.state.plaintext[self.plaintext_pos..];
+            if !plaintext.is_empty() || self.closed {
+                let n = plaintext.len().min(buf.bytes_total());
+                // Safety: n is bounded by the capacity of the buffer.
+                unsafe {
+                    std::ptr::copy_nonoverlapping(plaintext.as_ptr(), buf.stable_mut_ptr(), n);
+                    buf.set_init(n);
+                }
+                self.plaintext_pos += n;
+                return (Ok(n), buf);
+            }
+
+            match self.open_record() {
+                Ok(true) => continue,
+                Ok(false) => (),
+                Err(e) => return (Err(e), buf),
+            }
+
+            let mut incoming = mem::take(&mut self.state.read_buffer);
+            let len = incoming.len();
+            incoming.reserve(READ_SIZE);
+            let (result, slice) = self.io.read(incoming.slice(len..)).await;
+            self.state.read_buffer = slice.into_inner();
+            match result {
+                Ok(0) => {
+                    return (
+                        Err(Error::new(ErrorKind::UnexpectedEof, "tls raw stream eof")),
+                        buf,
+                    )
+                }
+                Ok(_) => (),
+                Err(e) => return (Err(e), buf),
+            }
+        }
+    }
+
+    /// Decrypts the first buffered record, if it is complete. Returns whether a record was consumed.
+    fn open_record(&mut self) -> io::Result<bool> {
+        let incoming = &mut self.state.read_buffer;
+        if incoming.len() < RECORD_HEADER_SIZE {
+            return Ok(false);
+        }
+        let len = u16::from_be_bytes([incoming[3], incoming[4]]) as usize;
+        if incoming.len() < RECORD_HEADER_SIZE + len {
+            return Ok(false);
+        }
+
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        header.copy_from_slice(&incoming[..RECORD_HEADER_SIZE]);
+        let mut payload: Vec<u8> = incoming
+            .drain(..RECORD_HEADER_SIZE + len)
+            .skip(RECORD_HEADER_SIZE)
+            .collect();
+
+        // TLS 1.3 peers may send change_cipher_spec for middlebox compatibility, it carries nothing.
+        if header[0] == CONTENT_CHANGE_CIPHER_SPEC {
+            return Ok(true);
+        }
+
+        let bad_record = || Error::new(ErrorKind::InvalidData, "failed to decrypt tls record");
+        let rx = &mut self.state.rx;
+        let key = LessSafeKey::new(
+            UnboundKey::new(rx.cipher.algorithm(), &rx.key).map_err(|_| bad_record())?,
+        );
+        let tag_len = rx.cipher.algorithm().tag_len();
+
+        let (typ, plaintext) = if self.state.version == ProtocolVersion::TLSv1_3.get_u16() {
+            let nonce = Nonce::assume_unique_for_key(rx.nonce());
+            let plaintext = key
+                .open_in_place(nonce, Aad::from(header), &mut payload)
+                .map_err(|_| bad_record())?;
+            // Strip the padding, the last non-zero byte is the real content type.
+            let end = plaintext
+                .iter()
+                .rposition(|&b| b != 0)
+                .ok_or_else(bad_record)?;
+            let typ = plaintext[end];
+            (typ, &plaintext[..end])
+        } else if rx.cipher == Cipher::Chacha20Poly1305 {
+            let plain_len = payload.len().checked_sub(tag_len).ok_or_else(bad_record)?;
+            let aad = tls12_aad(rx.seq, header[0], plain_len);
+            let nonce = Nonce::assume_unique_for_key(rx.nonce());
+            let plaintext = key
+                .open_in_place(nonce, Aad::from(aad), &mut payload)
+                .map_err(|_| bad_record())?;
+            (header[0], &*plaintext)
+        } else {
+            let plain_len = payload
+                .len()
+                .checked_sub(GCM_EXPLICIT_NONCE_SIZE + tag_len)
+                .ok_or_else(bad_record)?;
+            let aad = tls12_aad(rx.seq, header[0], plain_len);
+            let mut nonce = rx.iv;
+            nonce[4..].copy_from_slice(&payload[..GCM_EXPLICIT_NONCE_SIZE]);
+            let nonce = Nonce::assume_unique_for_key(nonce);
+            let plaintext = key
+                .open_within(
+                    nonce,
+                    Aad::from(aad),
+                    &mut payload,
+                    GCM_EXPLICIT_NONCE_SIZE..,
+                )
+                .map_err(|_| bad_record())?;
+            (header[0], &*plaintext)
+        };
+        rx.seq += 1;
+
+        match typ {
+            CONTENT_APPLICATION_DATA => {
+                let state = &mut self.state;
+                state.plaintext.drain(..self.plaintext_pos);
+                state.plaintext.extend_from_slice(plaintext);
+                self.plaintext_pos = 0;
+            }
+            CONTENT_ALERT => match plaintext {
+                // close_notify
+                [_, 0] => self.closed = true,
+                [_, description] => {
+                    return Err(Error::new(
+                        ErrorKind::ConnectionAborted,
+                        format!("received tls alert {}", description),
+                    ))
+                }
+                _ => return Err(bad_record()),
+            },
+            CONTENT_HANDSHAKE => {
+                let mut messages = plaintext;
+                while messages.len() >= 4 {
+                    let len =
+                        u32::from_be_bytes([0, messages[1], messages[2], messages[3]]) as usize;
+                    match messages[0] {
+                        HANDSHAKE_NEW_SESSION_TICKET | HANDSHAKE_HELLO_REQUEST => (),
+                        _ => {
+                            return Err(Error::new(
+                                ErrorKind::Unsupported,
+                                "post-handshake message not supported after handoff",
+                            ))
+                        }
+                    }
+                    messages = messages.get(4 + len..).unwrap_or_default();
+                }
+            }
+            _ => return Err(bad_record()),
+        }
+
+        Ok(true)
+    }
+
+    /// Encrypts `data` into one record and appends it to `out`.
+    fn seal_record(&mut self, typ: u8, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let tls13 = self.is_tls13();
+        let tx = &mut self.state.tx;
+        let key = LessSafeKey::new(
+            UnboundKey::new(tx.cipher.algorithm(), &tx.key)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "bad traffic key"))?,
+        );
+        let tag_len = tx.cipher.algorithm().tag_len();
+        let nonce = tx.nonce();
+        let seal_failed = |_| Error::other("failed to encrypt tls record");
+
+        let start = out.len();
+        if tls13 {
+            let len = data.len() + 1 + tag_len;
+            out.extend_from_slice(&[CONTENT_APPLICATION_DATA, 3, 3]);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+
+            let mut header = [0u8; RECORD_HEADER_SIZE];
+            header.copy_from_slice(&out[start..]);
+            let mut payload = Vec::with_capacity(len);
+            payload.extend_from_slice(data);
+            payload.push(typ);
+            key.seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(header),
+                &mut payload,
+            )
+            .map_err(seal_failed)?;
+            out.extend_from_slice(&payload);
+        } else {
+            let explicit = if tx.cipher == Cipher::Chacha20Poly1305 {
+                0
+            } else {
+                GCM_EXPLICIT_NONCE_SIZE
+            };
+            let len = explicit + data.len() + tag_len;
+            out.extend_from_slice(&[typ, 3, 3]);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+            out.extend_from_slice(&nonce[12 - explicit..]);
+
+            let mut payload = data.to_vec();
+            key.seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(tls12_aad(tx.seq, typ, data.len())),
+                &mut payload,
+            )
+            .map_err(seal_failed)?;
+            out.extend_from_slice(&payload);
+        }
+        tx.seq += 1;
+
+        Ok(())
+    }
+
+    pub async fn write<B: IoBuf>(&mut self, buf: B) -> BufResult<usize, B> {
+        let size = buf.bytes_init().min(MAX_FRAGMENT_SIZE);
+        let result = self.write_all(buf.slice(..size)).await;
+        (result.0.map(|_| size), result.1.into_inner())
+    }
+
+    pub async fn write_all<B: IoBuf>(&mut self, buf: B) -> BufResult<(), B> {
+        // Safety: the buffer promises bytes_init bytes of initialized memory.
+        let data = unsafe { std::slice::from_raw_parts(buf.stable_ptr(), buf.bytes_init()) };
+
+        let mut records = Vec::with_capacity(data.len() + 64);
+        for chunk in data.chunks(MAX_FRAGMENT_SIZE) {
+            if let Err(e) = self.seal_record(CONTENT_APPLICATION_DATA, chunk, &mut records) {
+                return (Err(e), buf);
+            }
+        }
+
+        let (result, _) = self.io.write_all(records).await;
+        (result, buf)
+    }
+
+    /// Sends a `close_notify` alert, telling the peer that nothing more will be written.
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        let mut record = Vec::new();
+        self.seal_record(CONTENT_ALERT, &[1, 0], &mut record)?;
+        self.io.write_all(record).await.0
+    }
+}
+
+fn tls12_aad(seq: u64, typ: u8, len: usize) -> [u8; 13] {
+    let mut aad = [0u8; 13];
+    aad[..8].copy_from_slice(&seq.to_be_bytes());
+    aad[8] = typ;
+    aad[9..11].copy_from_slice(&[3, 3]);
+    aad[11..].copy_from_slice(&(len as u16).to_be_bytes());
+    aad
+}
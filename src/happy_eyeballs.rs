@@ -0,0 +1,266 @@
+use std::{error::Error, fmt, io, net::SocketAddr, time::Duration};
+use tokio::{
+    sync::mpsc,
+    time::{timeout_at, Instant},
+};
+use tokio_uring::net::TcpStream;
+
+/// Time to wait for a connection attempt before starting the next one in parallel, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Error returned by [`TlsConnector::connect_to`](crate::TlsConnector::connect_to) when no connection could be
+/// established to any of the addresses the host resolved to.
+///
+/// It is carried inside the returned `io::Error`, and can be retrieved with `io::Error::get_ref`.
+#[derive(Debug)]
+pub struct ConnectError {
+    host: String,
+    port: u16,
+    attempts: Vec<(SocketAddr, io::Error)>,
+}
+
+impl ConnectError {
+    /// Every address that was tried, in the order the attempts failed, along with the reason.
+    pub fn attempts(&self) -> &[(SocketAddr, io::Error)] {
+        &self.attempts
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.attempts.is_empty() {
+            return write!(
+                f,
+                "{}:{} did not resolve to any address",
+                self.host, self.port
+            );
+        }
+
+        write!(f, "failed to connect to {}:{}", self.host, self.port)?;
+        for (i, (addr, err)) in self.attempts.iter().enumerate() {
+            let sep = if i == 0 { ": " } else { ", " };
+            write!(f, "{}{} ({})", sep, addr, err)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConnectError {}
+
+/// Orders addresses as described in RFC 8305 section 4, alternating between the address families and starting
+/// with IPv6. The relative order within each family is kept.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+
+    let mut out = Vec::with_capacity(v6.len() + v4.len());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => out.extend(a.into_iter().chain(b)),
+        }
+    }
+    out
+}
+
+/// Resolves `host` without blocking the ring thread, and connects to it following RFC 8305 Happy Eyeballs.
+///
+/// Connection attempts are started one at a time, a new one whenever the previous one failed or has been
+/// pending for [`CONNECTION_ATTEMPT_DELAY`]. The first attempt to succeed wins, the others are cancelled.
+pub(crate) async fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let addrs = tokio::net::lookup_host((host, port)).await?.collect();
+    connect_addrs(host, port, interleave(addrs)).await
+}
+
+/// Connects to the first of `addrs` that accepts a connection, trying them in order.
+async fn connect_addrs(host: &str, port: u16, addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+    let mut pending = addrs.into_iter().peekable();
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut tasks = Vec::new();
+    let mut in_flight = 0;
+    let mut attempts = Vec::new();
+    let mut next_attempt = Instant::now();
+
+    loop {
+        // Start the next attempt if it is due, or if there is nothing else to wait for.
+        if in_flight == 0 || Instant::now() >= next_attempt {
+            match pending.next() {
+                Some(addr) => {
+                    let tx = tx.clone();
+                    tasks.push(tokio_uring::spawn(async move {
+                        let _ = tx.send((addr, TcpStream::connect(addr).await));
+                    }));
+                    in_flight += 1;
+                    next_attempt = Instant::now() + CONNECTION_ATTEMPT_DELAY;
+                }
+                None if in_flight == 0 => break,
+                None => (),
+            }
+        }
+
+        let result = if pending.peek().is_some() {
+            match timeout_at(next_attempt, rx.recv()).await {
+                Ok(result) => result,
+                Err(_) => continue,
+            }
+        } else {
+            rx.recv().await
+        };
+
+        // The sender is kept alive above, so the channel can't be closed.
+        let (addr, result) = result.expect("bug: connection attempt channel closed");
+        in_flight -= 1;
+        match result {
+            Ok(socket) => {
+                for task in tasks {
+                    task.abort();
+                }
+                return Ok(socket);
+            }
+            Err(err) => {
+                attempts.push((addr, err));
+                // Don't wait for the delay to pass, move on to the next address right away.
+                next_attempt = Instant::now();
+            }
+        }
+    }
+
+    let kind = match attempts.last() {
+        Some((_, err)) => err.kind(),
+        None => io::ErrorKind::NotFound,
+    };
+    Err(io::Error::new(
+        kind,
+        ConnectError {
+            host: host.to_string(),
+            port,
+            attempts,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect_addrs, interleave, ConnectError, CONNECTION_ATTEMPT_DELAY};
+
+    use std::{
+        io,
+        net::{SocketAddr, TcpListener, TcpStream},
+        time::{Duration, Instant},
+    };
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    /// An address nothing listens on, connecting to it is refused right away.
+    fn refused() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    #[test]
+    fn interleave_starts_with_ipv6_and_alternates() {
+        assert_eq!(
+            interleave(addrs(&[
+                "1.1.1.1:443",
+                "2.2.2.2:443",
+                "[::1]:443",
+                "3.3.3.3:443",
+                "[::2]:443",
+            ])),
+            addrs(&[
+                "[::1]:443",
+                "1.1.1.1:443",
+                "[::2]:443",
+                "2.2.2.2:443",
+                "3.3.3.3:443",
+            ])
+        );
+        assert_eq!(
+            interleave(addrs(&[
+                "[::1]:443",
+                "[::2]:443",
+                "[::3]:443",
+                "1.1.1.1:443"
+            ])),
+            addrs(&["[::1]:443", "1.1.1.1:443", "[::2]:443", "[::3]:443"])
+        );
+        assert_eq!(interleave(Vec::new()), Vec::new());
+    }
+
+    // An attempt that gets no answer doesn't hold up the next address for longer than the attempt delay.
+    #[test]
+    #[cfg_attr(miri, ignore = "needs io_uring")]
+    fn stalled_address_falls_through_after_delay() {
+        // With its backlog filled, the listener drops further SYNs and connecting to it hangs.
+        let stalled = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut filler = Vec::new();
+        while let Ok(socket) =
+            TcpStream::connect_timeout(&stalled.local_addr().unwrap(), Duration::from_millis(100))
+        {
+            filler.push(socket);
+        }
+        let good = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        tokio_uring::start(async {
+            let start = Instant::now();
+            connect_addrs(
+                "localhost",
+                0,
+                vec![stalled.local_addr().unwrap(), good.local_addr().unwrap()],
+            )
+            .await
+            .unwrap();
+            let elapsed = start.elapsed();
+            assert!(elapsed >= CONNECTION_ATTEMPT_DELAY, "{:?}", elapsed);
+            assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+        });
+
+        good.set_nonblocking(true).unwrap();
+        good.accept().unwrap();
+    }
+
+    // When every address fails, the error lists each of them with its reason, in order.
+    #[test]
+    #[cfg_attr(miri, ignore = "needs io_uring")]
+    fn error_lists_every_attempt() {
+        let tried = vec![refused(), refused()];
+
+        let err = tokio_uring::start(connect_addrs("localhost", 443, tried.clone()))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        let err = err
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<ConnectError>()
+            .unwrap();
+        let attempts = err.attempts();
+        assert_eq!(
+            attempts.iter().map(|(addr, _)| *addr).collect::<Vec<_>>(),
+            tried
+        );
+        for (_, err) in attempts {
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        }
+        let message = err.to_string();
+        assert!(message.starts_with("failed to connect to localhost:443: "));
+        for addr in &tried {
+            assert!(message.contains(&addr.to_string()), "{}", message);
+        }
+
+        let err = tokio_uring::start(connect_addrs("localhost", 443, Vec::new()))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(
+            err.to_string(),
+            "localhost:443 did not resolve to any address"
+        );
+    }
+}
@@ -0,0 +1,225 @@
+use crate::stream::TlsStream;
+
+use rustls::{
+    client::HandshakeSignatureValid,
+    server::{ClientCertVerified, ClientCertVerifier},
+    Certificate, CertificateError, ConnectionCommon, DigitallySignedStruct, DistinguishedName,
+    SideData, SignatureScheme,
+};
+use std::{
+    io::{self, Error, ErrorKind},
+    net::IpAddr,
+    ops::Deref,
+    sync::Arc,
+    time::SystemTime,
+};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+/// Scheme of the URI that identifies a SPIFFE workload.
+const SPIFFE_SCHEME: &str = "spiffe://";
+
+/// The identity of a peer, taken from the end-entity certificate it authenticated with, see
+/// [`TlsStream::peer_identity`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerIdentity {
+    subject: String,
+    common_name: Option<String>,
+    dns_names: Vec<String>,
+    uris: Vec<String>,
+    emails: Vec<String>,
+    ips: Vec<IpAddr>,
+}
+
+impl PeerIdentity {
+    /// Parses a DER encoded certificate.
+    ///
+    /// The certificate is not verified, for a connection that is up to the verifier of the config.
+    pub fn from_der(der: &[u8]) -> io::Result<Self> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid peer certificate: {}", e),
+            )
+        };
+        let (_, cert) = X509Certificate::from_der(der).map_err(|e| invalid(&e))?;
+
+        let mut identity = PeerIdentity {
+            subject: cert.subject().to_string(),
+            common_name: cert
+                .subject()
+                .iter_common_name()
+                .next()
+                .and_then(|cn| cn.as_str().ok())
+                .map(str::to_owned),
+            ..Default::default()
+        };
+
+        let san = cert.subject_alternative_name().map_err(|e| invalid(&e))?;
+        for name in san.iter().flat_map(|san| &san.value.general_names) {
+            match name {
+                GeneralName::DNSName(name) => identity.dns_names.push(name.to_string()),
+                GeneralName::URI(uri) => identity.uris.push(uri.to_string()),
+                GeneralName::RFC822Name(email) => identity.emails.push(email.to_string()),
+                GeneralName::IPAddress(ip) => match ip.len() {
+                    4 => identity
+                        .ips
+                        .push(IpAddr::from(<[u8; 4]>::try_from(*ip).unwrap())),
+                    16 => identity
+                        .ips
+                        .push(IpAddr::from(<[u8; 16]>::try_from(*ip).unwrap())),
+                    _ => return Err(invalid(&"bad ip address in subject alternative name")),
+                },
+                _ => (),
+            }
+        }
+
+        Ok(identity)
+    }
+
+    /// The distinguished name of the subject, e.g. `CN=client, O=Example`.
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// The first common name of the subject.
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    /// The DNS names among the subject alternative names.
+    pub fn dns_names(&self) -> &[String] {
+        &self.dns_names
+    }
+
+    /// The URIs among the subject alternative names.
+    pub fn uris(&self) -> &[String] {
+        &self.uris
+    }
+
+    /// The email addresses among the subject alternative names.
+    pub fn emails(&self) -> &[String] {
+        &self.emails
+    }
+
+    /// The IP addresses among the subject alternative names.
+    pub fn ips(&self) -> &[IpAddr] {
+        &self.ips
+    }
+
+    /// The SPIFFE ID of the peer, e.g. `spiffe://example.org/service`.
+    ///
+    /// An X.509-SVID carries it as its only URI, a certificate with more than one URI has none.
+    pub fn spiffe_id(&self) -> Option<&str> {
+        match &self.uris[..] {
+            [uri] if uri.starts_with(SPIFFE_SCHEME) => Some(uri),
+            _ => None,
+        }
+    }
+}
+
+impl<C, SD: SideData> TlsStream<C>
+where
+    C: Deref<Target = ConnectionCommon<SD>>,
+{
+    /// The identity of the peer, parsed from the end-entity certificate it authenticated with.
+    ///
+    /// Returns `None` if the peer sent no certificate, e.g. a client when client authentication is optional.
+    pub fn peer_identity(&self) -> io::Result<Option<PeerIdentity>> {
+        match self
+            .session
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+        {
+            Some(cert) => PeerIdentity::from_der(&cert.0).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A client certificate verifier that runs an authorization callback on the identity of every client that
+/// `inner` has verified, e.g. to only let through some SPIFFE IDs.
+///
+/// A client the callback rejects fails the handshake, rustls sends it an `access_denied` alert and the accept
+/// fails with an `InvalidData` error carrying `CertificateError::ApplicationVerificationFailure`. This holds for
+/// every way of accepting a connection, as the check is part of the config. A client that sends no
+/// certificate, when `inner` allows it, is not seen by the callback.
+///
+/// Set it with `ServerConfig::builder().with_safe_defaults().with_client_cert_verifier(verifier)`:
+///
+/// ```ignore
+/// let verifier = AuthorizingVerifier::new(Arc::new(AllowAnyAuthenticatedClient::new(roots)), |peer| {
+///     peer.spiffe_id() == Some("spiffe://example.org/frontend")
+/// });
+/// ```
+pub struct AuthorizingVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    authorize: Box<dyn Fn(&PeerIdentity) -> bool + Send + Sync>,
+}
+
+impl AuthorizingVerifier {
+    pub fn new(
+        inner: Arc<dyn ClientCertVerifier>,
+        authorize: impl Fn(&PeerIdentity) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        AuthorizingVerifier {
+            inner,
+            authorize: Box::new(authorize),
+        }
+    }
+}
+
+impl ClientCertVerifier for AuthorizingVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.inner.client_auth_mandatory()
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        self.inner.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let verified = self
+            .inner
+            .verify_client_cert(end_entity, intermediates, now)?;
+        let identity = PeerIdentity::from_der(&end_entity.0)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        if (self.authorize)(&identity) {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
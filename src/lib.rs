@@ -1,12 +1,68 @@
+mod alpn;
 mod buffer;
 mod client;
+#[cfg(feature = "crl")]
+mod crl;
+mod der;
+mod drain;
+mod fs;
+#[cfg(feature = "handoff")]
+mod handoff;
+mod happy_eyeballs;
+#[cfg(feature = "identity")]
+mod identity;
+mod listener;
+mod maybe_tls;
+#[cfg(feature = "pem")]
+mod ocsp;
+#[cfg(feature = "pem")]
+pub mod pem;
+mod proxy;
+#[cfg(feature = "reload")]
+mod reload;
+mod resumption;
+mod router;
+mod runner;
 mod server;
+mod session_store;
 mod stream;
+pub mod starttls;
 mod split;
+#[cfg(feature = "tickets")]
+mod ticket_keys;
 
+pub use alpn::AlpnDispatcher;
 pub use client::TlsConnector;
+#[cfg(feature = "crl")]
+pub use crl::{CrlStatus, CrlVerifier};
+pub use drain::Drain;
+#[cfg(feature = "handoff")]
+pub use handoff::{ExportedStream, ResumedStream, SessionState};
+pub use happy_eyeballs::ConnectError;
+#[cfg(feature = "identity")]
+pub use identity::{AuthorizingVerifier, PeerIdentity};
+pub use listener::TlsListener;
+pub use maybe_tls::MaybeTlsStream;
+#[cfg(feature = "pem")]
+pub use ocsp::{OcspCertStatus, OcspEvent, OcspResolver, OcspResponse};
+pub use proxy::{ProxyHeader, ProxyMode};
+#[cfg(feature = "reload")]
+pub use reload::CertReloader;
+pub use resumption::{ClientSessionCache, ResumptionStats, ServerResumptionStats};
+pub use router::{PeekedClientHello, Route, SniRouter};
+pub use runner::ServerHandle;
+pub use runner::TlsServer;
+pub use server::LazyConfigAcceptor;
+pub use server::ReloadableTlsAcceptor;
+pub use server::StartHandshake;
 pub use server::TlsAcceptor;
+pub use session_store::FileSessionStore;
+pub use stream::HandshakeError;
 pub use stream::TlsStream;
+pub use stream::TlsStreamParts;
 pub use split::split;
 pub use split::ReadHalf;
+pub use split::ReuniteError;
 pub use split::WriteHalf;
+#[cfg(feature = "tickets")]
+pub use ticket_keys::TicketKeys;
@@ -0,0 +1,187 @@
+use crate::{proxy::ProxyMode, server::TlsAcceptor, stream::TlsStream};
+
+use rustls::ServerConnection;
+use std::{
+    io::{self, Error, ErrorKind},
+    net::{Shutdown, SocketAddr},
+    os::fd::{AsRawFd, BorrowedFd},
+    rc::Rc,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, Semaphore},
+    task::JoinHandle,
+};
+use tokio_uring::net::TcpListener;
+
+/// Maximum number of handshakes in flight by default.
+const DEFAULT_MAX_HANDSHAKES: usize = 256;
+
+/// Time a client gets to complete the handshake by default.
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Accepted = io::Result<(TlsStream<ServerConnection>, SocketAddr)>;
+
+/// A listener that performs the TLS handshake of incoming connections in the background.
+///
+/// Every accepted connection is handshaked in its own task, so a slow client doesn't hold up the others.
+/// [`TlsListener::accept`] only yields connections that completed the handshake. Connections that fail it, or
+/// don't complete it in time, are passed to the [`TlsListener::on_error`] callback instead.
+///
+/// The background work starts with the first call to `accept`, and stops when the listener is dropped.
+pub struct TlsListener {
+    listener: Rc<TcpListener>,
+    acceptor: TlsAcceptor,
+    max_handshakes: usize,
+    handshake_timeout: Duration,
+    proxy_protocol: Option<ProxyMode>,
+    on_error: Option<Rc<dyn Fn(SocketAddr, io::Error)>>,
+    incoming: Option<(mpsc::Receiver<Accepted>, JoinHandle<()>)>,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: TlsAcceptor) -> Self {
+        TlsListener {
+            listener: Rc::new(listener),
+            acceptor,
+            max_handshakes: DEFAULT_MAX_HANDSHAKES,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            proxy_protocol: None,
+            on_error: None,
+            incoming: None,
+        }
+    }
+
+    /// Sets how many handshakes may be in flight at once, 256 by default.
+    ///
+    /// Established connections that have not been picked up by [`TlsListener::accept`] yet count towards the
+    /// limit. Once it is reached, no new connections are accepted from the socket.
+    pub fn max_handshakes(mut self, max: usize) -> Self {
+        self.max_handshakes = max.max(1);
+        self
+    }
+
+    /// Sets how long a client gets to complete the handshake, 10 seconds by default.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Expects connections to start with a PROXY protocol header, see
+    /// [`TlsAcceptor::accept_proxied`].
+    ///
+    /// The header has to arrive within the handshake timeout. The address returned by
+    /// [`TlsListener::accept`] is still the one of the peer of the socket, the client's address is found in
+    /// [`TlsStream::proxy_header`].
+    pub fn proxy_protocol(mut self, mode: ProxyMode) -> Self {
+        self.proxy_protocol = Some(mode);
+        self
+    }
+
+    /// Sets a callback that is called with the peer address and the error of every failed handshake.
+    pub fn on_error(mut self, f: impl Fn(SocketAddr, io::Error) + 'static) -> Self {
+        self.on_error = Some(Rc::new(f));
+        self
+    }
+
+    /// The local address the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Waits for the next connection that completed the handshake.
+    ///
+    /// Errors are those of accepting from the socket, e.g. when running out of file descriptors. The listener
+    /// keeps going after one, so `accept` can be called again.
+    pub async fn accept(&mut self) -> io::Result<(TlsStream<ServerConnection>, SocketAddr)> {
+        if self.incoming.is_none() {
+            self.incoming = Some(self.start());
+        }
+
+        let (rx, _) = self.incoming.as_mut().expect("bug: accept loop expected");
+        // The accept loop never stops while the listener is alive, the channel can't be closed.
+        rx.recv().await.expect("bug: accept loop stopped")
+    }
+
+    fn start(&self) -> (mpsc::Receiver<Accepted>, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(self.max_handshakes);
+        let permits = Arc::new(Semaphore::new(self.max_handshakes));
+        let listener = self.listener.clone();
+        let acceptor = self.acceptor.clone();
+        let handshake_timeout = self.handshake_timeout;
+        let proxy_protocol = self.proxy_protocol;
+        let on_error = self.on_error.clone();
+
+        let task = tokio_uring::spawn(async move {
+            loop {
+                let permit = match permits.clone().acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => return,
+                };
+
+                let (socket, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        if tx.send(Err(e)).await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+
+                let tx = tx.clone();
+                let acceptor = acceptor.clone();
+                let on_error = on_error.clone();
+                tokio_uring::spawn(async move {
+                    // A handshake that times out while waiting for the client leaves its read in flight, and the
+                    // read keeps the socket open until the client sends something. Shutting the socket down
+                    // through a duplicate descriptor completes the read, so the connection is closed right away.
+                    // Safety: the descriptor is owned by `socket`, which is alive while the duplicate is made.
+                    let fd = unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) }
+                        .try_clone_to_owned()
+                        .ok();
+                    let handshake = async {
+                        match proxy_protocol {
+                            Some(mode) => acceptor.accept_proxied(socket, mode).await,
+                            None => acceptor.accept(socket).await,
+                        }
+                    };
+                    let result = match tokio::time::timeout(handshake_timeout, handshake).await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            if let Some(fd) = fd {
+                                let _ = std::net::TcpStream::from(fd).shutdown(Shutdown::Both);
+                            }
+                            Err(Error::new(ErrorKind::TimedOut, "tls handshake timed out"))
+                        }
+                    };
+
+                    match result {
+                        Ok(stream) => {
+                            let _ = tx.send(Ok((stream, addr))).await;
+                        }
+                        Err(e) => {
+                            if let Some(on_error) = on_error {
+                                on_error(addr, e);
+                            }
+                        }
+                    }
+
+                    // The permit is held until the connection has been handed over.
+                    drop(permit);
+                });
+            }
+        });
+
+        (rx, task)
+    }
+}
+
+impl Drop for TlsListener {
+    fn drop(&mut self) {
+        if let Some((_, task)) = &self.incoming {
+            task.abort();
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use crate::{server::TlsAcceptor, stream::TlsStream};
+
+use rustls::ServerConnection;
+use std::{io, net::Shutdown, os::fd::AsRawFd, time::Duration};
+use tokio::{
+    io::{unix::AsyncFd, Interest},
+    time::Instant,
+};
+use tokio_uring::{net::TcpStream, BufResult};
+
+/// Type of a TLS handshake record.
+const HANDSHAKE: u8 = 0x16;
+
+/// A connection that was accepted either with or without TLS, created by [`TlsAcceptor::accept_maybe_tls`].
+///
+/// Both variants are read from and written to the same way, so a handler can serve both, and check which one it
+/// got for things like redirecting plain HTTP to HTTPS.
+#[allow(clippy::large_enum_variant)]
+pub enum MaybeTlsStream {
+    /// The client started with a TLS handshake, which has been completed.
+    Tls(TlsStream<ServerConnection>),
+    /// The client started with something else.
+    Plain {
+        socket: TcpStream,
+        /// The bytes read while telling the protocols apart, which are returned by the first reads.
+        prefix: Vec<u8>,
+    },
+}
+
+impl TlsAcceptor {
+    /// Sets how long [`TlsAcceptor::accept_maybe_tls`] waits for the bytes that tell TLS from plaintext. A
+    /// connection that hasn't sent them by then is returned as plaintext, with whatever it sent, e.g. for a
+    /// protocol where the server speaks first. Without it, they are waited for as long as it takes.
+    pub fn maybe_tls_timeout(self, timeout: Duration) -> Self {
+        self.with_policy(|policy| policy.detect_timeout = Some(timeout))
+    }
+
+    /// Accepts a connection that may or may not start with a TLS handshake, for serving TLS and plaintext on the
+    /// same port.
+    ///
+    /// The first bytes are read to tell the two apart: a TLS handshake record runs the handshake with them,
+    /// anything else, including a connection closed before sending 2 bytes, is returned as is along with them.
+    /// So is a connection that doesn't send them in time, if [`TlsAcceptor::maybe_tls_timeout`] is set.
+    pub async fn accept_maybe_tls(&self, socket: TcpStream) -> io::Result<MaybeTlsStream> {
+        let deadline = self.policy().detect_timeout.map(|t| Instant::now() + t);
+        let mut prefix = Vec::new();
+        while prefix.len() < 2 && prefix.first().is_none_or(|b| *b == HANDSHAKE) {
+            if let Some(deadline) = deadline {
+                if !readable_by(&socket, deadline).await? {
+                    break;
+                }
+            }
+            let (res, buf) = socket.read(vec![0u8; 1024]).await;
+            match res? {
+                0 => break,
+                n => prefix.extend_from_slice(&buf[..n]),
+            }
+        }
+
+        if prefix.starts_with(&[HANDSHAKE, 0x03]) {
+            let stream = self.accept_with_prefix(socket, &prefix).await?;
+            Ok(MaybeTlsStream::Tls(stream))
+        } else {
+            Ok(MaybeTlsStream::Plain { socket, prefix })
+        }
+    }
+}
+
+/// Waits for `socket` to have data or be closed, returning false if `deadline` passes first.
+///
+/// A read can't be given up on instead, as bytes that arrive while it is being cancelled would be lost.
+async fn readable_by(socket: &TcpStream, deadline: Instant) -> io::Result<bool> {
+    let fd = AsyncFd::with_interest(socket.as_raw_fd(), Interest::READABLE)?;
+    match tokio::time::timeout_at(deadline, fd.readable()).await {
+        Ok(ready) => ready.map(|_| true),
+        Err(_) => Ok(false),
+    }
+}
+
+impl MaybeTlsStream {
+    pub fn is_tls(&self) -> bool {
+        matches!(self, MaybeTlsStream::Tls(_))
+    }
+
+    pub async fn read<B: tokio_uring::buf::IoBufMut>(&mut self, mut buf: B) -> BufResult<usize, B> {
+        match self {
+            MaybeTlsStream::Tls(stream) => stream.read(buf).await,
+            MaybeTlsStream::Plain { socket, prefix } if prefix.is_empty() => socket.read(buf).await,
+            MaybeTlsStream::Plain { prefix, .. } => {
+                let n = prefix.len().min(buf.bytes_total());
+                // Safety: n is within the capacity of the buffer, and the bytes up to it are initialized below.
+                let slice = unsafe { std::slice::from_raw_parts_mut(buf.stable_mut_ptr(), n) };
+                slice.copy_from_slice(&prefix[..n]);
+                unsafe { buf.set_init(n) };
+                prefix.drain(..n);
+                (Ok(n), buf)
+            }
+        }
+    }
+
+    pub async fn write<B: tokio_uring::buf::IoBuf>(&mut self, buf: B) -> BufResult<usize, B> {
+        match self {
+            MaybeTlsStream::Tls(stream) => stream.write(buf).await,
+            MaybeTlsStream::Plain { socket, .. } => socket.write(buf).await,
+        }
+    }
+
+    pub async fn write_all<B: tokio_uring::buf::IoBuf>(&mut self, buf: B) -> BufResult<(), B> {
+        match self {
+            MaybeTlsStream::Tls(stream) => stream.write_all(buf).await,
+            MaybeTlsStream::Plain { socket, .. } => socket.write_all(buf).await,
+        }
+    }
+
+    /// Shuts down the write side of the connection, after sending a close_notify alert for TLS.
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            MaybeTlsStream::Tls(stream) => stream.shutdown().await,
+            MaybeTlsStream::Plain { socket, .. } => socket.shutdown(Shutdown::Write),
+        }
+    }
+}
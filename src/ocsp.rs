@@ -0,0 +1,476 @@
+use crate::{
+    der,
+    fs::read_file,
+    pem::{self, with_path},
+    server::TlsAcceptor,
+};
+
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use std::{
+    io::{self, Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, RwLockReadGuard},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How often the files are checked for changes by default.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long before its `nextUpdate` time an expiring response is reported by default.
+const DEFAULT_WARN_BEFORE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// `id-pkix-ocsp-basic`, 1.3.6.1.5.5.7.48.1.1, the only response type in use.
+const OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const ENUMERATED: u8 = 0x0a;
+const GENERALIZED_TIME: u8 = 0x18;
+const EXPLICIT_0: u8 = 0xa0;
+
+/// Tags of the `CertStatus` choice.
+const STATUS_GOOD: u8 = 0x80;
+const STATUS_REVOKED: u8 = 0xa1;
+const STATUS_UNKNOWN: u8 = 0x82;
+
+/// The status of the certificate as given by an [`OcspResponse`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OcspCertStatus {
+    Good,
+    Revoked,
+    /// The responder doesn't know the certificate.
+    Unknown,
+}
+
+/// The parts of an OCSP response that concern the certificate it is stapled to.
+///
+/// The signature of the response is not checked, that is up to the clients it is stapled for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OcspResponse {
+    status: OcspCertStatus,
+    produced_at: SystemTime,
+    this_update: SystemTime,
+    next_update: Option<SystemTime>,
+}
+
+impl OcspResponse {
+    /// Parses a DER encoded OCSP response, as written by `openssl ocsp -respout`, for the certificate with the
+    /// given DER encoded serial number.
+    fn parse(der: &[u8], serial: &[u8]) -> io::Result<Self> {
+        let mut data = der;
+        let mut response = expect(&mut data, SEQUENCE)?;
+        match expect(&mut response, ENUMERATED)? {
+            [0] => (),
+            status => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("ocsp responder returned error status {:?}", status),
+                ))
+            }
+        }
+
+        let mut bytes = expect(&mut response, EXPLICIT_0)?;
+        let mut bytes = expect(&mut bytes, SEQUENCE)?;
+        if expect(&mut bytes, OID)? != OCSP_BASIC {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "unsupported ocsp response type",
+            ));
+        }
+        let mut basic = expect(&mut bytes, OCTET_STRING)?;
+        let mut basic = expect(&mut basic, SEQUENCE)?;
+        let mut data = expect(&mut basic, SEQUENCE)?;
+
+        // Version, which has a default, and responder id
+        if data.first() == Some(&EXPLICIT_0) {
+            take(&mut data)?;
+        }
+        take(&mut data)?;
+        let produced_at = parse_time(expect(&mut data, GENERALIZED_TIME)?)?;
+
+        let mut responses = expect(&mut data, SEQUENCE)?;
+        while !responses.is_empty() {
+            let mut single = expect(&mut responses, SEQUENCE)?;
+            // Hash algorithm, issuer name hash and issuer key hash come first
+            let mut cert_id = expect(&mut single, SEQUENCE)?;
+            for _ in 0..3 {
+                take(&mut cert_id)?;
+            }
+            if expect(&mut cert_id, INTEGER)? != serial {
+                continue;
+            }
+
+            let status = match take(&mut single)?.0 {
+                STATUS_GOOD => OcspCertStatus::Good,
+                STATUS_REVOKED => OcspCertStatus::Revoked,
+                STATUS_UNKNOWN => OcspCertStatus::Unknown,
+                _ => return Err(malformed()),
+            };
+            let this_update = parse_time(expect(&mut single, GENERALIZED_TIME)?)?;
+            let next_update = match single.first() {
+                Some(&EXPLICIT_0) => {
+                    let mut next = expect(&mut single, EXPLICIT_0)?;
+                    Some(parse_time(expect(&mut next, GENERALIZED_TIME)?)?)
+                }
+                _ => None,
+            };
+
+            return Ok(OcspResponse {
+                status,
+                produced_at,
+                this_update,
+                next_update,
+            });
+        }
+
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "ocsp response is not for the certificate",
+        ))
+    }
+
+    pub fn status(&self) -> OcspCertStatus {
+        self.status
+    }
+
+    /// When the responder signed the response.
+    pub fn produced_at(&self) -> SystemTime {
+        self.produced_at
+    }
+
+    /// The time the status is known to be correct at.
+    pub fn this_update(&self) -> SystemTime {
+        self.this_update
+    }
+
+    /// The time by which a newer response will be available, after which clients consider this one stale.
+    /// `None` if the responder always has newer information.
+    pub fn next_update(&self) -> Option<SystemTime> {
+        self.next_update
+    }
+}
+
+fn malformed() -> Error {
+    Error::new(ErrorKind::InvalidData, "malformed ocsp response")
+}
+
+/// Splits the next DER element off `data`, returning its tag and content.
+fn take<'a>(data: &mut &'a [u8]) -> io::Result<(u8, &'a [u8])> {
+    der::take(data).ok_or_else(malformed)
+}
+
+/// Like [`take`], for an element that must have the given tag.
+fn expect<'a>(data: &mut &'a [u8], tag: u8) -> io::Result<&'a [u8]> {
+    match take(data)? {
+        (t, content) if t == tag => Ok(content),
+        _ => Err(malformed()),
+    }
+}
+
+/// Returns the DER encoded serial number of a certificate.
+fn serial_number(cert: &[u8]) -> io::Result<&[u8]> {
+    let mut data = cert;
+    let mut cert = expect(&mut data, SEQUENCE)?;
+    let mut tbs = expect(&mut cert, SEQUENCE)?;
+    if tbs.first() == Some(&EXPLICIT_0) {
+        take(&mut tbs)?;
+    }
+    expect(&mut tbs, INTEGER)
+}
+
+/// Parses a `GeneralizedTime`, which is `YYYYMMDDHHMMSSZ` in DER, optionally with fractional seconds.
+fn parse_time(value: &[u8]) -> io::Result<SystemTime> {
+    let digits = |range: std::ops::Range<usize>| -> io::Result<u64> {
+        let s = value.get(range).ok_or_else(malformed)?;
+        if !s.iter().all(u8::is_ascii_digit) {
+            return Err(malformed());
+        }
+        Ok(s.iter().fold(0, |n, d| n * 10 + (d - b'0') as u64))
+    };
+    if value.last() != Some(&b'Z') {
+        return Err(malformed());
+    }
+
+    let (year, month, day) = (digits(0..4)?, digits(4..6)?, digits(6..8)?);
+    let (hour, minute, second) = (digits(8..10)?, digits(10..12)?, digits(12..14)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return Err(malformed());
+    }
+
+    // Days since the epoch, counting years from March so that the leap day comes last.
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era_days = y / 400 * 146097;
+    let year_of_era = y % 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era_days + day_of_era)
+        .checked_sub(719468)
+        .ok_or_else(malformed)?;
+
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// What [`OcspResolver::run`] reports to the callback set through [`OcspResolver::on_event`].
+#[derive(Debug)]
+pub enum OcspEvent {
+    /// The files changed and the new response is stapled from now on.
+    Updated(OcspResponse),
+    /// The response reaches its `nextUpdate` time within the warning period, without a newer one in sight.
+    Expiring(OcspResponse),
+    /// The response is past its `nextUpdate` time and is no longer stapled.
+    Expired(OcspResponse),
+    /// The files changed but could not be loaded, the previous certificate and response are kept.
+    Failed(io::Error),
+}
+
+/// Serves a certificate chain with a stapled OCSP response, both loaded from files that are checked for changes.
+///
+/// The response is expected to be refreshed on disk by another process, e.g. `openssl ocsp` run from cron. It is
+/// swapped in as soon as the file changes, along with the certificate chain and private key if those changed,
+/// so an acceptor built with the resolver never has to be rebuilt. A response past its `nextUpdate` time is no
+/// longer stapled, as clients would reject it. For the same reason, a new response that doesn't say the
+/// certificate is good, or that is already past its `nextUpdate` time, is not loaded, the previous one is kept.
+///
+/// ```ignore
+/// let resolver = Arc::new(
+///     OcspResolver::new("cert.pem", "key.pem", "cert.ocsp").await?
+///         .on_event(|event| eprintln!("ocsp: {:?}", event)),
+/// );
+/// let acceptor = acceptor.ocsp_resolver(resolver.clone());
+/// tokio_uring::spawn(async move { resolver.run().await });
+/// ```
+pub struct OcspResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    ocsp_path: PathBuf,
+    interval: Duration,
+    warn_before: Duration,
+    on_event: Option<Box<dyn Fn(OcspEvent) + Send + Sync>>,
+    current: RwLock<Current>,
+}
+
+struct Current {
+    key: Arc<CertifiedKey>,
+    response: OcspResponse,
+    files: (Vec<u8>, Vec<u8>, Vec<u8>),
+    warned: bool,
+    expired: bool,
+}
+
+impl OcspResolver {
+    /// Loads the certificate chain and private key from PEM files, and the DER encoded OCSP response for the
+    /// end-entity certificate, which must say the certificate is good and not be past its `nextUpdate` time.
+    pub async fn new(
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+        ocsp_path: impl AsRef<Path>,
+    ) -> io::Result<Self> {
+        let cert_path = cert_path.as_ref().to_path_buf();
+        let key_path = key_path.as_ref().to_path_buf();
+        let ocsp_path = ocsp_path.as_ref().to_path_buf();
+
+        let files = (
+            read(&cert_path).await?,
+            read(&key_path).await?,
+            read(&ocsp_path).await?,
+        );
+        let (key, response) = load(&files, &cert_path, &key_path, &ocsp_path)?;
+        let current = Current {
+            key: Arc::new(key),
+            response,
+            files,
+            warned: false,
+            expired: false,
+        };
+
+        Ok(OcspResolver {
+            cert_path,
+            key_path,
+            ocsp_path,
+            interval: DEFAULT_INTERVAL,
+            warn_before: DEFAULT_WARN_BEFORE,
+            on_event: None,
+            current: RwLock::new(current),
+        })
+    }
+
+    /// Sets how often [`OcspResolver::run`] checks the files for changes, a minute by default.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets how long before its `nextUpdate` time a response that hasn't been replaced is reported as
+    /// [`OcspEvent::Expiring`], a day by default.
+    pub fn warn_before(mut self, warn_before: Duration) -> Self {
+        self.warn_before = warn_before;
+        self
+    }
+
+    /// Sets a callback that is told about updates, failures and expiring responses by [`OcspResolver::run`].
+    pub fn on_event(mut self, f: impl Fn(OcspEvent) + Send + Sync + 'static) -> Self {
+        self.on_event = Some(Box::new(f));
+        self
+    }
+
+    /// The response currently loaded, whether it is still stapled or not.
+    pub fn response(&self) -> OcspResponse {
+        self.read().response.clone()
+    }
+
+    /// Whether the response is stapled, which stops once it is past its `nextUpdate` time.
+    pub fn is_stapled(&self) -> bool {
+        !self.read().expired
+    }
+
+    /// Checks the files for changes at every interval, swapping in the new certificate and response, and checks
+    /// whether the response is about to expire.
+    ///
+    /// Never returns, it is meant to be spawned on the runtime.
+    pub async fn run(&self) {
+        loop {
+            tokio::time::sleep(self.interval).await;
+
+            if let Some(event) = self.reload().await {
+                self.report(event);
+            }
+            if let Some(event) = self.check_expiry() {
+                self.report(event);
+            }
+        }
+    }
+
+    /// Reads the files, loading them if they changed since the last time.
+    async fn reload(&self) -> Option<OcspEvent> {
+        let files = match (
+            read(&self.cert_path).await,
+            read(&self.key_path).await,
+            read(&self.ocsp_path).await,
+        ) {
+            (Ok(cert), Ok(key), Ok(ocsp)) => (cert, key, ocsp),
+            // A file that can't be read is likely in the middle of being replaced, the next check will pick it
+            // up.
+            _ => return None,
+        };
+        if self.read().files == files {
+            return None;
+        }
+
+        let loaded = load(&files, &self.cert_path, &self.key_path, &self.ocsp_path);
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        // Remember the contents even if they were rejected, so a broken file is reported once.
+        current.files = files;
+        match loaded {
+            Ok((key, response)) => {
+                current.key = Arc::new(key);
+                current.response = response.clone();
+                current.warned = false;
+                current.expired = false;
+                Some(OcspEvent::Updated(response))
+            }
+            Err(e) => Some(OcspEvent::Failed(e)),
+        }
+    }
+
+    fn check_expiry(&self) -> Option<OcspEvent> {
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        let next_update = current.response.next_update;
+
+        if !current.expired && is_past(next_update, Duration::ZERO) {
+            current.unstaple();
+            return Some(OcspEvent::Expired(current.response.clone()));
+        }
+        if !current.warned && !current.expired && is_past(next_update, self.warn_before) {
+            current.warned = true;
+            return Some(OcspEvent::Expiring(current.response.clone()));
+        }
+        None
+    }
+
+    fn report(&self, event: OcspEvent) {
+        if let Some(on_event) = &self.on_event {
+            on_event(event);
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Current> {
+        // The lock is never held across anything that can panic, poisoning can be ignored.
+        self.current.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Current {
+    /// Stops stapling the response.
+    fn unstaple(&mut self) {
+        let mut key = CertifiedKey::clone(&self.key);
+        key.ocsp = None;
+        self.key = Arc::new(key);
+        self.expired = true;
+    }
+}
+
+impl ResolvesServerCert for OcspResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.read().key.clone())
+    }
+}
+
+impl TlsAcceptor {
+    /// Serves the certificate chain and stapled OCSP response of `resolver`, which keeps them up to date.
+    ///
+    /// The acceptor gets a copy of its config with the resolver set.
+    pub fn ocsp_resolver(self, resolver: Arc<OcspResolver>) -> Self {
+        self.with_config(|config| config.cert_resolver = resolver)
+    }
+}
+
+async fn read(path: &Path) -> io::Result<Vec<u8>> {
+    read_file(path).await.map_err(|e| with_path(e, path))
+}
+
+/// Whether `time` is less than `margin` away, `None` never being.
+fn is_past(time: Option<SystemTime>, margin: Duration) -> bool {
+    time.is_some_and(|time| SystemTime::now() + margin >= time)
+}
+
+/// Parses the files, checking that the key matches the certificate, and that the response is for it, says it is
+/// good and is not past its `nextUpdate` time.
+fn load(
+    (cert, key, ocsp): &(Vec<u8>, Vec<u8>, Vec<u8>),
+    cert_path: &Path,
+    key_path: &Path,
+    ocsp_path: &Path,
+) -> io::Result<(CertifiedKey, OcspResponse)> {
+    let certs = pem::parse_certs(cert).map_err(|e| with_path(e, cert_path))?;
+    let key = pem::parse_private_key(key).map_err(|e| with_path(e, key_path))?;
+    let mut certified = pem::certified_key(certs, &key).map_err(|e| with_path(e, key_path))?;
+
+    let serial = serial_number(&certified.cert[0].0).map_err(|e| with_path(e, cert_path))?;
+    let response = OcspResponse::parse(ocsp, serial).map_err(|e| with_path(e, ocsp_path))?;
+    // Clients would reject the connection over either of these, it is better to keep the response in use.
+    let unusable = match response.status {
+        OcspCertStatus::Good if is_past(response.next_update, Duration::ZERO) => {
+            Some("ocsp response is past its nextUpdate time".to_string())
+        }
+        OcspCertStatus::Good => None,
+        status => Some(format!("ocsp response gives certificate status {:?}", status)),
+    };
+    if let Some(message) = unusable {
+        return Err(with_path(
+            Error::new(ErrorKind::InvalidData, message),
+            ocsp_path,
+        ));
+    }
+    certified.ocsp = Some(ocsp.clone());
+    Ok((certified, response))
+}
@@ -0,0 +1,341 @@
+//! Loading certificates and private keys.
+//!
+//! The parsers accept PEM, as well as a single DER encoded item for input that holds no PEM markers. The
+//! `load_*` functions read files through the ring. All failures are reported as `io::Error`s carrying a
+//! [`PemError`], which can be retrieved with `io::Error::get_ref`.
+
+use crate::{fs::read_file, TlsAcceptor, TlsConnector};
+
+use rustls::{
+    server::UnparsedCertRevocationList,
+    sign::{any_supported_type, CertifiedKey},
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, SignatureScheme,
+};
+use rustls_pemfile::Item;
+use std::{
+    error::Error,
+    fmt,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Error returned when certificates or keys can't be loaded.
+#[derive(Debug)]
+pub struct PemError {
+    path: Option<PathBuf>,
+    kind: PemErrorKind,
+}
+
+/// The reason certificates or keys could not be loaded, see [`PemError`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PemErrorKind {
+    /// The file could not be read.
+    Io(io::Error),
+    /// The input is empty.
+    Empty,
+    /// The input is not valid PEM.
+    Malformed(io::Error),
+    /// The input holds PEM items, but none of the expected type.
+    WrongItem {
+        expected: &'static str,
+        found: Vec<&'static str>,
+    },
+    /// The private key is of a type rustls can't sign with.
+    UnsupportedKey,
+    /// The private key does not belong to the end entity certificate.
+    KeyMismatch,
+    /// None of the certificates in a CA bundle could be parsed.
+    NoValidCertificates { ignored: usize },
+}
+
+impl PemError {
+    /// The file the error occurred in, if loading from a file.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// What went wrong.
+    pub fn kind(&self) -> &PemErrorKind {
+        &self.kind
+    }
+}
+
+impl fmt::Display for PemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(path) = &self.path {
+            write!(f, "{}: ", path.display())?;
+        }
+        match &self.kind {
+            PemErrorKind::Io(e) => write!(f, "{}", e),
+            PemErrorKind::Empty => write!(f, "no data"),
+            PemErrorKind::Malformed(e) => write!(f, "malformed pem: {}", e),
+            PemErrorKind::WrongItem { expected, found } => {
+                write!(f, "expected {}, found {}", expected, found.join(", "))
+            }
+            PemErrorKind::UnsupportedKey => write!(f, "unsupported private key type"),
+            PemErrorKind::KeyMismatch => {
+                write!(f, "private key does not match the certificate")
+            }
+            PemErrorKind::NoValidCertificates { ignored } => {
+                write!(f, "no valid certificate, {} ignored", ignored)
+            }
+        }
+    }
+}
+
+impl Error for PemError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.kind {
+            PemErrorKind::Io(e) | PemErrorKind::Malformed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn error(kind: PemErrorKind) -> io::Error {
+    let io_kind = match &kind {
+        PemErrorKind::Io(e) => e.kind(),
+        _ => ErrorKind::InvalidData,
+    };
+    io::Error::new(io_kind, PemError { path: None, kind })
+}
+
+/// Attaches `path` to an error coming from one of the parsers, or wraps a read error.
+pub(crate) fn with_path(err: io::Error, path: &Path) -> io::Error {
+    let kind = err.kind();
+    let mut err = if err.get_ref().is_some_and(|e| e.is::<PemError>()) {
+        *err.into_inner()
+            .and_then(|e| e.downcast::<PemError>().ok())
+            .expect("bug: pem error expected")
+    } else {
+        PemError {
+            path: None,
+            kind: PemErrorKind::Io(err),
+        }
+    };
+    err.path = Some(path.to_path_buf());
+    io::Error::new(kind, err)
+}
+
+fn item_name(item: &Item) -> &'static str {
+    match item {
+        Item::X509Certificate(_) => "certificate",
+        Item::RSAKey(_) => "RSA private key",
+        Item::PKCS8Key(_) => "PKCS#8 private key",
+        Item::ECKey(_) => "SEC1 private key",
+        Item::Crl(_) => "CRL",
+        _ => "unknown item",
+    }
+}
+
+fn is_pem(data: &[u8]) -> bool {
+    data.windows(11).any(|w| w == b"-----BEGIN ")
+}
+
+/// Parses every PEM item in `data`, treating input without PEM markers as a single DER item built by `der`.
+fn read_items(data: &[u8], der: impl FnOnce(Vec<u8>) -> Item) -> io::Result<Vec<Item>> {
+    if data.iter().all(u8::is_ascii_whitespace) {
+        return Err(error(PemErrorKind::Empty));
+    }
+    if !is_pem(data) {
+        return Ok(vec![der(data.to_vec())]);
+    }
+    match rustls_pemfile::read_all(&mut &*data) {
+        Ok(items) => Ok(items),
+        Err(e) => Err(error(PemErrorKind::Malformed(e))),
+    }
+}
+
+fn wrong_item(expected: &'static str, items: &[Item]) -> io::Error {
+    error(PemErrorKind::WrongItem {
+        expected,
+        found: items.iter().map(item_name).collect(),
+    })
+}
+
+/// Parses a certificate chain, in the order it is sent to the peer, end entity certificate first.
+pub fn parse_certs(data: &[u8]) -> io::Result<Vec<Certificate>> {
+    let items = read_items(data, Item::X509Certificate)?;
+    let certs: Vec<_> = items
+        .iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der.clone())),
+            _ => None,
+        })
+        .collect();
+
+    if certs.is_empty() {
+        return Err(wrong_item("certificate", &items));
+    }
+    Ok(certs)
+}
+
+/// Parses the first RSA (PKCS#1), PKCS#8 or SEC1 private key, and checks that rustls can sign with it.
+pub fn parse_private_key(data: &[u8]) -> io::Result<PrivateKey> {
+    let items = read_items(data, Item::PKCS8Key)?;
+    let key = items.iter().find_map(|item| match item {
+        Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der.clone())),
+        _ => None,
+    });
+
+    let key = match key {
+        Some(key) => key,
+        None => return Err(wrong_item("private key", &items)),
+    };
+    if any_supported_type(&key).is_err() {
+        return Err(error(PemErrorKind::UnsupportedKey));
+    }
+    Ok(key)
+}
+
+/// Parses a CA bundle into a root store.
+///
+/// Certificates that can't be parsed are skipped, as is common for system bundles. It fails only if none of
+/// them can be used.
+pub fn parse_root_store(data: &[u8]) -> io::Result<RootCertStore> {
+    let certs: Vec<_> = parse_certs(data)?.into_iter().map(|c| c.0).collect();
+
+    let mut roots = RootCertStore::empty();
+    let (added, ignored) = roots.add_parsable_certificates(&certs);
+    if added == 0 {
+        return Err(error(PemErrorKind::NoValidCertificates { ignored }));
+    }
+    Ok(roots)
+}
+
+/// Parses certificate revocation lists, checking that rustls can use them.
+pub fn parse_crls(data: &[u8]) -> io::Result<Vec<UnparsedCertRevocationList>> {
+    let items = read_items(data, Item::Crl)?;
+    let crls: Vec<_> = items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Crl(der) => Some(UnparsedCertRevocationList(der.clone())),
+            _ => None,
+        })
+        .collect();
+
+    if crls.is_empty() {
+        return Err(wrong_item("CRL", &items));
+    }
+    for crl in &crls {
+        if let Err(e) = crl.parse() {
+            return Err(error(PemErrorKind::Malformed(io::Error::new(
+                ErrorKind::InvalidData,
+                rustls::Error::from(e),
+            ))));
+        }
+    }
+    Ok(crls)
+}
+
+/// Pairs a certificate chain with its private key, checking that the key belongs to the end entity
+/// certificate.
+///
+/// The check signs a message with the key and verifies it against the certificate's public key.
+pub fn certified_key(certs: Vec<Certificate>, key: &PrivateKey) -> io::Result<CertifiedKey> {
+    let signing_key = match any_supported_type(key) {
+        Ok(key) => key,
+        Err(_) => return Err(error(PemErrorKind::UnsupportedKey)),
+    };
+    let end_entity = match certs.first() {
+        Some(cert) => cert,
+        None => return Err(wrong_item("certificate", &[])),
+    };
+    let end_entity = match webpki::EndEntityCert::try_from(end_entity.0.as_slice()) {
+        Ok(cert) => cert,
+        Err(e) => {
+            return Err(error(PemErrorKind::Malformed(io::Error::new(
+                ErrorKind::InvalidData,
+                e.to_string(),
+            ))))
+        }
+    };
+
+    const MESSAGE: &[u8] = b"tokio-uring-rustls key check";
+    let schemes = [
+        SignatureScheme::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        SignatureScheme::RSA_PKCS1_SHA256,
+    ];
+    let matches = signing_key.choose_scheme(&schemes).is_some_and(|signer| {
+        let alg = match signer.scheme() {
+            SignatureScheme::ED25519 => &webpki::ED25519,
+            SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+            _ => &webpki::RSA_PKCS1_2048_8192_SHA256,
+        };
+        match signer.sign(MESSAGE) {
+            Ok(sig) => end_entity.verify_signature(alg, MESSAGE, &sig).is_ok(),
+            Err(_) => false,
+        }
+    });
+    if !matches {
+        return Err(error(PemErrorKind::KeyMismatch));
+    }
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+async fn load<T>(path: &Path, parse: impl FnOnce(&[u8]) -> io::Result<T>) -> io::Result<T> {
+    read_file(path)
+        .await
+        .and_then(|data| parse(&data))
+        .map_err(|e| with_path(e, path))
+}
+
+/// Loads a certificate chain from a file, see [`parse_certs`].
+pub async fn load_certs(path: impl AsRef<Path>) -> io::Result<Vec<Certificate>> {
+    load(path.as_ref(), parse_certs).await
+}
+
+/// Loads a private key from a file, see [`parse_private_key`].
+pub async fn load_private_key(path: impl AsRef<Path>) -> io::Result<PrivateKey> {
+    load(path.as_ref(), parse_private_key).await
+}
+
+/// Loads a CA bundle from a file, see [`parse_root_store`].
+pub async fn load_root_store(path: impl AsRef<Path>) -> io::Result<RootCertStore> {
+    load(path.as_ref(), parse_root_store).await
+}
+
+/// Loads certificate revocation lists from a file, see [`parse_crls`].
+pub async fn load_crls(path: impl AsRef<Path>) -> io::Result<Vec<UnparsedCertRevocationList>> {
+    load(path.as_ref(), parse_crls).await
+}
+
+impl TlsAcceptor {
+    /// Creates an acceptor with rustls' safe defaults and no client authentication, serving the certificate
+    /// chain and private key loaded from the given files.
+    pub async fn from_pem_files(
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> io::Result<TlsAcceptor> {
+        let certs = load_certs(cert_path.as_ref()).await?;
+        let key = load_private_key(key_path.as_ref()).await?;
+        certified_key(certs.clone(), &key).map_err(|e| with_path(e, key_path.as_ref()))?;
+
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(io::Error::other)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+impl TlsConnector {
+    /// Creates a connector with rustls' safe defaults and no client authentication, trusting the CA bundle
+    /// loaded from the given file.
+    pub async fn from_pem_file(ca_path: impl AsRef<Path>) -> io::Result<TlsConnector> {
+        let roots = load_root_store(ca_path).await?;
+
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+}
@@ -0,0 +1,503 @@
+use std::{
+    io::{self, Error, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio_uring::net::TcpStream;
+
+/// Signature that starts a version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest possible version 1 header, including the line ending.
+const V1_MAX_LEN: usize = 107;
+
+/// Whether connections have to start with a PROXY protocol header, see
+/// [`TlsAcceptor::accept_proxied`](crate::TlsAcceptor::accept_proxied).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyMode {
+    /// Connections without a header are rejected.
+    Strict,
+    /// Connections without a header are accepted, e.g. for clients that bypass the load balancer.
+    ///
+    /// Anyone who can reach the port directly can then claim any source address, so this is only safe when
+    /// those clients are trusted.
+    Optional,
+}
+
+/// A PROXY protocol header, version 1 or 2, as sent by a load balancer ahead of the client's data.
+///
+/// Available on the stream through [`TlsStream::proxy_header`](crate::TlsStream::proxy_header).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyHeader {
+    version: u8,
+    source: Option<SocketAddr>,
+    destination: Option<SocketAddr>,
+    tlvs: Vec<(u8, Vec<u8>)>,
+}
+
+impl ProxyHeader {
+    /// Application protocol, as negotiated with the client by the proxy.
+    pub const TLV_ALPN: u8 = 0x01;
+    /// Host name the client asked for, as sent in SNI.
+    pub const TLV_AUTHORITY: u8 = 0x02;
+    /// Checksum of the header, verified while parsing.
+    pub const TLV_CRC32C: u8 = 0x03;
+    /// Padding, without meaning.
+    pub const TLV_NOOP: u8 = 0x04;
+    /// Opaque connection id assigned by the proxy.
+    pub const TLV_UNIQUE_ID: u8 = 0x05;
+    /// Details about a TLS connection the proxy terminated, made up of sub-TLVs.
+    pub const TLV_SSL: u8 = 0x20;
+    /// Network namespace the connection was accepted in.
+    pub const TLV_NETNS: u8 = 0x30;
+
+    /// The protocol version the header was sent with, 1 or 2.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Address of the client as seen by the proxy.
+    ///
+    /// `None` when the proxy did not relay a TCP connection, e.g. for its own health checks, which are sent as
+    /// `LOCAL` in version 2 and `UNKNOWN` in version 1.
+    pub fn source(&self) -> Option<SocketAddr> {
+        self.source
+    }
+
+    /// Address the client connected to on the proxy, see [`ProxyHeader::source`].
+    pub fn destination(&self) -> Option<SocketAddr> {
+        self.destination
+    }
+
+    /// The type and value of every TLV in the header, in the order they were sent. Always empty for
+    /// version 1.
+    pub fn tlvs(&self) -> impl Iterator<Item = (u8, &[u8])> {
+        self.tlvs.iter().map(|(kind, value)| (*kind, &value[..]))
+    }
+
+    /// The value of the first TLV of the given type, see the `TLV_` constants for the registered ones.
+    pub fn tlv(&self, kind: u8) -> Option<&[u8]> {
+        self.tlvs().find(|(k, _)| *k == kind).map(|(_, v)| v)
+    }
+}
+
+fn malformed(msg: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("invalid proxy protocol header: {}", msg),
+    )
+}
+
+/// Reads the PROXY protocol header off the socket.
+///
+/// Returns the header, `None` if there was none in [`ProxyMode::Optional`], along with any bytes read past it.
+pub(crate) async fn read_header(
+    io: &TcpStream,
+    mode: ProxyMode,
+) -> io::Result<(Option<ProxyHeader>, Vec<u8>)> {
+    let mut buf = Vec::new();
+    loop {
+        if !buf.is_empty() {
+            if let Some((header, len)) = parse(&buf, mode)? {
+                buf.drain(..len);
+                return Ok((header, buf));
+            }
+        }
+
+        let (res, chunk) = io.read(vec![0u8; 1024]).await;
+        match res? {
+            0 => {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "connection closed before the proxy protocol header",
+                ))
+            }
+            n => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+/// Parses a header at the start of `buf`, returning it along with its length, or `None` if more data is needed.
+#[allow(clippy::type_complexity)]
+fn parse(buf: &[u8], mode: ProxyMode) -> io::Result<Option<(Option<ProxyHeader>, usize)>> {
+    let n = buf.len().min(V2_SIGNATURE.len());
+    if buf[..n] == V2_SIGNATURE[..n] {
+        if buf.len() < 16 {
+            return Ok(None);
+        }
+        let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        if buf.len() < len {
+            return Ok(None);
+        }
+        return parse_v2(&buf[..len]).map(|header| Some((Some(header), len)));
+    }
+
+    let n = buf.len().min(6);
+    if buf[..n] == b"PROXY "[..n] {
+        return match buf.windows(2).position(|w| w == b"\r\n") {
+            Some(pos) if pos + 2 <= V1_MAX_LEN => {
+                parse_v1(&buf[..pos]).map(|header| Some((Some(header), pos + 2)))
+            }
+            None if buf.len() < V1_MAX_LEN => Ok(None),
+            _ => Err(malformed("line too long")),
+        };
+    }
+
+    match mode {
+        ProxyMode::Strict => Err(Error::new(
+            ErrorKind::InvalidData,
+            "missing proxy protocol header",
+        )),
+        ProxyMode::Optional => Ok(Some((None, 0))),
+    }
+}
+
+/// Parses a version 1 header, without the line ending.
+fn parse_v1(line: &[u8]) -> io::Result<ProxyHeader> {
+    let line = std::str::from_utf8(line).map_err(|_| malformed("not ascii"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    let (source, destination) = match fields[1..] {
+        ["UNKNOWN", ..] => (None, None),
+        [family @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let ip = |s: &str| -> io::Result<IpAddr> {
+                let ip = match family {
+                    "TCP4" => s.parse::<Ipv4Addr>().map(IpAddr::V4),
+                    _ => s.parse::<Ipv6Addr>().map(IpAddr::V6),
+                };
+                ip.map_err(|_| malformed("bad address"))
+            };
+            let port = |s: &str| s.parse::<u16>().map_err(|_| malformed("bad port"));
+            (
+                Some(SocketAddr::new(ip(src)?, port(sport)?)),
+                Some(SocketAddr::new(ip(dst)?, port(dport)?)),
+            )
+        }
+        _ => return Err(malformed("bad protocol line")),
+    };
+
+    Ok(ProxyHeader {
+        version: 1,
+        source,
+        destination,
+        tlvs: Vec::new(),
+    })
+}
+
+/// Parses a complete version 2 header, including the signature.
+fn parse_v2(header: &[u8]) -> io::Result<ProxyHeader> {
+    let local = match header[12] {
+        0x20 => true,
+        0x21 => false,
+        _ => return Err(malformed("unsupported version or command")),
+    };
+
+    let body = &header[16..];
+    let (addrs_len, stream) = match header[13] {
+        0x00 => (0, false),
+        0x11 | 0x12 => (12, header[13] == 0x11),
+        0x21 | 0x22 => (36, header[13] == 0x21),
+        0x31 | 0x32 => (216, false),
+        _ => return Err(malformed("unsupported address family")),
+    };
+    if body.len() < addrs_len {
+        return Err(malformed("truncated addresses"));
+    }
+    let (addrs, mut rest) = body.split_at(addrs_len);
+
+    // Only TCP over IP is represented as addresses, the receiver has to ignore them for LOCAL connections.
+    let (source, destination) = match addrs_len {
+        12 if stream && !local => {
+            let ip = |b: &[u8]| IpAddr::from(<[u8; 4]>::try_from(b).unwrap());
+            let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+            (
+                Some(SocketAddr::new(ip(&addrs[0..4]), port(&addrs[8..10]))),
+                Some(SocketAddr::new(ip(&addrs[4..8]), port(&addrs[10..12]))),
+            )
+        }
+        36 if stream && !local => {
+            let ip = |b: &[u8]| IpAddr::from(<[u8; 16]>::try_from(b).unwrap());
+            let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+            (
+                Some(SocketAddr::new(ip(&addrs[0..16]), port(&addrs[32..34]))),
+                Some(SocketAddr::new(ip(&addrs[16..32]), port(&addrs[34..36]))),
+            )
+        }
+        _ => (None, None),
+    };
+
+    let mut tlvs = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < 3 {
+            return Err(malformed("truncated tlv"));
+        }
+        let kind = rest[0];
+        let len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+        if rest.len() < 3 + len {
+            return Err(malformed("truncated tlv"));
+        }
+        let value = &rest[3..3 + len];
+
+        if kind == ProxyHeader::TLV_CRC32C {
+            // The checksum covers the whole header, with the checksum itself zeroed.
+            let offset = header.len() - rest.len() + 3;
+            let mut zeroed = header.to_vec();
+            zeroed[offset..offset + len].fill(0);
+            if len != 4 || crc32c(&zeroed).to_be_bytes() != value {
+                return Err(malformed("checksum mismatch"));
+            }
+        }
+
+        tlvs.push((kind, value.to_vec()));
+        rest = &rest[3 + len..];
+    }
+
+    Ok(ProxyHeader {
+        version: 2,
+        source,
+        destination,
+        tlvs,
+    })
+}
+
+/// CRC-32C (Castagnoli), as used by the checksum TLV.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f63b78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32c, parse, ProxyHeader, ProxyMode, V2_SIGNATURE};
+
+    use std::{io::ErrorKind, net::SocketAddr};
+
+    const TCP4_ADDRS: [u8; 12] = [192, 0, 2, 1, 198, 51, 100, 2, 0x30, 0x39, 0x01, 0xbb];
+
+    /// A version 2 header with the given command, address family and protocol, addresses and TLVs.
+    fn v2(command: u8, family: u8, addrs: &[u8], tlvs: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut body = addrs.to_vec();
+        for (kind, value) in tlvs {
+            body.push(*kind);
+            body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            body.extend_from_slice(value);
+        }
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[command, family]);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(&body);
+        header
+    }
+
+    /// A version 2 header ending with a checksum TLV, filled in over the rest of it.
+    fn v2_with_crc(addrs: &[u8]) -> Vec<u8> {
+        let mut header = v2(0x21, 0x11, addrs, &[(ProxyHeader::TLV_CRC32C, &[0; 4])]);
+        let crc = crc32c(&header).to_be_bytes();
+        let len = header.len();
+        header[len - 4..].copy_from_slice(&crc);
+        header
+    }
+
+    fn parsed(buf: &[u8]) -> (ProxyHeader, usize) {
+        match parse(buf, ProxyMode::Strict).unwrap() {
+            Some((Some(header), len)) => (header, len),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn error(buf: &[u8], mode: ProxyMode) -> String {
+        let err = parse(buf, mode).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        err.to_string()
+    }
+
+    fn addr(s: &str) -> Option<SocketAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let (header, len) = parsed(b"PROXY TCP4 192.0.2.1 198.51.100.2 12345 443\r\n\x16\x03\x01");
+        assert_eq!(len, 45);
+        assert_eq!(header.version(), 1);
+        assert_eq!(header.source(), addr("192.0.2.1:12345"));
+        assert_eq!(header.destination(), addr("198.51.100.2:443"));
+        assert_eq!(header.tlvs().count(), 0);
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let (header, _) = parsed(b"PROXY TCP6 2001:db8::1 2001:db8::2 12345 443\r\n");
+        assert_eq!(header.source(), addr("[2001:db8::1]:12345"));
+        assert_eq!(header.destination(), addr("[2001:db8::2]:443"));
+
+        // The address has to match the family.
+        assert!(error(
+            b"PROXY TCP6 192.0.2.1 198.51.100.2 1 2\r\n",
+            ProxyMode::Strict
+        )
+        .contains("bad address"));
+        assert!(error(
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 1 65536\r\n",
+            ProxyMode::Strict
+        )
+        .contains("bad port"));
+    }
+
+    #[test]
+    fn v1_unknown() {
+        for line in [&b"PROXY UNKNOWN\r\n"[..], b"PROXY UNKNOWN ::1 ::2 1 2\r\n"] {
+            let (header, len) = parsed(line);
+            assert_eq!(len, line.len());
+            assert_eq!(header.source(), None);
+            assert_eq!(header.destination(), None);
+        }
+    }
+
+    #[test]
+    fn v1_overlong_line() {
+        let mut line = b"PROXY TCP6 ".to_vec();
+        line.resize(super::V1_MAX_LEN, b'f');
+        assert!(error(&line, ProxyMode::Strict).contains("line too long"));
+
+        // Even with the line ending just past the limit.
+        line.truncate(super::V1_MAX_LEN - 1);
+        line.extend_from_slice(b"\r\n");
+        assert!(error(&line, ProxyMode::Strict).contains("line too long"));
+    }
+
+    #[test]
+    fn v2_proxy_and_local() {
+        let (header, len) = parsed(&v2(0x21, 0x11, &TCP4_ADDRS, &[]));
+        assert_eq!(len, 28);
+        assert_eq!(header.version(), 2);
+        assert_eq!(header.source(), addr("192.0.2.1:12345"));
+        assert_eq!(header.destination(), addr("198.51.100.2:443"));
+
+        let mut addrs = [0u8; 36];
+        addrs[15] = 1;
+        addrs[31] = 2;
+        addrs[32..].copy_from_slice(&[0x30, 0x39, 0x01, 0xbb]);
+        let (header, _) = parsed(&v2(0x21, 0x21, &addrs, &[]));
+        assert_eq!(header.source(), addr("[::1]:12345"));
+        assert_eq!(header.destination(), addr("[::2]:443"));
+
+        // The addresses of a LOCAL connection are ignored, as are those of anything but TCP.
+        for (command, family) in [(0x20, 0x11), (0x20, 0x00), (0x21, 0x12)] {
+            let addrs = if family == 0x00 { &[][..] } else { &TCP4_ADDRS };
+            let (header, _) = parsed(&v2(command, family, addrs, &[]));
+            assert_eq!(header.source(), None);
+            assert_eq!(header.destination(), None);
+        }
+
+        assert!(error(&v2(0x22, 0x11, &TCP4_ADDRS, &[]), ProxyMode::Strict)
+            .contains("unsupported version or command"));
+        assert!(error(&v2(0x21, 0x41, &TCP4_ADDRS, &[]), ProxyMode::Strict)
+            .contains("unsupported address family"));
+    }
+
+    #[test]
+    fn v2_tlvs() {
+        let (header, _) = parsed(&v2(
+            0x21,
+            0x11,
+            &TCP4_ADDRS,
+            &[
+                (ProxyHeader::TLV_ALPN, b"h2"),
+                (ProxyHeader::TLV_AUTHORITY, b"example.com"),
+                (ProxyHeader::TLV_NOOP, b""),
+                (ProxyHeader::TLV_ALPN, b"http/1.1"),
+            ],
+        ));
+        assert_eq!(
+            header.tlvs().collect::<Vec<_>>(),
+            [
+                (ProxyHeader::TLV_ALPN, &b"h2"[..]),
+                (ProxyHeader::TLV_AUTHORITY, b"example.com"),
+                (ProxyHeader::TLV_NOOP, b""),
+                (ProxyHeader::TLV_ALPN, b"http/1.1"),
+            ]
+        );
+        assert_eq!(header.tlv(ProxyHeader::TLV_ALPN), Some(&b"h2"[..]));
+        assert_eq!(header.tlv(ProxyHeader::TLV_UNIQUE_ID), None);
+    }
+
+    #[test]
+    fn v2_checksum() {
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+
+        let header = v2_with_crc(&TCP4_ADDRS);
+        parsed(&header);
+
+        // A changed address no longer matches the checksum.
+        let mut changed = header.clone();
+        changed[16] ^= 1;
+        assert!(error(&changed, ProxyMode::Strict).contains("checksum mismatch"));
+
+        let short = v2(
+            0x21,
+            0x11,
+            &TCP4_ADDRS,
+            &[(ProxyHeader::TLV_CRC32C, &[0; 2])],
+        );
+        assert!(error(&short, ProxyMode::Strict).contains("checksum mismatch"));
+    }
+
+    #[test]
+    fn truncated_headers() {
+        // More data is needed, whichever the version.
+        let header = v2(0x21, 0x11, &TCP4_ADDRS, &[(ProxyHeader::TLV_ALPN, b"h2")]);
+        let line = b"PROXY TCP4 192.0.2.1 198.51.100.2 12345 443\r\n";
+        for len in 1..header.len() {
+            assert!(parse(&header[..len], ProxyMode::Strict).unwrap().is_none());
+        }
+        for len in 1..line.len() {
+            assert!(parse(&line[..len], ProxyMode::Strict).unwrap().is_none());
+        }
+
+        // A header that is complete according to its length, but too short for what it holds.
+        assert!(
+            error(&v2(0x21, 0x11, &TCP4_ADDRS[..8], &[]), ProxyMode::Strict)
+                .contains("truncated addresses")
+        );
+        let mut header = v2(0x21, 0x11, &TCP4_ADDRS, &[(ProxyHeader::TLV_ALPN, b"h2")]);
+        header.pop();
+        header[15] -= 1;
+        assert!(error(&header, ProxyMode::Strict).contains("truncated tlv"));
+        header.truncate(header.len() - 2);
+        header[15] -= 2;
+        assert!(error(&header, ProxyMode::Strict).contains("truncated tlv"));
+    }
+
+    #[test]
+    fn strict_and_optional_without_header() {
+        for data in [
+            &b"\x16\x03\x01\x02\x00"[..],
+            b"GET / HTTP/1.1\r\n",
+            b"PROXY\r\n",
+        ] {
+            assert_eq!(
+                error(data, ProxyMode::Strict),
+                "missing proxy protocol header",
+                "{:?}",
+                data
+            );
+            assert!(matches!(
+                parse(data, ProxyMode::Optional).unwrap(),
+                Some((None, 0))
+            ));
+        }
+
+        // Something that starts out like a header is waited for, or rejected, in either mode.
+        assert!(parse(b"PRO", ProxyMode::Optional).unwrap().is_none());
+        assert!(parse(&V2_SIGNATURE[..4], ProxyMode::Optional)
+            .unwrap()
+            .is_none());
+        assert!(error(b"PROXY TCP5\r\n", ProxyMode::Optional).contains("bad protocol line"));
+    }
+}
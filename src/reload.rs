@@ -0,0 +1,161 @@
+use crate::{
+    fs::read_file,
+    pem::{self, with_path},
+    server::TlsAcceptor,
+};
+
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// How often the files are checked for changes by default.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Serves a certificate chain and private key loaded from PEM files, which are reloaded when they change.
+///
+/// The reloader is a certificate resolver: the key is swapped inside it, the config it is set on never changes.
+/// Set it with [`TlsAcceptor::cert_reloader`], or call it from a resolver of your own, e.g. one reloader per
+/// name behind an SNI resolver. If the files can't be read or parsed, the current certificate is kept.
+///
+/// ```ignore
+/// let reloader = Arc::new(CertReloader::new("cert.pem", "key.pem").await?);
+/// let acceptor = acceptor.cert_reloader(reloader.clone());
+/// tokio_uring::spawn(async move { reloader.run().await });
+/// ```
+pub struct CertReloader {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    interval: Duration,
+    on_reload: Option<Box<dyn Fn(io::Result<()>) + Send + Sync>>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertReloader {
+    /// Loads the certificate chain from `cert_path` and the private key from `key_path`.
+    pub async fn new(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> io::Result<Self> {
+        let cert_path = cert_path.as_ref().to_path_buf();
+        let key_path = key_path.as_ref().to_path_buf();
+        let key = load(
+            &cert_path,
+            &read(&cert_path).await?,
+            &key_path,
+            &read(&key_path).await?,
+        )?;
+
+        Ok(CertReloader {
+            cert_path,
+            key_path,
+            interval: DEFAULT_INTERVAL,
+            on_reload: None,
+            current: RwLock::new(Arc::new(key)),
+        })
+    }
+
+    /// Sets how often [`CertReloader::run`] checks the files for changes, 10 seconds by default.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets a callback that is told the outcome of every reload attempted by [`CertReloader::run`].
+    pub fn on_reload(mut self, f: impl Fn(io::Result<()>) + Send + Sync + 'static) -> Self {
+        self.on_reload = Some(Box::new(f));
+        self
+    }
+
+    /// Reads the files and swaps the new certificate in.
+    pub async fn reload(&self) -> io::Result<()> {
+        let cert = read(&self.cert_path).await?;
+        let key = read(&self.key_path).await?;
+        self.apply(&cert, &key)
+    }
+
+    /// Reloads the files right away, then whenever their contents change or the process receives SIGHUP.
+    ///
+    /// This installs a SIGHUP handler, which replaces the default behavior of terminating the process. It only
+    /// returns if the handler can't be installed, and is meant to be spawned on the runtime.
+    pub async fn run(&self) -> io::Result<()> {
+        let mut sighup = signal(SignalKind::hangup())?;
+        let mut seen: Option<(Vec<u8>, Vec<u8>)> = None;
+        let mut forced = true;
+
+        loop {
+            let result = match (read(&self.cert_path).await, read(&self.key_path).await) {
+                (Ok(cert), Ok(key)) => {
+                    let changed = match &seen {
+                        Some((c, k)) => *c != cert || *k != key,
+                        None => true,
+                    };
+                    let result = if changed || forced {
+                        Some(self.apply(&cert, &key))
+                    } else {
+                        None
+                    };
+                    // Remember the contents even if they were rejected, so a broken file is reported once,
+                    // not on every check.
+                    seen = Some((cert, key));
+                    result
+                }
+                // A file that can't be read is likely in the middle of being replaced, the next check will
+                // pick it up. Only report it when a reload was explicitly asked for.
+                (Err(e), _) | (_, Err(e)) if forced => Some(Err(e)),
+                _ => None,
+            };
+
+            if let (Some(result), Some(on_reload)) = (result, self.on_reload.as_ref()) {
+                on_reload(result);
+            }
+
+            forced = tokio::time::timeout(self.interval, sighup.recv())
+                .await
+                .is_ok();
+        }
+    }
+
+    /// Parses the files and swaps the new certificate in.
+    fn apply(&self, cert: &[u8], key: &[u8]) -> io::Result<()> {
+        let key = load(&self.cert_path, cert, &self.key_path, key)?;
+        // The lock is never held across anything that can panic, poisoning can be ignored.
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertReloader {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(
+            self.current
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+        )
+    }
+}
+
+impl TlsAcceptor {
+    /// Serves the certificate chain and private key of `reloader`, which keeps them up to date.
+    ///
+    /// The acceptor gets a copy of its config with the reloader as its certificate resolver.
+    pub fn cert_reloader(self, reloader: Arc<CertReloader>) -> Self {
+        self.with_config(|config| config.cert_resolver = reloader)
+    }
+}
+
+async fn read(path: &Path) -> io::Result<Vec<u8>> {
+    read_file(path).await.map_err(|e| with_path(e, path))
+}
+
+/// Parses the files, checking that the key matches the certificate.
+fn load(cert_path: &Path, cert: &[u8], key_path: &Path, key: &[u8]) -> io::Result<CertifiedKey> {
+    let certs = pem::parse_certs(cert).map_err(|e| with_path(e, cert_path))?;
+    let key = pem::parse_private_key(key).map_err(|e| with_path(e, key_path))?;
+    pem::certified_key(certs, &key).map_err(|e| with_path(e, key_path))
+}
@@ -0,0 +1,393 @@
+use rustls::{
+    client::{ClientSessionStore, Tls12ClientSessionValue, Tls13ClientSessionValue},
+    NamedGroup, ServerName,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+/// Number of server names remembered by default.
+const DEFAULT_CAPACITY: usize = 256;
+
+/// Time a session is kept by default, unless the server gave it a shorter lifetime.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Number of TLS 1.3 tickets kept per server, servers usually issue 2 per connection.
+const MAX_TICKETS_PER_SERVER: usize = 8;
+
+/// A client session cache that can be shared by the connectors of all threads.
+///
+/// It is bounded to a number of server names, the least recently used one is dropped to make room for a new
+/// one, and entries are dropped after a maximum age. Attach it with
+/// [`TlsConnector::session_cache`](crate::TlsConnector::session_cache), which also keeps the
+/// [`ResumptionStats`] up to date.
+///
+/// Sessions are only kept in memory, persisting them to disk is not supported. Rustls 0.21 has no way to
+/// serialize the session values it hands to the store, and keeps their constructors to itself, so they can't be
+/// written to a file and rebuilt after a restart.
+pub struct ClientSessionCache {
+    capacity: usize,
+    max_age: Duration,
+    servers: Mutex<Servers>,
+    stats: Stats,
+}
+
+#[derive(Default)]
+struct Servers {
+    entries: HashMap<ServerName, Entry>,
+    /// Server names from least to most recently used.
+    order: VecDeque<ServerName>,
+}
+
+#[derive(Default)]
+struct Entry {
+    kx_hint: Option<NamedGroup>,
+    tls12: Option<(Instant, Tls12ClientSessionValue)>,
+    tls13: VecDeque<(Instant, Tls13ClientSessionValue)>,
+}
+
+#[derive(Default)]
+struct Stats {
+    offered: AtomicU64,
+    resumed: AtomicU64,
+    full: AtomicU64,
+}
+
+/// Counters of a [`ClientSessionCache`], see [`ClientSessionCache::stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResumptionStats {
+    /// Lookups that found a session in the cache to offer to the server.
+    pub offered: u64,
+    /// Handshakes in which the server resumed the session.
+    pub resumed: u64,
+    /// Handshakes in which the server did not resume a session, whether one was offered or not.
+    pub full: u64,
+}
+
+/// Counters of a server side session store, see [`FileSessionStore::stats`](crate::FileSessionStore::stats), or
+/// of a set of ticket keys.
+///
+/// Rustls looks a session up when a client asks to resume one, and stores a new one or issues a ticket after
+/// every handshake that completes, so the rate is over the clients that tried to resume.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServerResumptionStats {
+    /// Sessions stored, or tickets issued.
+    pub issued: u64,
+    /// Lookups that found the session the client asked for.
+    pub resumed: u64,
+    /// Lookups for a session that is unknown, expired or can't be decrypted, leading to a full handshake.
+    pub missed: u64,
+}
+
+impl ServerResumptionStats {
+    /// The share of lookups that found the session, between 0 and 1, or 0 before the first lookup.
+    pub fn resumption_rate(&self) -> f64 {
+        match self.resumed + self.missed {
+            0 => 0.0,
+            total => self.resumed as f64 / total as f64,
+        }
+    }
+}
+
+/// Atomic counters behind [`ServerResumptionStats`].
+#[derive(Default)]
+pub(crate) struct ServerStats {
+    issued: AtomicU64,
+    resumed: AtomicU64,
+    missed: AtomicU64,
+}
+
+impl ServerStats {
+    pub(crate) fn issued(&self) {
+        self.issued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a lookup, passing its result through.
+    pub(crate) fn looked_up<T>(&self, found: Option<T>) -> Option<T> {
+        let counter = if found.is_some() {
+            &self.resumed
+        } else {
+            &self.missed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    pub(crate) fn get(&self) -> ServerResumptionStats {
+        ServerResumptionStats {
+            issued: self.issued.load(Ordering::Relaxed),
+            resumed: self.resumed.load(Ordering::Relaxed),
+            missed: self.missed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for ClientSessionCache {
+    fn default() -> Self {
+        ClientSessionCache {
+            capacity: DEFAULT_CAPACITY,
+            max_age: DEFAULT_MAX_AGE,
+            servers: Mutex::default(),
+            stats: Stats::default(),
+        }
+    }
+}
+
+impl ClientSessionCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how many server names are remembered, 256 by default.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Sets how long a session is kept at most, a day by default. Rustls also drops sessions past the lifetime
+    /// the server gave them.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// The number of server names with an entry.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets every session.
+    pub fn clear(&self) {
+        let mut servers = self.lock();
+        servers.entries.clear();
+        servers.order.clear();
+    }
+
+    pub fn stats(&self) -> ResumptionStats {
+        ResumptionStats {
+            offered: self.stats.offered.load(Ordering::Relaxed),
+            resumed: self.stats.resumed.load(Ordering::Relaxed),
+            full: self.stats.full.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counts a completed handshake.
+    pub(crate) fn record_handshake(&self, resumed: bool) {
+        let counter = if resumed {
+            &self.stats.resumed
+        } else {
+            &self.stats.full
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Servers> {
+        // The lock is never held across anything that can panic, poisoning can be ignored.
+        self.servers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Runs `f` on the entry for `name`, creating it if needed, and marks it as most recently used.
+    fn edit<T>(&self, name: &ServerName, f: impl FnOnce(&mut Entry) -> T) -> T {
+        self.touch(&mut self.lock(), name, f)
+    }
+
+    /// Like [`ClientSessionCache::edit`], for lookups, which don't create an entry.
+    fn get<T>(&self, name: &ServerName, f: impl FnOnce(&mut Entry) -> Option<T>) -> Option<T> {
+        // Checked under the same lock as the edit, so an entry evicted in between isn't created anew.
+        let mut servers = self.lock();
+        if !servers.entries.contains_key(name) {
+            return None;
+        }
+        self.touch(&mut servers, name, f)
+    }
+
+    fn touch<T>(
+        &self,
+        servers: &mut Servers,
+        name: &ServerName,
+        f: impl FnOnce(&mut Entry) -> T,
+    ) -> T {
+        if let Some(pos) = servers.order.iter().position(|n| n == name) {
+            servers.order.remove(pos);
+        } else if servers.entries.len() >= self.capacity {
+            if let Some(oldest) = servers.order.pop_front() {
+                servers.entries.remove(&oldest);
+            }
+        }
+        servers.order.push_back(name.clone());
+
+        let entry = servers.entries.entry(name.clone()).or_default();
+        let now = Instant::now();
+        entry.tls12 = entry
+            .tls12
+            .take()
+            .filter(|(at, _)| now.duration_since(*at) < self.max_age);
+        entry
+            .tls13
+            .retain(|(at, _)| now.duration_since(*at) < self.max_age);
+        f(entry)
+    }
+
+    fn offered<T>(&self, value: Option<T>) -> Option<T> {
+        if value.is_some() {
+            self.stats.offered.fetch_add(1, Ordering::Relaxed);
+        }
+        value
+    }
+}
+
+impl ClientSessionStore for ClientSessionCache {
+    fn set_kx_hint(&self, server_name: &ServerName, group: NamedGroup) {
+        self.edit(server_name, |e| e.kx_hint = Some(group));
+    }
+
+    fn kx_hint(&self, server_name: &ServerName) -> Option<NamedGroup> {
+        self.get(server_name, |e| e.kx_hint)
+    }
+
+    fn set_tls12_session(&self, server_name: &ServerName, value: Tls12ClientSessionValue) {
+        self.edit(server_name, |e| e.tls12 = Some((Instant::now(), value)));
+    }
+
+    fn tls12_session(&self, server_name: &ServerName) -> Option<Tls12ClientSessionValue> {
+        let session = self.get(server_name, |e| e.tls12.as_ref().map(|(_, v)| v.clone()));
+        self.offered(session)
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName) {
+        self.get(server_name, |e| e.tls12.take());
+    }
+
+    fn insert_tls13_ticket(&self, server_name: &ServerName, value: Tls13ClientSessionValue) {
+        self.edit(server_name, |e| {
+            if e.tls13.len() >= MAX_TICKETS_PER_SERVER {
+                e.tls13.pop_front();
+            }
+            e.tls13.push_back((Instant::now(), value));
+        });
+    }
+
+    fn take_tls13_ticket(&self, server_name: &ServerName) -> Option<Tls13ClientSessionValue> {
+        // The newest ticket is the one least likely to have expired on the server.
+        let ticket = self.get(server_name, |e| e.tls13.pop_back().map(|(_, v)| v));
+        self.offered(ticket)
+    }
+}
+
+/// Handshake message types.
+const SERVER_HELLO: u8 = 2;
+const CERTIFICATE: u8 = 11;
+
+/// Extension types.
+const PRE_SHARED_KEY: u16 = 41;
+const SUPPORTED_VERSIONS: u16 = 43;
+
+/// The random of a ServerHello that is actually a HelloRetryRequest.
+const HELLO_RETRY_REQUEST: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+enum Negotiated {
+    Unknown,
+    Tls12,
+    Tls13 { psk: bool },
+}
+
+/// Tells whether the server resumed the session, from the data it sent during the handshake so far, or `None`
+/// if that data doesn't tell yet.
+///
+/// Rustls doesn't report this on the client side. A TLS 1.3 server that resumes answers with a `pre_shared_key`
+/// extension in its ServerHello, a TLS 1.2 server skips its Certificate message and goes straight to its
+/// ChangeCipherSpec. Both are sent in plaintext, in the first flight of the server.
+pub(crate) fn server_resumed(received: &[u8]) -> Option<bool> {
+    let mut negotiated = Negotiated::Unknown;
+    let mut handshake = Vec::new();
+    let mut records = received;
+
+    while records.len() >= 5 {
+        let len = u16::from_be_bytes([records[3], records[4]]) as usize;
+        let fragment = records.get(5..5 + len)?;
+        match (records[0], &negotiated) {
+            (22, _) => handshake.extend_from_slice(fragment),
+            // Past a TLS 1.2 ChangeCipherSpec everything is encrypted, in TLS 1.3 it is sent for compatibility only
+            (20, Negotiated::Tls12) => return Some(true),
+            (20, _) => (),
+            // Anything else, such as an alert, ends the handshake without telling
+            _ => return Some(false),
+        }
+        records = &records[5 + len..];
+
+        while handshake.len() >= 4 {
+            let len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            let Some(body) = handshake.get(4..4 + len) else {
+                break;
+            };
+            match handshake[0] {
+                SERVER_HELLO => {
+                    if let Some(server_hello) = parse_server_hello(body) {
+                        negotiated = server_hello;
+                    }
+                }
+                CERTIFICATE => {
+                    if let Negotiated::Tls12 = negotiated {
+                        return Some(false);
+                    }
+                }
+                _ => (),
+            }
+            handshake.drain(..4 + len);
+        }
+
+        // TLS 1.3 encrypts everything after the ServerHello
+        if let Negotiated::Tls13 { psk } = negotiated {
+            return Some(psk);
+        }
+    }
+
+    None
+}
+
+/// Parses a ServerHello body, returning `None` for a HelloRetryRequest or anything malformed.
+fn parse_server_hello(body: &[u8]) -> Option<Negotiated> {
+    let random = body.get(2..34)?;
+    if random == HELLO_RETRY_REQUEST {
+        return None;
+    }
+    let session_id_len = *body.get(34)? as usize;
+    // Session id, cipher suite and compression method
+    let mut extensions = body.get(35 + session_id_len + 3..)?;
+    if extensions.len() < 2 {
+        return Some(Negotiated::Tls12);
+    }
+    extensions = &extensions[2..];
+
+    let mut tls13 = false;
+    let mut psk = false;
+    while extensions.len() >= 4 {
+        let kind = u16::from_be_bytes([extensions[0], extensions[1]]);
+        let len = u16::from_be_bytes([extensions[2], extensions[3]]) as usize;
+        let value = extensions.get(4..4 + len)?;
+        match kind {
+            SUPPORTED_VERSIONS => tls13 = value == [0x03, 0x04],
+            PRE_SHARED_KEY => psk = true,
+            _ => (),
+        }
+        extensions = &extensions[4 + len..];
+    }
+
+    if tls13 {
+        Some(Negotiated::Tls13 { psk })
+    } else {
+        Some(Negotiated::Tls12)
+    }
+}
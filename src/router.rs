@@ -0,0 +1,208 @@
+use crate::{buffer::SyncReadAdaptor, server::TlsAcceptor, stream::TlsStream};
+
+use rustls::{server::Acceptor, ServerConnection};
+use std::{
+    collections::HashMap,
+    io::{self, Error, ErrorKind},
+    net::{Shutdown, SocketAddr},
+};
+use tokio_uring::net::TcpStream;
+
+/// Size of the buffer used for each direction of a spliced connection.
+const SPLICE_BUFFER_SIZE: usize = 16 * 1024;
+
+/// A connection whose `ClientHello` has been read, but not consumed, created by [`PeekedClientHello::read`].
+///
+/// Every byte read from the socket is kept, so the connection can still be handed to a [`TlsAcceptor`] or
+/// forwarded as is to a backend that terminates TLS itself.
+pub struct PeekedClientHello {
+    socket: TcpStream,
+    peeked: Vec<u8>,
+    server_name: Option<String>,
+    alpn: Vec<Vec<u8>>,
+}
+
+impl PeekedClientHello {
+    /// Reads from `socket` until a complete `ClientHello` has arrived.
+    pub async fn read(socket: TcpStream) -> io::Result<Self> {
+        let mut acceptor = Acceptor::default();
+        let mut rbuffer = SyncReadAdaptor::default();
+        rbuffer.start_recording();
+
+        loop {
+            match acceptor.read_tls(&mut rbuffer) {
+                Ok(0) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "tls raw stream eof before client hello",
+                    ))
+                }
+                Ok(_) => (),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                    rbuffer.do_io(&socket).await?;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+
+            match acceptor.accept() {
+                Ok(Some(accepted)) => {
+                    let hello = accepted.client_hello();
+                    let server_name = hello.server_name().map(str::to_owned);
+                    let alpn = hello
+                        .alpn()
+                        .map(|protocols| protocols.map(<[u8]>::to_vec).collect())
+                        .unwrap_or_default();
+                    return Ok(PeekedClientHello {
+                        socket,
+                        peeked: rbuffer.take_recorded(),
+                        server_name,
+                        alpn,
+                    });
+                }
+                Ok(None) => (),
+                Err(err) => return Err(Error::new(ErrorKind::InvalidData, err)),
+            }
+        }
+    }
+
+    /// The server name the client asked for through SNI.
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// The application protocols offered by the client through ALPN, in its order of preference.
+    pub fn alpn(&self) -> impl Iterator<Item = &[u8]> {
+        self.alpn.iter().map(|p| &p[..])
+    }
+
+    /// The bytes read from the socket so far, starting with the `ClientHello`.
+    pub fn peeked(&self) -> &[u8] {
+        &self.peeked
+    }
+
+    /// Terminates TLS locally, running the handshake with the bytes read so far.
+    pub async fn terminate(
+        self,
+        acceptor: &TlsAcceptor,
+    ) -> io::Result<TlsStream<ServerConnection>> {
+        acceptor.accept_with_prefix(self.socket, &self.peeked).await
+    }
+
+    /// Forwards the connection to `upstream` without terminating TLS.
+    ///
+    /// The bytes read so far are sent first, then data is copied in both directions until both sides have
+    /// closed the connection, or either fails. Returns the number of bytes sent to and received from `upstream`.
+    pub async fn splice(self, upstream: &TcpStream) -> io::Result<(u64, u64)> {
+        let sent = self.peeked.len() as u64;
+        upstream.write_all(self.peeked).await.0?;
+
+        let (up, down) =
+            tokio::try_join!(copy(&self.socket, upstream), copy(upstream, &self.socket))?;
+        Ok((sent + up, down))
+    }
+
+    /// Returns the socket along with the bytes read from it so far.
+    pub fn into_parts(self) -> (TcpStream, Vec<u8>) {
+        (self.socket, self.peeked)
+    }
+}
+
+/// Copies data from `from` to `to` until `from` is closed, then closes the write side of `to`.
+async fn copy(from: &TcpStream, to: &TcpStream) -> io::Result<u64> {
+    let mut total = 0;
+    let mut buf = Vec::with_capacity(SPLICE_BUFFER_SIZE);
+    loop {
+        let (res, read) = from.read(buf).await;
+        let n = res?;
+        if n == 0 {
+            // The peer may have closed the connection already, in which case there's nobody left to tell.
+            let _ = to.shutdown(Shutdown::Write);
+            return Ok(total);
+        }
+
+        let (res, mut written) = to.write_all(read).await;
+        res?;
+        total += n as u64;
+        written.clear();
+        buf = written;
+    }
+}
+
+/// Where [`SniRouter`] sends a connection.
+#[derive(Clone)]
+pub enum Route {
+    /// Terminates TLS locally with the acceptor.
+    Terminate(TlsAcceptor),
+    /// Forwards the encrypted connection to a backend at the address.
+    Passthrough(SocketAddr),
+}
+
+/// Routes connections by the server name in their `ClientHello`, either terminating TLS locally or passing the
+/// encrypted connection through to a backend.
+///
+/// Names are matched case insensitively. A route for `*.example.com` matches any name directly below
+/// `example.com` that has no route of its own. Connections without SNI, or with a name that has no route, take
+/// the fallback route, or are rejected if there is none.
+#[derive(Clone, Default)]
+pub struct SniRouter {
+    routes: HashMap<String, Route>,
+    fallback: Option<Route>,
+}
+
+impl SniRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a route for `name`, which can be a `*.` wildcard.
+    pub fn route(mut self, name: &str, route: Route) -> Self {
+        self.routes.insert(name.to_ascii_lowercase(), route);
+        self
+    }
+
+    /// Sets the route for connections that match no other.
+    pub fn fallback(mut self, route: Route) -> Self {
+        self.fallback = Some(route);
+        self
+    }
+
+    /// Finds the route for a server name.
+    pub fn find(&self, server_name: Option<&str>) -> Option<&Route> {
+        let route = server_name.and_then(|name| {
+            let name = name.to_ascii_lowercase();
+            self.routes.get(&name).or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                self.routes.get(&format!("*.{}", parent))
+            })
+        });
+        route.or(self.fallback.as_ref())
+    }
+
+    /// Reads the `ClientHello` of `socket` and routes the connection.
+    ///
+    /// Returns the stream when TLS is terminated locally. Passed through connections are served to completion
+    /// before this returns `None`.
+    pub async fn accept(
+        &self,
+        socket: TcpStream,
+    ) -> io::Result<Option<TlsStream<ServerConnection>>> {
+        let hello = PeekedClientHello::read(socket).await?;
+
+        match self.find(hello.server_name()) {
+            Some(Route::Terminate(acceptor)) => hello.terminate(acceptor).await.map(Some),
+            Some(Route::Passthrough(addr)) => {
+                let upstream = TcpStream::connect(*addr).await?;
+                hello.splice(&upstream).await?;
+                Ok(None)
+            }
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "no route for server name {}",
+                    hello.server_name().unwrap_or("(none)")
+                ),
+            )),
+        }
+    }
+}
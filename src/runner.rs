@@ -0,0 +1,310 @@
+use crate::{
+    drain::Drain, listener::TlsListener, proxy::ProxyMode, server::TlsAcceptor, stream::TlsStream,
+};
+
+use rustls::ServerConnection;
+use std::{
+    cell::Cell,
+    future::Future,
+    io::{self, Error, ErrorKind},
+    net::SocketAddr,
+    rc::Rc,
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+use tokio::sync::{watch, Notify};
+use tokio_uring::net::TcpListener;
+
+type ErrorCallback = Arc<dyn Fn(SocketAddr, io::Error) + Send + Sync>;
+
+/// Serves TLS on every core, with one tokio-uring runtime per thread.
+///
+/// Each thread binds its own listener to the same address, relying on `SO_REUSEPORT` to have the kernel
+/// spread incoming connections across them. tokio-uring sets it on every listener it binds, and doesn't hand
+/// out the socket to set it otherwise; if it ever stopped, every thread but the first would fail to bind and
+/// [`TlsServer::start`] would return an `AddrInUse` error saying so. Note that the option also lets other
+/// processes of the same user bind the address. The `TlsAcceptor` is shared, and every thread runs a
+/// [`TlsListener`], calling the handler in a new task for each established connection.
+pub struct TlsServer {
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    threads: usize,
+    max_handshakes: Option<usize>,
+    handshake_timeout: Option<Duration>,
+    proxy_protocol: Option<ProxyMode>,
+    on_error: Option<ErrorCallback>,
+    drain: Option<(Drain, Duration)>,
+}
+
+/// A running [`TlsServer`], created by [`TlsServer::start`].
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: watch::Sender<bool>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl TlsServer {
+    pub fn new(addr: SocketAddr, acceptor: TlsAcceptor) -> Self {
+        TlsServer {
+            addr,
+            acceptor,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            max_handshakes: None,
+            handshake_timeout: None,
+            proxy_protocol: None,
+            on_error: None,
+            drain: None,
+        }
+    }
+
+    /// Sets the number of threads, the number of available cores by default.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Sets the per thread handshake limit, see [`TlsListener::max_handshakes`].
+    pub fn max_handshakes(mut self, max: usize) -> Self {
+        self.max_handshakes = Some(max);
+        self
+    }
+
+    /// See [`TlsListener::handshake_timeout`].
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// See [`TlsListener::proxy_protocol`].
+    pub fn proxy_protocol(mut self, mode: ProxyMode) -> Self {
+        self.proxy_protocol = Some(mode);
+        self
+    }
+
+    /// See [`TlsListener::on_error`]. The callback is shared by all threads.
+    pub fn on_error(mut self, f: impl Fn(SocketAddr, io::Error) + Send + Sync + 'static) -> Self {
+        self.on_error = Some(Arc::new(f));
+        self
+    }
+
+    /// Drains the connections served through `drain` on shutdown, giving them `grace` to finish, see
+    /// [`Drain::shutdown`].
+    pub fn drain(mut self, drain: &Drain, grace: Duration) -> Self {
+        self.drain = Some((drain.clone(), grace));
+        self
+    }
+
+    /// Binds the listeners and starts serving, calling `handler` with every established connection.
+    ///
+    /// The handler is shared by all threads, the futures it returns stay on the thread that accepted the
+    /// connection and don't need to be `Send`. Returns once every thread is listening, or with the error of the
+    /// first thread that failed to bind.
+    pub fn start<H, F>(self, handler: H) -> io::Result<ServerHandle>
+    where
+        H: Fn(TlsStream<ServerConnection>, SocketAddr) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + 'static,
+    {
+        let handler = Arc::new(handler);
+        let (shutdown, _) = watch::channel(false);
+        let mut handle = ServerHandle {
+            local_addr: self.addr,
+            shutdown,
+            workers: Vec::with_capacity(self.threads),
+        };
+
+        for i in 0..self.threads {
+            let (ready_tx, ready_rx) = mpsc::channel();
+            // The first listener resolves a port of 0, the others have to bind to the same port.
+            let addr = handle.local_addr;
+            let worker = Worker {
+                acceptor: self.acceptor.clone(),
+                max_handshakes: self.max_handshakes,
+                handshake_timeout: self.handshake_timeout,
+                proxy_protocol: self.proxy_protocol,
+                on_error: self.on_error.clone(),
+                drain: self.drain.clone(),
+                shutdown: handle.shutdown.subscribe(),
+            };
+            let handler = handler.clone();
+
+            let spawned = thread::Builder::new()
+                .name(format!("tls-server-{}", i))
+                .spawn(move || worker.run(addr, ready_tx, handler));
+            let ready = match spawned {
+                Ok(thread) => {
+                    handle.workers.push(thread);
+                    // A worker that dies before reporting back counts as a failed bind.
+                    ready_rx.recv().unwrap_or_else(|_| {
+                        Err(io::Error::other("server thread exited during startup"))
+                    })
+                }
+                Err(e) => Err(e),
+            };
+
+            // The first listener took the port, the others can only share it through SO_REUSEPORT.
+            let ready = ready.map_err(|e| match e.kind() {
+                ErrorKind::AddrInUse if i > 0 => Error::new(
+                    ErrorKind::AddrInUse,
+                    format!(
+                        "thread {} could not bind {}, SO_REUSEPORT is not set on the listeners: {}",
+                        i, addr, e
+                    ),
+                ),
+                _ => e,
+            });
+
+            match ready {
+                Ok(addr) => handle.local_addr = addr,
+                Err(e) => {
+                    handle.shutdown();
+                    handle.join();
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(handle)
+    }
+}
+
+impl ServerHandle {
+    /// The address the server listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops accepting connections on every thread.
+    ///
+    /// Connections that are already being handled are not interrupted, each thread exits once all of its
+    /// handlers have returned. With a [`Drain`] set through [`TlsServer::drain`], the connections it serves
+    /// are drained first.
+    pub fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+    }
+
+    /// Blocks until every thread has exited, resuming the panic of a thread that panicked.
+    pub fn join(self) {
+        for worker in self.workers {
+            if let Err(panic) = worker.join() {
+                std::panic::resume_unwind(panic);
+            }
+        }
+    }
+}
+
+/// The state moved to each server thread.
+struct Worker {
+    acceptor: TlsAcceptor,
+    max_handshakes: Option<usize>,
+    handshake_timeout: Option<Duration>,
+    proxy_protocol: Option<ProxyMode>,
+    on_error: Option<ErrorCallback>,
+    drain: Option<(Drain, Duration)>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Worker {
+    fn run<H, F>(
+        mut self,
+        addr: SocketAddr,
+        ready: mpsc::Sender<io::Result<SocketAddr>>,
+        handler: Arc<H>,
+    ) where
+        H: Fn(TlsStream<ServerConnection>, SocketAddr) -> F + 'static,
+        F: Future<Output = ()> + 'static,
+    {
+        tokio_uring::start(async move {
+            let listener = match TcpListener::bind(addr).and_then(|l| Ok((l.local_addr()?, l))) {
+                Ok((addr, listener)) => {
+                    let _ = ready.send(Ok(addr));
+                    listener
+                }
+                Err(e) => {
+                    let _ = ready.send(Err(e));
+                    return;
+                }
+            };
+
+            let mut listener = TlsListener::new(listener, self.acceptor);
+            if let Some(max) = self.max_handshakes {
+                listener = listener.max_handshakes(max);
+            }
+            if let Some(timeout) = self.handshake_timeout {
+                listener = listener.handshake_timeout(timeout);
+            }
+            if let Some(mode) = self.proxy_protocol {
+                listener = listener.proxy_protocol(mode);
+            }
+            if let Some(on_error) = self.on_error {
+                listener = listener.on_error(move |addr, e| on_error(addr, e));
+            }
+
+            let active = Active::default();
+            while !*self.shutdown.borrow() {
+                let accepted = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    _ = self.shutdown.changed() => break,
+                };
+
+                match accepted {
+                    Ok((stream, addr)) => {
+                        let guard = active.enter();
+                        let handler = handler.clone();
+                        tokio_uring::spawn(async move {
+                            handler(stream, addr).await;
+                            drop(guard);
+                        });
+                    }
+                    // Running out of file descriptors or the like, give connections a chance to finish.
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+
+            // Stop accepting before waiting for the handlers.
+            drop(listener);
+            if let Some((drain, grace)) = self.drain {
+                drain.shutdown(grace).await;
+            }
+            active.wait_idle().await;
+        });
+    }
+}
+
+/// Counts the handlers running on a thread.
+#[derive(Default)]
+struct Active {
+    count: Rc<Cell<usize>>,
+    idle: Rc<Notify>,
+}
+
+/// Held by a running handler, dropped when it returns or panics.
+struct ActiveGuard {
+    count: Rc<Cell<usize>>,
+    idle: Rc<Notify>,
+}
+
+impl Active {
+    fn enter(&self) -> ActiveGuard {
+        self.count.set(self.count.get() + 1);
+        ActiveGuard {
+            count: self.count.clone(),
+            idle: self.idle.clone(),
+        }
+    }
+
+    async fn wait_idle(&self) {
+        while self.count.get() > 0 {
+            self.idle.notified().await;
+        }
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.count.set(self.count.get() - 1);
+        if self.count.get() == 0 {
+            self.idle.notify_one();
+        }
+    }
+}
@@ -1,32 +1,369 @@
-use crate::stream::TlsStream;
+use crate::{
+    buffer::{SyncReadAdaptor, SyncWriteAdaptor},
+    proxy::{self, ProxyHeader, ProxyMode},
+    stream::{HandshakeError, TlsStream},
+};
 
-use rustls::{ServerConfig, ServerConnection};
+use rustls::{
+    server::{Accepted, Acceptor, ClientHello, ProducesTickets, StoresServerSessions},
+    ServerConfig, ServerConnection,
+};
 use std::{
     io::{self, Error, ErrorKind},
-    sync::Arc,
+    sync::{Arc, RwLock},
+    time::Duration,
 };
 use tokio_uring::net::TcpStream;
 
 #[derive(Clone)]
 pub struct TlsAcceptor {
     inner: Arc<ServerConfig>,
+    policy: Policy,
 }
 
 impl From<Arc<ServerConfig>> for TlsAcceptor {
     #[inline]
     fn from(inner: Arc<ServerConfig>) -> TlsAcceptor {
-        TlsAcceptor { inner }
+        TlsAcceptor {
+            inner,
+            policy: Policy::default(),
+        }
+    }
+}
+
+/// The settings of a [`TlsAcceptor`] that go beyond its config. They are applied to every config the acceptor
+/// uses, including one picked per connection through [`StartHandshake::into_stream`] or set on a
+/// [`ReloadableTlsAcceptor`].
+#[derive(Clone, Default)]
+pub(crate) struct Policy {
+    pub(crate) ticketer: Option<Arc<dyn ProducesTickets>>,
+    pub(crate) session_storage: Option<Arc<dyn StoresServerSessions + Send + Sync>>,
+    /// How long [`TlsAcceptor::accept_maybe_tls`] waits for the first bytes before falling back to plaintext.
+    pub(crate) detect_timeout: Option<Duration>,
+}
+
+impl Policy {
+    /// Returns `config` with the settings of the policy, copying it only if it doesn't have them already.
+    fn apply(&self, config: Arc<ServerConfig>) -> Arc<ServerConfig> {
+        let ticketer = self
+            .ticketer
+            .as_ref()
+            .filter(|t| !Arc::ptr_eq(t, &config.ticketer));
+        let session_storage = self
+            .session_storage
+            .as_ref()
+            .filter(|s| !Arc::ptr_eq(s, &config.session_storage));
+        if ticketer.is_none() && session_storage.is_none() {
+            return config;
+        }
+
+        let mut config = ServerConfig::clone(&config);
+        if let Some(ticketer) = ticketer {
+            config.ticketer = ticketer.clone();
+        }
+        if let Some(session_storage) = session_storage {
+            config.session_storage = session_storage.clone();
+        }
+        Arc::new(config)
     }
 }
 
 impl TlsAcceptor {
+    /// The config connections are accepted with.
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.inner.clone()
+    }
+
+    /// Replaces the config with a copy changed by `f`, keeping the rest of the acceptor.
+    #[cfg(feature = "pem")]
+    pub(crate) fn with_config(mut self, f: impl FnOnce(&mut ServerConfig)) -> Self {
+        let mut config = ServerConfig::clone(&self.inner);
+        f(&mut config);
+        self.inner = Arc::new(config);
+        self
+    }
+
+    pub(crate) fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Changes the policy with `f` and applies it to the config.
+    pub(crate) fn with_policy(mut self, f: impl FnOnce(&mut Policy)) -> Self {
+        f(&mut self.policy);
+        self.inner = self.policy.apply(self.inner);
+        self
+    }
+
     pub async fn accept(&self, socket: TcpStream) -> io::Result<TlsStream<ServerConnection>> {
-        let session = match ServerConnection::new(self.inner.clone()) {
+        self.accept_with(socket, |_| ()).await
+    }
+
+    /// Like [`TlsAcceptor::accept`], but calls `f` on the new session before the handshake starts.
+    pub async fn accept_with<F>(
+        &self,
+        socket: TcpStream,
+        f: F,
+    ) -> io::Result<TlsStream<ServerConnection>>
+    where
+        F: FnOnce(&mut ServerConnection),
+    {
+        let mut session = match ServerConnection::new(self.inner.clone()) {
             Ok(s) => s,
-            Err(e) => return Err(Error::new(ErrorKind::Other, e)),
+            Err(e) => return Err(Error::other(e)),
         };
+        f(&mut session);
         let mut stream = TlsStream::new(socket, session);
         stream.handshake().await?;
         Ok(stream)
     }
+
+    /// Like [`TlsAcceptor::accept`], for a connection whose first bytes have already been read from the socket.
+    ///
+    /// This is the case after a STARTTLS style upgrade, or after peeking at the data to tell protocols apart.
+    /// The handshake starts with `prefix`, then continues with data from the socket.
+    pub async fn accept_with_prefix(
+        &self,
+        socket: TcpStream,
+        prefix: &[u8],
+    ) -> io::Result<TlsStream<ServerConnection>> {
+        self.accept_prefixed(socket, prefix, None).await
+    }
+
+    /// Like [`TlsAcceptor::accept`], for a connection that starts with a PROXY protocol header, version 1 or 2.
+    ///
+    /// The header is read ahead of the handshake and made available through
+    /// [`TlsStream::proxy_header`]. In [`ProxyMode::Optional`], connections that start straight with the
+    /// handshake are accepted too.
+    pub async fn accept_proxied(
+        &self,
+        socket: TcpStream,
+        mode: ProxyMode,
+    ) -> io::Result<TlsStream<ServerConnection>> {
+        let (header, rest) = proxy::read_header(&socket, mode).await?;
+        self.accept_prefixed(socket, &rest, header).await
+    }
+
+    async fn accept_prefixed(
+        &self,
+        socket: TcpStream,
+        prefix: &[u8],
+        proxy: Option<ProxyHeader>,
+    ) -> io::Result<TlsStream<ServerConnection>> {
+        let session = match ServerConnection::new(self.inner.clone()) {
+            Ok(s) => s,
+            Err(e) => return Err(Error::other(e)),
+        };
+        let mut stream = TlsStream::with_prefix(socket, session, prefix);
+        stream.proxy = proxy;
+        stream.handshake().await?;
+        Ok(stream)
+    }
+
+    /// Like [`TlsAcceptor::accept`], but hands the socket back if the handshake fails.
+    ///
+    /// See [`HandshakeError`] for what is returned on failure.
+    pub async fn try_accept(
+        &self,
+        socket: TcpStream,
+    ) -> Result<TlsStream<ServerConnection>, HandshakeError> {
+        let session = match ServerConnection::new(self.inner.clone()) {
+            Ok(s) => s,
+            Err(e) => return Err(HandshakeError::new(socket, Vec::new(), Error::other(e))),
+        };
+        let (stream, _) = TlsStream::new(socket, session).try_handshake().await?;
+        Ok(stream)
+    }
+
+    /// Reads the `ClientHello` of a connection, leaving the choice of config to [`StartHandshake::into_stream`].
+    ///
+    /// Unlike with [`LazyConfigAcceptor::accept`], the settings of the acceptor beyond its config, the ticket
+    /// keys and session store, carry over to the chosen config.
+    pub async fn accept_lazy(&self, socket: TcpStream) -> io::Result<StartHandshake> {
+        let mut start = LazyConfigAcceptor::accept(Acceptor::default(), socket).await?;
+        start.policy = self.policy.clone();
+        Ok(start)
+    }
+}
+
+/// A [`TlsAcceptor`] whose `ServerConfig` can be replaced while the server is running, e.g. to rotate
+/// certificates.
+///
+/// Clones share the same config, so a config set through one clone is picked up by all of them, across
+/// threads. Each connection uses the config that was current when its handshake started, handshakes already in
+/// flight keep going with the config they started with. The settings of the acceptor it was created from, such
+/// as ticket keys or a session store, apply to every config set.
+#[derive(Clone)]
+pub struct ReloadableTlsAcceptor {
+    inner: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl From<Arc<ServerConfig>> for ReloadableTlsAcceptor {
+    #[inline]
+    fn from(inner: Arc<ServerConfig>) -> ReloadableTlsAcceptor {
+        ReloadableTlsAcceptor::from(TlsAcceptor::from(inner))
+    }
+}
+
+impl From<TlsAcceptor> for ReloadableTlsAcceptor {
+    #[inline]
+    fn from(inner: TlsAcceptor) -> ReloadableTlsAcceptor {
+        ReloadableTlsAcceptor {
+            inner: Arc::new(RwLock::new(inner)),
+        }
+    }
+}
+
+impl ReloadableTlsAcceptor {
+    /// The config new handshakes currently start with.
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.acceptor().config()
+    }
+
+    /// Replaces the config for new handshakes, returning the previous one.
+    ///
+    /// The settings of the acceptor are applied to `config`, which is copied if it doesn't have them already.
+    pub fn set_config(&self, config: Arc<ServerConfig>) -> Arc<ServerConfig> {
+        let mut inner = self.inner.write().unwrap_or_else(|e| e.into_inner());
+        let config = inner.policy.apply(config);
+        std::mem::replace(&mut inner.inner, config)
+    }
+
+    /// A [`TlsAcceptor`] bound to the current config, which is not affected by later changes.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        // The lock is never held across anything that can panic, poisoning can be ignored.
+        self.inner.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// See [`TlsAcceptor::accept`].
+    pub async fn accept(&self, socket: TcpStream) -> io::Result<TlsStream<ServerConnection>> {
+        self.acceptor().accept(socket).await
+    }
+
+    /// See [`TlsAcceptor::accept_with`].
+    pub async fn accept_with<F>(
+        &self,
+        socket: TcpStream,
+        f: F,
+    ) -> io::Result<TlsStream<ServerConnection>>
+    where
+        F: FnOnce(&mut ServerConnection),
+    {
+        self.acceptor().accept_with(socket, f).await
+    }
+
+    /// See [`TlsAcceptor::accept_with_prefix`].
+    pub async fn accept_with_prefix(
+        &self,
+        socket: TcpStream,
+        prefix: &[u8],
+    ) -> io::Result<TlsStream<ServerConnection>> {
+        self.acceptor().accept_with_prefix(socket, prefix).await
+    }
+
+    /// See [`TlsAcceptor::accept_proxied`].
+    pub async fn accept_proxied(
+        &self,
+        socket: TcpStream,
+        mode: ProxyMode,
+    ) -> io::Result<TlsStream<ServerConnection>> {
+        self.acceptor().accept_proxied(socket, mode).await
+    }
+
+    /// See [`TlsAcceptor::try_accept`].
+    pub async fn try_accept(
+        &self,
+        socket: TcpStream,
+    ) -> Result<TlsStream<ServerConnection>, HandshakeError> {
+        self.acceptor().try_accept(socket).await
+    }
+}
+
+/// Accepts a connection without choosing a `ServerConfig` up front.
+///
+/// The `ClientHello` is read first and exposed through [`StartHandshake`], which can then pick the config for
+/// the rest of the handshake based on it, e.g. by SNI or ALPN.
+///
+/// The connection is not tied to any [`TlsAcceptor`], so none of the settings of one apply, such as ticket
+/// keys. Use [`TlsAcceptor::accept_lazy`] to keep them.
+pub struct LazyConfigAcceptor;
+
+impl LazyConfigAcceptor {
+    /// Reads from `socket` until a complete `ClientHello` has arrived.
+    pub async fn accept(mut acceptor: Acceptor, socket: TcpStream) -> io::Result<StartHandshake> {
+        let mut rbuffer = SyncReadAdaptor::default();
+
+        loop {
+            match acceptor.read_tls(&mut rbuffer) {
+                Ok(0) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "tls raw stream eof before client hello",
+                    ))
+                }
+                Ok(_) => (),
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                    rbuffer.do_io(&socket).await?;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+
+            match acceptor.accept() {
+                Ok(Some(accepted)) => {
+                    return Ok(StartHandshake {
+                        accepted,
+                        io: socket,
+                        rbuffer,
+                        policy: Policy::default(),
+                    })
+                }
+                Ok(None) => (),
+                Err(err) => return Err(Error::new(ErrorKind::InvalidData, err)),
+            }
+        }
+    }
+}
+
+/// A connection whose `ClientHello` has been read, created by [`LazyConfigAcceptor::accept`] or
+/// [`TlsAcceptor::accept_lazy`].
+pub struct StartHandshake {
+    accepted: Accepted,
+    io: TcpStream,
+    rbuffer: SyncReadAdaptor,
+    policy: Policy,
+}
+
+impl StartHandshake {
+    /// The `ClientHello` sent by the client, carrying SNI, ALPN, cipher suites and signature schemes.
+    pub fn client_hello(&self) -> ClientHello<'_> {
+        self.accepted.client_hello()
+    }
+
+    /// Continues the handshake with the given config.
+    ///
+    /// If the connection came from [`TlsAcceptor::accept_lazy`], the settings of that acceptor are applied to
+    /// `config`, which is copied for every connection if it doesn't have them already.
+    pub async fn into_stream(
+        self,
+        config: Arc<ServerConfig>,
+    ) -> io::Result<TlsStream<ServerConnection>> {
+        let session = match self.accepted.into_connection(self.policy.apply(config)) {
+            Ok(s) => s,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+        };
+        let mut stream = TlsStream {
+            io: self.io,
+            session,
+            rbuffer: self.rbuffer,
+            wbuffer: SyncWriteAdaptor::default(),
+            proxy: None,
+        };
+        stream.handshake().await?;
+        Ok(stream)
+    }
+
+    /// Gives up on the handshake, returning the socket.
+    pub fn into_inner(self) -> TcpStream {
+        self.io
+    }
 }
@@ -0,0 +1,337 @@
+use crate::{
+    fs::{read_file, write_file},
+    resumption::{ServerResumptionStats, ServerStats},
+    server::TlsAcceptor,
+};
+
+use rustls::server::StoresServerSessions;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{self, Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Number of sessions kept by default.
+const DEFAULT_CAPACITY: usize = 4096;
+
+/// Time a session is kept by default.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often the file is synced by default.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Magic bytes and format version of a session file.
+const FILE_MAGIC: &[u8; 4] = b"TUSS";
+const FILE_FORMAT: u8 = 1;
+
+/// A server session store that keeps sessions in a file, so they survive restarts and can be shared by the
+/// instances of a fleet.
+///
+/// Sessions are looked up in memory, rustls does so in the middle of the handshake. The file is read by
+/// [`FileSessionStore::load`] and written by [`FileSessionStore::save`], or both periodically by
+/// [`FileSessionStore::run`]. Plug it in with [`TlsAcceptor::session_store`].
+///
+/// Instances sharing the file, on the same host or on a shared volume, merge the sessions found in it with
+/// their own before writing it back. A session resumed on one instance can still be resumed on another until
+/// both have synced, which is fine for resumption but rules out relying on single use, e.g. for early data.
+///
+/// Whether a given connection was resumed is not reported, rustls 0.21 doesn't tell on the server side, only
+/// the counters of [`FileSessionStore::stats`] over all connections are.
+///
+/// The file holds the secrets of the sessions, it is created readable by the owner only.
+pub struct FileSessionStore {
+    path: PathBuf,
+    capacity: usize,
+    max_age: Duration,
+    interval: Duration,
+    on_sync: Option<Box<dyn Fn(io::Result<()>) + Send + Sync>>,
+    sessions: Mutex<Sessions>,
+    stats: ServerStats,
+}
+
+#[derive(Default)]
+struct Sessions {
+    entries: HashMap<Vec<u8>, Entry>,
+    /// Keys by insertion order, the lowest is the oldest.
+    order: BTreeMap<u64, Vec<u8>>,
+    next: u64,
+    /// Keys taken since the last save, which must not come back from the file.
+    taken: HashSet<Vec<u8>>,
+    /// Whether anything changed since the last save.
+    dirty: bool,
+}
+
+struct Entry {
+    seq: u64,
+    /// Seconds since the Unix epoch, as the file outlives the process.
+    created: u64,
+    value: Vec<u8>,
+}
+
+impl Sessions {
+    fn insert(&mut self, key: Vec<u8>, created: u64, value: Vec<u8>, capacity: usize) {
+        self.remove(&key);
+        while self.entries.len() >= capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+
+        let seq = self.next;
+        self.next += 1;
+        self.order.insert(seq, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                seq,
+                created,
+                value,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.seq);
+        Some(entry)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl FileSessionStore {
+    /// Creates an empty store that is synced with the file at `path`.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        FileSessionStore {
+            path: path.as_ref().to_path_buf(),
+            capacity: DEFAULT_CAPACITY,
+            max_age: DEFAULT_MAX_AGE,
+            interval: DEFAULT_INTERVAL,
+            on_sync: None,
+            sessions: Mutex::default(),
+            stats: ServerStats::default(),
+        }
+    }
+
+    /// Sets how many sessions are kept, 4096 by default. The oldest one is dropped to make room for a new one.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Sets how long a session can be resumed, a day by default.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Sets how often [`FileSessionStore::run`] syncs with the file, 10 seconds by default.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets a callback that is told the outcome of every sync done by [`FileSessionStore::run`].
+    pub fn on_sync(mut self, f: impl Fn(io::Result<()>) + Send + Sync + 'static) -> Self {
+        self.on_sync = Some(Box::new(f));
+        self
+    }
+
+    /// The number of sessions in memory.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> ServerResumptionStats {
+        self.stats.get()
+    }
+
+    /// Reads the file, adding the sessions that aren't known yet. A missing file counts as an empty one.
+    pub async fn load(&self) -> io::Result<()> {
+        let data = match read_file(&self.path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut loaded = decode(&data).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{}: invalid session file", self.path.display()),
+            )
+        })?;
+        loaded.sort_by_key(|(created, _, _)| *created);
+
+        let oldest = now().saturating_sub(self.max_age.as_secs());
+        let mut sessions = self.lock();
+        for (created, key, value) in loaded {
+            if created >= oldest
+                && !sessions.entries.contains_key(&key)
+                && !sessions.taken.contains(&key)
+            {
+                sessions.insert(key, created, value, self.capacity);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the sessions in memory to the file, replacing its contents.
+    pub async fn save(&self) -> io::Result<()> {
+        let oldest = now().saturating_sub(self.max_age.as_secs());
+        let data = {
+            let mut sessions = self.lock();
+            let expired: Vec<Vec<u8>> = sessions
+                .entries
+                .iter()
+                .filter(|(_, e)| e.created < oldest)
+                .map(|(k, _)| k.clone())
+                .collect();
+            for key in expired {
+                sessions.remove(&key);
+            }
+            sessions.dirty = false;
+            encode(&sessions)
+        };
+
+        let result = write_file(&self.path, data).await;
+        let mut sessions = self.lock();
+        match result {
+            // Sessions taken since then weren't written either, they can't come back from the file.
+            Ok(()) => sessions.taken.clear(),
+            Err(_) => sessions.dirty = true,
+        }
+        result
+    }
+
+    /// Syncs with the file at every interval: sessions added by other instances are loaded, then the file is
+    /// written if this one changed anything.
+    ///
+    /// Never returns, it is meant to be spawned on the runtime. Call [`FileSessionStore::save`] on shutdown to
+    /// keep the sessions of the last interval.
+    pub async fn run(&self) {
+        loop {
+            tokio::time::sleep(self.interval).await;
+
+            let mut result = self.load().await;
+            if self.lock().dirty {
+                // A file that can't be read must not keep the sessions of this instance from being saved.
+                let saved = self.save().await;
+                result = result.and(saved);
+            }
+
+            if let Some(on_sync) = &self.on_sync {
+                on_sync(result);
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Sessions> {
+        // The lock is never held across anything that can panic, poisoning can be ignored.
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn is_fresh(&self, entry: &Entry) -> bool {
+        now().saturating_sub(entry.created) < self.max_age.as_secs()
+    }
+}
+
+impl StoresServerSessions for FileSessionStore {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        let mut sessions = self.lock();
+        sessions.taken.remove(&key);
+        sessions.insert(key, now(), value, self.capacity);
+        sessions.dirty = true;
+        self.stats.issued();
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut sessions = self.lock();
+        let value = match sessions.entries.get(key) {
+            Some(entry) if self.is_fresh(entry) => Some(entry.value.clone()),
+            Some(_) => {
+                sessions.remove(key);
+                None
+            }
+            None => None,
+        };
+        self.stats.looked_up(value)
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut sessions = self.lock();
+        let value = sessions.remove(key);
+        if value.is_some() {
+            sessions.taken.insert(key.to_vec());
+            sessions.dirty = true;
+        }
+        self.stats
+            .looked_up(value.filter(|e| self.is_fresh(e)).map(|e| e.value))
+    }
+
+    fn can_cache(&self) -> bool {
+        true
+    }
+}
+
+/// Serializes the sessions, oldest first.
+fn encode(sessions: &Sessions) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(FILE_MAGIC);
+    data.push(FILE_FORMAT);
+    for key in sessions.order.values() {
+        let entry = &sessions.entries[key];
+        data.extend_from_slice(&entry.created.to_be_bytes());
+        data.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        data.extend_from_slice(key);
+        data.extend_from_slice(&(entry.value.len() as u32).to_be_bytes());
+        data.extend_from_slice(&entry.value);
+    }
+    data
+}
+
+/// Parses a session file into its creation times, keys and values.
+#[allow(clippy::type_complexity)]
+fn decode(data: &[u8]) -> Option<Vec<(u64, Vec<u8>, Vec<u8>)>> {
+    let mut rest = data
+        .strip_prefix(FILE_MAGIC)?
+        .strip_prefix(&[FILE_FORMAT])?;
+    let mut take = |n: usize| -> Option<&[u8]> {
+        let (head, tail) = rest.split_at_checked(n)?;
+        rest = tail;
+        Some(head)
+    };
+
+    let mut sessions = Vec::new();
+    while let Some(created) = take(8) {
+        let created = u64::from_be_bytes(created.try_into().unwrap());
+        let len = u16::from_be_bytes(take(2)?.try_into().unwrap()) as usize;
+        let key = take(len)?.to_vec();
+        let len = u32::from_be_bytes(take(4)?.try_into().unwrap()) as usize;
+        let value = take(len)?.to_vec();
+        sessions.push((created, key, value));
+    }
+    // Anything shorter than a creation time is a truncated file.
+    take(1).is_none().then_some(sessions)
+}
+
+impl TlsAcceptor {
+    /// Keeps the sessions of the acceptor in `store`, so they can be resumed after a restart or on another
+    /// instance.
+    ///
+    /// The acceptor gets a copy of its config with the store set, as does any config it is later used with. With
+    /// TLS 1.3, rustls only uses it when no ticket keys are set, as tickets carry the session themselves.
+    pub fn session_store(self, store: Arc<FileSessionStore>) -> Self {
+        self.with_policy(|policy| policy.session_storage = Some(store))
+    }
+}
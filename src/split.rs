@@ -1,22 +1,149 @@
-use crate::TlsStream;
+use crate::{
+    buffer::{SyncReadAdaptor, SyncWriteAdaptor},
+    proxy::ProxyHeader,
+    stream, TlsStream,
+};
 
 use rustls::{ConnectionCommon, SideData};
-use tokio_uring::BufResult;
+use tokio::sync::Mutex;
+use tokio_uring::{net::TcpStream, BufResult};
 
 use std::{
-    cell::UnsafeCell,
+    cell::RefCell,
+    error::Error,
+    fmt,
     ops::{Deref, DerefMut},
     rc::Rc,
 };
 
-#[derive(Debug)]
+/// The read half of a [`TlsStream`], created by [`split`].
+///
+/// The read half exclusively owns the read buffer, the write half owns the write buffer. The socket is shared
+/// as tokio-uring submits reads and writes through a shared reference, and the rustls session is only borrowed
+/// for the synchronous parts of each operation, never across an `.await`. A pending read and a pending write
+/// can therefore be in flight at the same time.
+///
+/// The read half also keeps a handle to the write buffer, so that it can send records rustls queues while
+/// reading when the write half is idle.
 pub struct ReadHalf<C> {
-    pub(crate) inner: Rc<UnsafeCell<TlsStream<C>>>,
+    pub(crate) io: Rc<TcpStream>,
+    pub(crate) session: Rc<RefCell<C>>,
+    pub(crate) rbuffer: SyncReadAdaptor,
+    pub(crate) wbuffer: Rc<Mutex<SyncWriteAdaptor>>,
+    pub(crate) proxy: Option<ProxyHeader>,
 }
 
-#[derive(Debug)]
+/// The write half of a [`TlsStream`], created by [`split`].
 pub struct WriteHalf<C> {
-    pub(crate) inner: Rc<UnsafeCell<TlsStream<C>>>,
+    pub(crate) io: Rc<TcpStream>,
+    pub(crate) session: Rc<RefCell<C>>,
+    pub(crate) wbuffer: Rc<Mutex<SyncWriteAdaptor>>,
+}
+
+/// Error indicating that two halves were not from the same stream, returned by [`ReadHalf::reunite`].
+///
+/// Both halves are handed back so that the caller can keep using them.
+pub struct ReuniteError<C>(pub ReadHalf<C>, pub WriteHalf<C>);
+
+impl<C> fmt::Debug for ReadHalf<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadHalf")
+            .field("proxy", &self.proxy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C> fmt::Debug for WriteHalf<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteHalf").finish_non_exhaustive()
+    }
+}
+
+impl<C> fmt::Debug for ReuniteError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ReuniteError").finish_non_exhaustive()
+    }
+}
+
+impl<C> fmt::Display for ReuniteError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tried to reunite halves that are not from the same stream"
+        )
+    }
+}
+
+impl<C> Error for ReuniteError<C> {}
+
+impl<C> ReadHalf<C> {
+    /// Checks if this read half and the given write half were split from the same stream.
+    pub fn is_pair_of(&self, other: &WriteHalf<C>) -> bool {
+        Rc::ptr_eq(&self.session, &other.session)
+    }
+
+    /// See [`TlsStream::proxy_header`].
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.proxy.as_ref()
+    }
+
+    /// Joins this read half with the write half it was split from, restoring the original [`TlsStream`].
+    ///
+    /// Fails with [`ReuniteError`] if the two halves were not split from the same stream.
+    #[allow(clippy::result_large_err)]
+    pub fn reunite(self, other: WriteHalf<C>) -> Result<TlsStream<C>, ReuniteError<C>> {
+        if !self.is_pair_of(&other) {
+            return Err(ReuniteError(self, other));
+        }
+
+        let ReadHalf {
+            io,
+            session,
+            rbuffer,
+            wbuffer,
+            proxy,
+        } = self;
+        let WriteHalf {
+            io: other_io,
+            session: other_session,
+            wbuffer: other_wbuffer,
+        } = other;
+
+        // Drop the write half's handles first, this leaves the read half's handles as the only owners.
+        drop(other_io);
+        drop(other_session);
+        drop(other_wbuffer);
+
+        let io = Rc::try_unwrap(io)
+            .unwrap_or_else(|_| unreachable!("bug: socket still shared after reunite"));
+        let session = Rc::try_unwrap(session)
+            .unwrap_or_else(|_| unreachable!("bug: session still shared after reunite"))
+            .into_inner();
+        let wbuffer = Rc::try_unwrap(wbuffer)
+            .unwrap_or_else(|_| unreachable!("bug: write buffer still shared after reunite"))
+            .into_inner();
+
+        Ok(TlsStream {
+            io,
+            session,
+            rbuffer,
+            wbuffer,
+            proxy,
+        })
+    }
+}
+
+impl<C> WriteHalf<C> {
+    /// Checks if this write half and the given read half were split from the same stream.
+    pub fn is_pair_of(&self, other: &ReadHalf<C>) -> bool {
+        other.is_pair_of(self)
+    }
+
+    /// Joins this write half with the read half it was split from, see [`ReadHalf::reunite`].
+    #[allow(clippy::result_large_err)]
+    pub fn reunite(self, other: ReadHalf<C>) -> Result<TlsStream<C>, ReuniteError<C>> {
+        other.reunite(self)
+    }
 }
 
 impl<C, SD: SideData + 'static> ReadHalf<C>
@@ -24,8 +151,14 @@ where
     C: DerefMut + Deref<Target = ConnectionCommon<SD>>,
 {
     pub async fn read<B: tokio_uring::buf::IoBufMut>(&mut self, buf: B) -> BufResult<usize, B> {
-        let inner = unsafe { &mut *self.inner.get() };
-        return inner.read(buf).await;
+        stream::read(
+            &self.io,
+            &mut &*self.session,
+            &mut self.rbuffer,
+            &self.wbuffer,
+            buf,
+        )
+        .await
     }
 }
 
@@ -34,24 +167,48 @@ where
     C: DerefMut + Deref<Target = ConnectionCommon<SD>>,
 {
     pub async fn write<B: tokio_uring::buf::IoBuf>(&mut self, buf: B) -> BufResult<usize, B> {
-        let inner = unsafe { &mut *self.inner.get() };
-        return inner.write(buf).await;
+        let mut wbuffer = self.wbuffer.lock().await;
+        stream::write(&self.io, &mut &*self.session, &mut wbuffer, buf).await
     }
 
     pub async fn write_all<B: tokio_uring::buf::IoBuf>(&mut self, buf: B) -> BufResult<(), B> {
-        let inner = unsafe { &mut *self.inner.get() };
-        return inner.write_all(buf).await;
+        let mut wbuffer = self.wbuffer.lock().await;
+        stream::write_all(&self.io, &mut &*self.session, &mut wbuffer, buf).await
+    }
+
+    /// See [`TlsStream::shutdown`].
+    pub async fn shutdown(&mut self) -> std::io::Result<()> {
+        let mut wbuffer = self.wbuffer.lock().await;
+        stream::shutdown(&self.io, &mut &*self.session, &mut wbuffer).await
     }
 }
 
 pub fn split<C: DerefMut + Deref<Target = ConnectionCommon<SD>>, SD: SideData + 'static>(
     stream: TlsStream<C>,
 ) -> (ReadHalf<C>, WriteHalf<C>) {
-    let shared = Rc::new(UnsafeCell::new(stream));
+    let TlsStream {
+        io,
+        session,
+        rbuffer,
+        wbuffer,
+        proxy,
+    } = stream;
+
+    let io = Rc::new(io);
+    let session = Rc::new(RefCell::new(session));
+    let wbuffer = Rc::new(Mutex::new(wbuffer));
     (
         ReadHalf {
-            inner: shared.clone(),
+            io: io.clone(),
+            session: session.clone(),
+            rbuffer,
+            wbuffer: wbuffer.clone(),
+            proxy,
+        },
+        WriteHalf {
+            io,
+            session,
+            wbuffer,
         },
-        WriteHalf { inner: shared },
     )
 }
@@ -0,0 +1,100 @@
+//! IMAP, upgraded with the `STARTTLS` command from RFC 3501.
+
+use super::{quit, refused, too_many_commands, Plaintext, MAX_COMMANDS};
+use crate::{TlsAcceptor, TlsConnector, TlsStream};
+
+use rustls::{ClientConnection, ServerConnection};
+use std::io::{self, Error, ErrorKind};
+use tokio_uring::net::TcpStream;
+
+const CAPABILITIES: &str = "IMAP4rev1 STARTTLS LOGINDISABLED";
+
+/// Waits for the server greeting, sends `STARTTLS` and upgrades the connection.
+pub async fn connect(
+    connector: &TlsConnector,
+    domain: rustls::ServerName,
+    socket: TcpStream,
+) -> io::Result<TlsStream<ClientConnection>> {
+    let mut p = Plaintext::new(&socket);
+
+    // STARTTLS is only allowed in the not authenticated state, a PREAUTH greeting skips it.
+    let greeting = p.read_line().await?;
+    if !greeting.starts_with("* OK") {
+        return Err(refused("imap", &greeting));
+    }
+
+    p.write_all("a001 STARTTLS\r\n").await?;
+    loop {
+        let line = p.read_line().await?;
+        if let Some(status) = line.strip_prefix("a001 ") {
+            if !status.starts_with("OK") {
+                return Err(refused("imap", status));
+            }
+            break;
+        }
+        // Untagged responses, such as a CAPABILITY update, are of no interest here.
+        if !line.starts_with("* ") {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unexpected imap response: {}", line),
+            ));
+        }
+    }
+
+    p.finish()?;
+    connector.connect(domain, socket).await
+}
+
+/// Greets the client, answers its commands until it sends `STARTTLS`, and upgrades the connection.
+///
+/// Only `CAPABILITY`, `NOOP` and `LOGOUT` are served, anything else is rejected until the connection has been
+/// upgraded.
+pub async fn accept(
+    acceptor: &TlsAcceptor,
+    socket: TcpStream,
+) -> io::Result<TlsStream<ServerConnection>> {
+    let mut p = Plaintext::new(&socket);
+    p.write_all(format!(
+        "* OK [CAPABILITY {}] Server ready\r\n",
+        CAPABILITIES
+    ))
+    .await?;
+
+    for _ in 0..MAX_COMMANDS {
+        let line = p.read_line().await?;
+        let (tag, command) = match line.split_once(' ') {
+            Some((tag, command)) => (tag, command.trim().to_ascii_uppercase()),
+            None => {
+                p.write_all("* BAD Missing command\r\n").await?;
+                continue;
+            }
+        };
+
+        let reply = match command.as_str() {
+            "CAPABILITY" => format!(
+                "* CAPABILITY {}\r\n{} OK CAPABILITY completed\r\n",
+                CAPABILITIES, tag
+            ),
+            "NOOP" => format!("{} OK NOOP completed\r\n", tag),
+            "STARTTLS" => {
+                p.write_all(format!("{} OK Begin TLS negotiation now\r\n", tag))
+                    .await?;
+                p.finish()?;
+                return acceptor.accept(socket).await;
+            }
+            "LOGOUT" => {
+                p.write_all(format!(
+                    "* BYE Logging out\r\n{} OK LOGOUT completed\r\n",
+                    tag
+                ))
+                .await?;
+                return Err(quit());
+            }
+            _ => format!("{} BAD Command not allowed before STARTTLS\r\n", tag),
+        };
+        p.write_all(reply).await?;
+    }
+
+    p.write_all("* BYE Too many commands\r\n").await?;
+    Err(too_many_commands())
+}
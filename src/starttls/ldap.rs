@@ -0,0 +1,145 @@
+//! LDAP, upgraded with the StartTLS extended operation from RFC 4511.
+
+use super::{refused, Plaintext};
+use crate::{
+    der::{self, put},
+    TlsAcceptor, TlsConnector, TlsStream,
+};
+
+use rustls::{ClientConnection, ServerConnection};
+use std::io::{self, Error, ErrorKind};
+use tokio_uring::net::TcpStream;
+
+const START_TLS_OID: &[u8] = b"1.3.6.1.4.1.1466.20037";
+
+/// Largest message accepted from the peer.
+const MAX_MESSAGE: usize = 64 * 1024;
+
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const ENUMERATED: u8 = 0x0a;
+const OCTET_STRING: u8 = 0x04;
+const EXTENDED_REQUEST: u8 = 0x77;
+const EXTENDED_RESPONSE: u8 = 0x78;
+const REQUEST_NAME: u8 = 0x80;
+const RESPONSE_NAME: u8 = 0x8a;
+
+fn malformed() -> Error {
+    Error::new(ErrorKind::InvalidData, "malformed ldap message")
+}
+
+/// Splits the next BER element off `data`, returning its tag and content.
+fn take<'a>(data: &mut &'a [u8]) -> io::Result<(u8, &'a [u8])> {
+    der::take(data).ok_or_else(malformed)
+}
+
+/// Reads one complete LDAPMessage off the socket, returning its content.
+async fn read_message(p: &mut Plaintext<'_>) -> io::Result<Vec<u8>> {
+    let header = p.read_exact(2).await?;
+    if header[0] != SEQUENCE {
+        return Err(malformed());
+    }
+
+    let len = match header[1] {
+        n if n < 0x80 => n as usize,
+        n if (0x81..=0x84).contains(&n) => p
+            .read_exact((n & 0x7f) as usize)
+            .await?
+            .iter()
+            .fold(0usize, |len, b| len << 8 | *b as usize),
+        _ => return Err(malformed()),
+    };
+    if len > MAX_MESSAGE {
+        return Err(Error::new(ErrorKind::InvalidData, "ldap message too large"));
+    }
+
+    p.read_exact(len).await
+}
+
+/// Sends a StartTLS extended request and upgrades the connection if the server agrees to it.
+pub async fn connect(
+    connector: &TlsConnector,
+    domain: rustls::ServerName,
+    socket: TcpStream,
+) -> io::Result<TlsStream<ClientConnection>> {
+    let mut p = Plaintext::new(&socket);
+
+    let mut op = Vec::new();
+    put(&mut op, REQUEST_NAME, START_TLS_OID);
+    let mut msg = Vec::new();
+    put(&mut msg, INTEGER, &[1]);
+    put(&mut msg, EXTENDED_REQUEST, &op);
+    let mut request = Vec::new();
+    put(&mut request, SEQUENCE, &msg);
+    p.write_all(request).await?;
+
+    let msg = read_message(&mut p).await?;
+    let mut msg = &msg[..];
+    let id = take(&mut msg)?;
+    let (tag, mut op) = take(&mut msg)?;
+    // A message id of 0 is an unsolicited notification, such as a notice of disconnection
+    if id != (INTEGER, &[1][..]) || tag != EXTENDED_RESPONSE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "unexpected ldap response to starttls",
+        ));
+    }
+
+    let (tag, code) = take(&mut op)?;
+    if tag != ENUMERATED {
+        return Err(malformed());
+    }
+    if code != [0] {
+        let diagnostic = take(&mut op)
+            .and_then(|_| take(&mut op))
+            .map(|(_, msg)| String::from_utf8_lossy(msg).into_owned())
+            .unwrap_or_default();
+        let code = code.iter().fold(0u32, |code, b| code << 8 | *b as u32);
+        return Err(refused(
+            "ldap",
+            &format!("result code {} {}", code, diagnostic),
+        ));
+    }
+
+    p.finish()?;
+    connector.connect(domain, socket).await
+}
+
+/// Waits for the client's StartTLS extended request and upgrades the connection.
+///
+/// The client has to send the request as its first message.
+pub async fn accept(
+    acceptor: &TlsAcceptor,
+    socket: TcpStream,
+) -> io::Result<TlsStream<ServerConnection>> {
+    let mut p = Plaintext::new(&socket);
+
+    let msg = read_message(&mut p).await?;
+    let mut msg = &msg[..];
+    let (tag, id) = take(&mut msg)?;
+    let (op_tag, mut op) = take(&mut msg)?;
+    if tag != INTEGER || id.is_empty() {
+        return Err(malformed());
+    }
+    if op_tag != EXTENDED_REQUEST || take(&mut op)? != (REQUEST_NAME, START_TLS_OID) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "ldap client did not request starttls",
+        ));
+    }
+
+    let mut op = Vec::new();
+    put(&mut op, ENUMERATED, &[0]);
+    put(&mut op, OCTET_STRING, b"");
+    put(&mut op, OCTET_STRING, b"");
+    put(&mut op, RESPONSE_NAME, START_TLS_OID);
+    let mut msg = Vec::new();
+    put(&mut msg, INTEGER, id);
+    put(&mut msg, EXTENDED_RESPONSE, &op);
+    let mut response = Vec::new();
+    put(&mut response, SEQUENCE, &msg);
+    p.write_all(response).await?;
+
+    p.finish()?;
+    acceptor.accept(socket).await
+}
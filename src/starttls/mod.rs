@@ -0,0 +1,112 @@
+//! Negotiators for protocols that start out in plaintext and upgrade to TLS on request.
+//!
+//! Each protocol has a `connect` function for the client side and an `accept` function for the server side.
+//! They drive the plaintext exchange on the socket up to the point where the upgrade is agreed on, then
+//! perform the handshake through [`TlsConnector::connect`](crate::TlsConnector::connect) or
+//! [`TlsAcceptor::accept`](crate::TlsAcceptor::accept).
+//!
+//! Plaintext that the peer sends after the upgrade command, but before the handshake, is rejected rather than
+//! carried over into the TLS session, as it could have been injected by a man in the middle.
+
+pub mod imap;
+pub mod ldap;
+pub mod pop3;
+pub mod postgres;
+pub mod smtp;
+
+use std::io::{self, Error, ErrorKind};
+use tokio_uring::net::TcpStream;
+
+/// Longest line accepted from the peer.
+const MAX_LINE: usize = 8192;
+
+/// Number of commands a server accepts before the client has to upgrade.
+const MAX_COMMANDS: usize = 16;
+
+/// Buffered plaintext access to the socket during the negotiation.
+struct Plaintext<'a> {
+    io: &'a TcpStream,
+    buf: Vec<u8>,
+}
+
+impl<'a> Plaintext<'a> {
+    fn new(io: &'a TcpStream) -> Self {
+        Plaintext {
+            io,
+            buf: Vec::new(),
+        }
+    }
+
+    async fn fill(&mut self) -> io::Result<()> {
+        let (res, buf) = self.io.read(vec![0u8; 1024]).await;
+        match res? {
+            0 => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed during starttls negotiation",
+            )),
+            n => {
+                self.buf.extend_from_slice(&buf[..n]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Reads a line, without the line ending.
+    async fn read_line(&mut self) -> io::Result<String> {
+        loop {
+            if let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=pos).collect();
+                let line = line.strip_suffix(b"\n").unwrap_or(&line);
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                return Ok(String::from_utf8_lossy(line).into_owned());
+            }
+            if self.buf.len() > MAX_LINE {
+                return Err(Error::new(ErrorKind::InvalidData, "line too long"));
+            }
+            self.fill().await?;
+        }
+    }
+
+    async fn read_exact(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        while self.buf.len() < n {
+            self.fill().await?;
+        }
+        Ok(self.buf.drain(..n).collect())
+    }
+
+    async fn write_all(&self, data: impl Into<Vec<u8>>) -> io::Result<()> {
+        self.io.write_all(data.into()).await.0
+    }
+
+    /// Ends the negotiation, making sure the peer did not send anything ahead of the handshake.
+    fn finish(self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "plaintext data received after the starttls command",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Splits a command line into its uppercased verb and its arguments.
+fn split_command(line: &str) -> (String, &str) {
+    let (verb, args) = line.split_once(' ').unwrap_or((line, ""));
+    (verb.to_ascii_uppercase(), args.trim())
+}
+
+fn refused(protocol: &str, response: &str) -> Error {
+    Error::other(format!(
+        "{} server refused starttls: {}",
+        protocol, response
+    ))
+}
+
+fn too_many_commands() -> Error {
+    Error::new(ErrorKind::InvalidData, "too many commands before starttls")
+}
+
+fn quit() -> Error {
+    Error::new(ErrorKind::ConnectionAborted, "client quit before starttls")
+}
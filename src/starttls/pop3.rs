@@ -0,0 +1,65 @@
+//! POP3, upgraded with the `STLS` command from RFC 2595.
+
+use super::{quit, refused, split_command, too_many_commands, Plaintext, MAX_COMMANDS};
+use crate::{TlsAcceptor, TlsConnector, TlsStream};
+
+use rustls::{ClientConnection, ServerConnection};
+use std::io;
+use tokio_uring::net::TcpStream;
+
+/// Waits for the server greeting, sends `STLS` and upgrades the connection.
+pub async fn connect(
+    connector: &TlsConnector,
+    domain: rustls::ServerName,
+    socket: TcpStream,
+) -> io::Result<TlsStream<ClientConnection>> {
+    let mut p = Plaintext::new(&socket);
+
+    let greeting = p.read_line().await?;
+    if !greeting.starts_with("+OK") {
+        return Err(refused("pop3", &greeting));
+    }
+
+    p.write_all("STLS\r\n").await?;
+    let response = p.read_line().await?;
+    if !response.starts_with("+OK") {
+        return Err(refused("pop3", &response));
+    }
+
+    p.finish()?;
+    connector.connect(domain, socket).await
+}
+
+/// Greets the client, answers its commands until it sends `STLS`, and upgrades the connection.
+///
+/// Only `CAPA`, `NOOP` and `QUIT` are served, anything else is rejected until the connection has been
+/// upgraded.
+pub async fn accept(
+    acceptor: &TlsAcceptor,
+    socket: TcpStream,
+) -> io::Result<TlsStream<ServerConnection>> {
+    let mut p = Plaintext::new(&socket);
+    p.write_all("+OK POP3 server ready\r\n").await?;
+
+    for _ in 0..MAX_COMMANDS {
+        let line = p.read_line().await?;
+        let reply = match split_command(&line).0.as_str() {
+            "CAPA" => "+OK Capability list follows\r\nSTLS\r\n.\r\n",
+            "NOOP" => "+OK\r\n",
+            "STLS" => {
+                p.write_all("+OK Begin TLS negotiation\r\n").await?;
+                p.finish()?;
+                return acceptor.accept(socket).await;
+            }
+            "QUIT" => {
+                p.write_all("+OK Bye\r\n").await?;
+                return Err(quit());
+            }
+            _ => "-ERR Command not allowed before STLS\r\n",
+        };
+        p.write_all(reply).await?;
+    }
+
+    p.write_all("-ERR Too many commands\r\n").await?;
+    Err(too_many_commands())
+}
@@ -0,0 +1,83 @@
+//! PostgreSQL, upgraded with an `SSLRequest` ahead of the startup message.
+
+use super::{refused, too_many_commands, Plaintext, MAX_COMMANDS};
+use crate::{TlsAcceptor, TlsConnector, TlsStream};
+
+use rustls::{ClientConnection, ServerConnection};
+use std::io::{self, Error, ErrorKind};
+use tokio_uring::net::TcpStream;
+
+/// Request code of an `SSLRequest`.
+const SSL_REQUEST: u32 = 80877103;
+
+/// Request code of a `GSSENCRequest`, which is declined in favour of TLS.
+const GSSENC_REQUEST: u32 = 80877104;
+
+fn request(code: u32) -> Vec<u8> {
+    let mut msg = 8u32.to_be_bytes().to_vec();
+    msg.extend_from_slice(&code.to_be_bytes());
+    msg
+}
+
+/// Sends an `SSLRequest` and upgrades the connection if the server agrees to it.
+///
+/// A server without TLS support answers with `N`, which fails with an error rather than falling back to
+/// plaintext.
+pub async fn connect(
+    connector: &TlsConnector,
+    domain: rustls::ServerName,
+    socket: TcpStream,
+) -> io::Result<TlsStream<ClientConnection>> {
+    let mut p = Plaintext::new(&socket);
+
+    p.write_all(request(SSL_REQUEST)).await?;
+    match p.read_exact(1).await?[0] {
+        b'S' => {}
+        b'N' => return Err(refused("postgres", "N")),
+        // Servers that predate TLS support answer with an ErrorResponse
+        b'E' => return Err(refused("postgres", "ErrorResponse")),
+        b => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unexpected postgres response to SSLRequest: {:#04x}", b),
+            ))
+        }
+    }
+
+    p.finish()?;
+    connector.connect(domain, socket).await
+}
+
+/// Waits for the client's `SSLRequest` and upgrades the connection.
+///
+/// A `GSSENCRequest` is declined so that the client can follow up with an `SSLRequest`. Clients that send
+/// their startup message without asking for TLS first are rejected.
+pub async fn accept(
+    acceptor: &TlsAcceptor,
+    socket: TcpStream,
+) -> io::Result<TlsStream<ServerConnection>> {
+    let mut p = Plaintext::new(&socket);
+
+    for _ in 0..MAX_COMMANDS {
+        let msg = p.read_exact(8).await?;
+        let len = u32::from_be_bytes([msg[0], msg[1], msg[2], msg[3]]);
+        let code = u32::from_be_bytes([msg[4], msg[5], msg[6], msg[7]]);
+
+        match (len, code) {
+            (8, SSL_REQUEST) => {
+                p.write_all(vec![b'S']).await?;
+                p.finish()?;
+                return acceptor.accept(socket).await;
+            }
+            (8, GSSENC_REQUEST) => p.write_all(vec![b'N']).await?,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "postgres client did not request tls",
+                ))
+            }
+        }
+    }
+
+    Err(too_many_commands())
+}
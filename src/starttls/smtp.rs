@@ -0,0 +1,111 @@
+//! SMTP, upgraded with the `STARTTLS` command from RFC 3207.
+
+use super::{quit, refused, split_command, too_many_commands, Plaintext, MAX_COMMANDS};
+use crate::{TlsAcceptor, TlsConnector, TlsStream};
+
+use rustls::{ClientConnection, ServerConnection};
+use std::io::{self, Error, ErrorKind};
+use tokio_uring::net::TcpStream;
+
+/// Reads a possibly multi-line reply, returning its code and the text of every line.
+async fn read_reply(p: &mut Plaintext<'_>) -> io::Result<(u16, Vec<String>)> {
+    let mut lines = Vec::new();
+    loop {
+        let line = p.read_line().await?;
+        let code = match line.get(..3).and_then(|c| c.parse().ok()) {
+            Some(code) => code,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("malformed smtp reply: {}", line),
+                ))
+            }
+        };
+        let last = line.as_bytes().get(3) != Some(&b'-');
+        lines.push(line.get(4..).unwrap_or_default().to_string());
+        if last {
+            return Ok((code, lines));
+        }
+    }
+}
+
+/// Greets the server with `EHLO ehlo_name`, checks that it offers `STARTTLS`, and upgrades the connection.
+///
+/// `ehlo_name` is the client's own host name, or an address literal such as `[192.0.2.1]`.
+pub async fn connect(
+    connector: &TlsConnector,
+    domain: rustls::ServerName,
+    socket: TcpStream,
+    ehlo_name: &str,
+) -> io::Result<TlsStream<ClientConnection>> {
+    let mut p = Plaintext::new(&socket);
+
+    let (code, lines) = read_reply(&mut p).await?;
+    if code != 220 {
+        return Err(refused("smtp", &lines.join(" ")));
+    }
+
+    p.write_all(format!("EHLO {}\r\n", ehlo_name)).await?;
+    let (code, lines) = read_reply(&mut p).await?;
+    if code != 250 {
+        return Err(refused("smtp", &lines.join(" ")));
+    }
+    let offered = lines
+        .iter()
+        .any(|line| line.split(' ').next() == Some("STARTTLS"));
+    if !offered {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "smtp server does not offer starttls",
+        ));
+    }
+
+    p.write_all("STARTTLS\r\n").await?;
+    let (code, lines) = read_reply(&mut p).await?;
+    if code != 220 {
+        return Err(refused("smtp", &lines.join(" ")));
+    }
+
+    p.finish()?;
+    connector.connect(domain, socket).await
+}
+
+/// Greets the client as `hostname`, answers its commands until it sends `STARTTLS`, and upgrades the
+/// connection.
+///
+/// Only `EHLO`, `HELO`, `NOOP`, `RSET` and `QUIT` are served, anything else is rejected until the connection
+/// has been upgraded.
+pub async fn accept(
+    acceptor: &TlsAcceptor,
+    socket: TcpStream,
+    hostname: &str,
+) -> io::Result<TlsStream<ServerConnection>> {
+    let mut p = Plaintext::new(&socket);
+    p.write_all(format!("220 {} ESMTP ready\r\n", hostname))
+        .await?;
+
+    for _ in 0..MAX_COMMANDS {
+        let line = p.read_line().await?;
+        let (verb, args) = split_command(&line);
+        let reply = match verb.as_str() {
+            "EHLO" => format!("250-{}\r\n250 STARTTLS\r\n", hostname),
+            "HELO" => format!("250 {}\r\n", hostname),
+            "STARTTLS" if args.is_empty() => {
+                p.write_all("220 2.0.0 Ready to start TLS\r\n").await?;
+                p.finish()?;
+                return acceptor.accept(socket).await;
+            }
+            "STARTTLS" => "501 5.5.4 Syntax error\r\n".to_string(),
+            "NOOP" | "RSET" => "250 2.0.0 OK\r\n".to_string(),
+            "QUIT" => {
+                p.write_all("221 2.0.0 Bye\r\n").await?;
+                return Err(quit());
+            }
+            _ => "530 5.7.0 Must issue a STARTTLS command first\r\n".to_string(),
+        };
+        p.write_all(reply).await?;
+    }
+
+    p.write_all("421 4.7.0 Too many commands\r\n").await?;
+    Err(too_many_commands())
+}
@@ -1,10 +1,19 @@
-use crate::buffer::{SyncReadAdaptor, SyncWriteAdaptor};
+use crate::{
+    buffer::{SyncReadAdaptor, SyncWriteAdaptor},
+    proxy::ProxyHeader,
+};
 
 use rustls::{ConnectionCommon, SideData};
 use std::{
+    cell::RefCell,
+    error::Error,
+    fmt,
     io::{self, Read, Write},
     ops::{Deref, DerefMut},
+    os::fd::{AsRawFd, BorrowedFd},
+    rc::Rc,
 };
+use tokio::sync::{Mutex, MutexGuard};
 use tokio_uring::{net::TcpStream, BufResult};
 
 pub struct TlsStream<C> {
@@ -12,6 +21,400 @@ pub struct TlsStream<C> {
     pub(crate) session: C,
     pub(crate) rbuffer: SyncReadAdaptor,
     pub(crate) wbuffer: SyncWriteAdaptor,
+    pub(crate) proxy: Option<ProxyHeader>,
+}
+
+/// The runtime independent parts of a [`TlsStream`], see [`TlsStream::into_parts`].
+///
+/// Unlike the stream itself, the parts are `Send` as long as the session is, so they can be moved to another
+/// thread and turned back into a stream with [`TlsStream::from_parts`] on that thread's runtime.
+#[derive(Debug)]
+pub struct TlsStreamParts<C> {
+    /// The underlying socket.
+    pub socket: std::net::TcpStream,
+    /// The rustls session, including any plaintext that was decrypted but not read yet.
+    pub session: C,
+    /// Ciphertext read from the socket that rustls has not consumed yet.
+    pub read_buffer: Vec<u8>,
+    /// Ciphertext produced by rustls that has not been written to the socket yet.
+    pub write_buffer: Vec<u8>,
+    /// The PROXY protocol header the connection was accepted with, see [`TlsStream::proxy_header`].
+    pub proxy_header: Option<ProxyHeader>,
+}
+
+/// Error returned by [`TlsAcceptor::try_accept`](crate::TlsAcceptor::try_accept) and
+/// [`TlsConnector::try_connect`](crate::TlsConnector::try_connect) when the handshake fails.
+///
+/// It hands the socket back along with every byte received from the peer during the handshake, so that the
+/// caller can still respond, e.g. with a plaintext "this port speaks HTTPS" reply. Nothing is sent to the peer
+/// on failure, not even the alert rustls queued, as it would get in the way of such a reply.
+pub struct HandshakeError {
+    socket: TcpStream,
+    received: Vec<u8>,
+    error: io::Error,
+}
+
+impl HandshakeError {
+    pub(crate) fn new(socket: TcpStream, received: Vec<u8>, error: io::Error) -> Self {
+        HandshakeError {
+            socket,
+            received,
+            error,
+        }
+    }
+
+    /// The socket the handshake was attempted on.
+    pub fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    /// The raw bytes read from the socket before the handshake failed.
+    ///
+    /// Some of them may already have been processed, e.g. when the handshake fails after the peer's first
+    /// flight, in which case the session has acted on that flight before the error.
+    pub fn received(&self) -> &[u8] {
+        &self.received
+    }
+
+    /// The reason the handshake failed.
+    pub fn error(&self) -> &io::Error {
+        &self.error
+    }
+
+    /// The TLS error the handshake failed with, if the failure was not caused by the socket itself.
+    pub fn tls_error(&self) -> Option<&rustls::Error> {
+        self.error.get_ref()?.downcast_ref()
+    }
+
+    /// Returns the socket, the received bytes and the error.
+    pub fn into_parts(self) -> (TcpStream, Vec<u8>, io::Error) {
+        (self.socket, self.received, self.error)
+    }
+}
+
+impl fmt::Debug for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandshakeError")
+            .field("received", &self.received.len())
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tls handshake failed: {}", self.error)
+    }
+}
+
+impl Error for HandshakeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<HandshakeError> for io::Error {
+    fn from(err: HandshakeError) -> io::Error {
+        err.error
+    }
+}
+
+/// Short-lived mutable access to a rustls session.
+///
+/// The read and write paths below never hold on to the session across an `.await`, they only touch it
+/// inside of `with`. This is what allows a split stream to share one session between its halves through a
+/// `RefCell` without ever creating overlapping mutable references.
+pub(crate) trait Session<SD: SideData> {
+    fn with<R>(&mut self, f: impl FnOnce(&mut ConnectionCommon<SD>) -> R) -> R;
+}
+
+impl<C, SD: SideData> Session<SD> for &mut C
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<SD>>,
+{
+    #[inline]
+    fn with<R>(&mut self, f: impl FnOnce(&mut ConnectionCommon<SD>) -> R) -> R {
+        f(self)
+    }
+}
+
+impl<C, SD: SideData> Session<SD> for &RefCell<C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<SD>>,
+{
+    #[inline]
+    fn with<R>(&mut self, f: impl FnOnce(&mut ConnectionCommon<SD>) -> R) -> R {
+        // The borrow is released before `with` returns, so it can never be observed by the other half.
+        f(&mut self.borrow_mut())
+    }
+}
+
+/// Access to the write buffer from the read path.
+///
+/// Reading may leave records in the session that rustls wants to send back, e.g. a TLS 1.3 KeyUpdate
+/// response or an alert. The read path flushes them through the write buffer if it can get hold of it without
+/// waiting. For a split stream, failing to do so means the write half is in the middle of a write, and its
+/// flush loop picks up the queued records before it releases the buffer.
+pub(crate) trait WriteBuffer {
+    type Guard<'a>: DerefMut<Target = SyncWriteAdaptor>
+    where
+        Self: 'a;
+
+    fn try_acquire(&mut self) -> Option<Self::Guard<'_>>;
+}
+
+impl WriteBuffer for &mut SyncWriteAdaptor {
+    type Guard<'a>
+        = &'a mut SyncWriteAdaptor
+    where
+        Self: 'a;
+
+    #[inline]
+    fn try_acquire(&mut self) -> Option<Self::Guard<'_>> {
+        Some(self)
+    }
+}
+
+impl WriteBuffer for &Rc<Mutex<SyncWriteAdaptor>> {
+    type Guard<'a>
+        = MutexGuard<'a, SyncWriteAdaptor>
+    where
+        Self: 'a;
+
+    #[inline]
+    fn try_acquire(&mut self) -> Option<Self::Guard<'_>> {
+        self.try_lock().ok()
+    }
+}
+
+/// Reads ciphertext from the socket into the session and processes it.
+pub(crate) async fn read_io<SD: SideData>(
+    io: &TcpStream,
+    session: &mut impl Session<SD>,
+    rbuffer: &mut SyncReadAdaptor,
+) -> io::Result<usize> {
+    let n = loop {
+        match session.with(|s| s.read_tls(rbuffer)) {
+            Ok(n) => {
+                break n;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                rbuffer.do_io(io).await?;
+                continue;
+            }
+            Err(err) => return Err(err),
+        }
+    };
+
+    let state = match session.with(|s| s.process_new_packets()) {
+        Ok(state) => state,
+        Err(err) => {
+            // User should manually shutdown it on error.
+            return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+        }
+    };
+
+    if state.peer_has_closed() && session.with(|s| s.is_handshaking()) {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "tls handshake alert",
+        ));
+    }
+
+    Ok(n)
+}
+
+/// Moves pending records out of the session and writes them to the socket.
+pub(crate) async fn write_io<SD: SideData>(
+    io: &TcpStream,
+    session: &mut impl Session<SD>,
+    wbuffer: &mut SyncWriteAdaptor,
+) -> io::Result<usize> {
+    let n = loop {
+        match session.with(|s| s.write_tls(wbuffer)) {
+            Ok(n) => {
+                break n;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                wbuffer.do_io(io).await?;
+                continue;
+            }
+            Err(err) => return Err(err),
+        }
+    };
+
+    wbuffer.do_io(io).await?;
+
+    Ok(n)
+}
+
+pub(crate) async fn read<SD: SideData, B: tokio_uring::buf::IoBufMut>(
+    io: &TcpStream,
+    session: &mut impl Session<SD>,
+    rbuffer: &mut SyncReadAdaptor,
+    mut wbuffer: impl WriteBuffer,
+    mut buf: B,
+) -> BufResult<usize, B> {
+    // Safety: bytes_total property promises the capacity of the buffer, such that we won't overrun.
+    let slice = unsafe { std::slice::from_raw_parts_mut(buf.stable_mut_ptr(), buf.bytes_total()) };
+
+    loop {
+        // read from rustls to buffer
+        match session.with(|s| s.reader().read(slice)) {
+            Ok(n) => {
+                // Safety: we already know from the reader that we have read n bytes, so the n bytes must
+                // be stored in the buffer.
+                unsafe { buf.set_init(n) };
+
+                return (Ok(n), buf);
+            }
+            // we need more data, read something.
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => {
+                return (Err(e), buf);
+            }
+        }
+
+        // now we need data, read something into rustls
+        let result = read_io(io, session, rbuffer).await;
+
+        // send out whatever rustls queued in response, this includes the alert if processing failed. A failure
+        // here is not a read failure, the write path reports it on its next write.
+        if session.with(|s| s.wants_write()) {
+            if let Some(mut wbuffer) = wbuffer.try_acquire() {
+                let _ = flush(io, session, &mut wbuffer).await;
+            }
+        }
+
+        match result {
+            Ok(0) => {
+                return (
+                    Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "tls raw stream eof",
+                    )),
+                    buf,
+                );
+            }
+            Ok(_) => (),
+            Err(e) => {
+                return (Err(e), buf);
+            }
+        };
+    }
+}
+
+/// Writes out every record the session has queued.
+pub(crate) async fn flush<SD: SideData>(
+    io: &TcpStream,
+    session: &mut impl Session<SD>,
+    wbuffer: &mut SyncWriteAdaptor,
+) -> io::Result<()> {
+    while session.with(|s| s.wants_write()) {
+        match write_io(io, session, wbuffer).await {
+            Ok(0) => {
+                break;
+            }
+            Ok(_) => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn shutdown<SD: SideData>(
+    io: &TcpStream,
+    session: &mut impl Session<SD>,
+    wbuffer: &mut SyncWriteAdaptor,
+) -> io::Result<()> {
+    session.with(|s| s.send_close_notify());
+    flush(io, session, wbuffer).await?;
+    io.shutdown(std::net::Shutdown::Write)
+}
+
+pub(crate) async fn write<SD: SideData, B: tokio_uring::buf::IoBuf>(
+    io: &TcpStream,
+    session: &mut impl Session<SD>,
+    wbuffer: &mut SyncWriteAdaptor,
+    buf: B,
+) -> BufResult<usize, B> {
+    let slice = unsafe { std::slice::from_raw_parts(buf.stable_ptr(), buf.bytes_init()) };
+
+    let size = match session.with(|s| {
+        let size = s.writer().write(slice)?;
+        s.writer().flush()?;
+        Ok(size)
+    }) {
+        Ok(size) => size,
+        Err(e) => return (Err(e), buf),
+    };
+
+    if let Err(e) = flush(io, session, wbuffer).await {
+        return (Err(e), buf);
+    }
+
+    (Ok(size), buf)
+}
+
+pub(crate) async fn write_all<SD: SideData, B: tokio_uring::buf::IoBuf>(
+    io: &TcpStream,
+    session: &mut impl Session<SD>,
+    wbuffer: &mut SyncWriteAdaptor,
+    buf: B,
+) -> BufResult<(), B> {
+    let slice = unsafe { std::slice::from_raw_parts(buf.stable_ptr(), buf.bytes_init()) };
+
+    if let Err(e) = session.with(|s| {
+        s.writer().write_all(slice)?;
+        s.writer().flush()
+    }) {
+        return (Err(e), buf);
+    }
+
+    if let Err(e) = flush(io, session, wbuffer).await {
+        return (Err(e), buf);
+    }
+
+    (Ok(()), buf)
+}
+
+impl<C> TlsStream<C> {
+    /// Takes the stream apart so that it can be handed to another tokio-uring runtime.
+    ///
+    /// The socket is detached from the current runtime by duplicating its file descriptor, the original
+    /// descriptor is closed when the runtime bound socket is dropped. No bytes are lost, both the ciphertext
+    /// buffered by this crate and the plaintext buffered by rustls are part of the returned [`TlsStreamParts`].
+    pub fn into_parts(self) -> io::Result<TlsStreamParts<C>> {
+        // Safety: the descriptor is owned by `self.io`, which stays alive until after the duplicate is made.
+        let fd = unsafe { BorrowedFd::borrow_raw(self.io.as_raw_fd()) }.try_clone_to_owned()?;
+
+        Ok(TlsStreamParts {
+            socket: std::net::TcpStream::from(fd),
+            read_buffer: self.rbuffer.buffered()?.to_vec(),
+            write_buffer: self.wbuffer.buffered()?.to_vec(),
+            session: self.session,
+            proxy_header: self.proxy,
+        })
+    }
+
+    /// The PROXY protocol header sent ahead of the handshake, for connections accepted with
+    /// [`TlsAcceptor::accept_proxied`](crate::TlsAcceptor::accept_proxied).
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.proxy.as_ref()
+    }
+
+    /// Rebuilds a stream from parts obtained through [`TlsStream::into_parts`], registering the socket with
+    /// the runtime of the calling thread.
+    pub fn from_parts(parts: TlsStreamParts<C>) -> Self {
+        TlsStream {
+            io: TcpStream::from_std(parts.socket),
+            session: parts.session,
+            rbuffer: SyncReadAdaptor::with_buffered(&parts.read_buffer),
+            wbuffer: SyncWriteAdaptor::with_buffered(&parts.write_buffer),
+            proxy: parts.proxy_header,
+        }
+    }
 }
 
 impl<C, SD: SideData> TlsStream<C>
@@ -24,67 +427,40 @@ where
             session,
             rbuffer: SyncReadAdaptor::default(),
             wbuffer: SyncWriteAdaptor::default(),
+            proxy: None,
         }
     }
 
-    async fn read_io(&mut self) -> io::Result<usize> {
-        let n = loop {
-            match self.session.read_tls(&mut self.rbuffer) {
-                Ok(n) => {
-                    break n;
-                }
-                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
-                    self.rbuffer.do_io(&mut self.io).await?;
-                    continue;
-                }
-                Err(err) => return Err(err),
-            }
-        };
-
-        let state = match self.session.process_new_packets() {
-            Ok(state) => state,
-            Err(err) => {
-                // When to write_io? If we do this in read call, the UnsafeWrite may crash
-                // when we impl split in an UnsafeCell way.
-                // Here we choose not to do write when read.
-                // User should manually shutdown it on error.
-                // if !splitted {
-                //     let _ = self.write_io().await;
-                // }
-                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
-            }
-        };
-
-        if state.peer_has_closed() && self.session.is_handshaking() {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "tls handshake alert",
-            ));
+    /// Creates a stream that processes `prefix` as if it was the first data read from the socket.
+    pub(crate) fn with_prefix(io: TcpStream, session: C, prefix: &[u8]) -> Self {
+        TlsStream {
+            io,
+            session,
+            rbuffer: SyncReadAdaptor::with_buffered(prefix),
+            wbuffer: SyncWriteAdaptor::default(),
+            proxy: None,
         }
+    }
 
-        Ok(n)
+    async fn read_io(&mut self) -> io::Result<usize> {
+        read_io(&self.io, &mut &mut self.session, &mut self.rbuffer).await
     }
 
     async fn write_io(&mut self) -> io::Result<usize> {
-        let n = loop {
-            match self.session.write_tls(&mut self.wbuffer) {
-                Ok(n) => {
-                    break n;
-                }
-                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
-                    self.wbuffer.do_io(&mut self.io).await?;
-                    continue;
-                }
-                Err(err) => return Err(err),
-            }
-        };
-
-        self.wbuffer.do_io(&mut self.io).await?;
-
-        Ok(n)
+        write_io(&self.io, &mut &mut self.session, &mut self.wbuffer).await
     }
 
+    /// Runs the handshake. If it fails, the alert rustls queued for the error is sent to the peer.
     pub(crate) async fn handshake(&mut self) -> io::Result<(usize, usize)> {
+        let result = self.drive_handshake().await;
+        if result.is_err() {
+            self.send_alert().await;
+        }
+        result
+    }
+
+    /// Runs the handshake, sending nothing more if it fails.
+    async fn drive_handshake(&mut self) -> io::Result<(usize, usize)> {
         let mut wrlen = 0;
         let mut rdlen = 0;
         let mut eof = false;
@@ -122,93 +498,190 @@ where
         Ok((rdlen, wrlen))
     }
 
-    pub async fn read<B: tokio_uring::buf::IoBufMut>(&mut self, mut buf: B) -> BufResult<usize, B> {
-        // Safety: bytes_total property promises the capacity of the buffer, such that we won't overrun.
-        let slice =
-            unsafe { std::slice::from_raw_parts_mut(buf.stable_mut_ptr(), buf.bytes_total()) };
+    /// Runs the handshake, showing `observe` every byte received from the peer after each read, until it
+    /// returns true. Only the bytes up to that point are kept.
+    pub(crate) async fn observed_handshake(
+        &mut self,
+        mut observe: impl FnMut(&[u8]) -> bool,
+    ) -> io::Result<()> {
+        self.rbuffer.start_recording();
+        let result = self.observe_handshake(&mut observe).await;
+        self.rbuffer.take_recorded();
+        if let Err(e) = result {
+            self.send_alert().await;
+            return Err(e);
+        }
+        self.handshake().await.map(|_| ())
+    }
 
-        loop {
-            // read from rustls to buffer
-            match self.session.reader().read(slice) {
-                Ok(n) => {
-                    // Safety: we already know from the reader that we have read n bytes, so the n bytes must
-                    // be stored in the buffer.
-                    unsafe { buf.set_init(n) };
-
-                    return (Ok(n), buf);
-                }
-                // we need more data, read something.
-                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
-                Err(e) => {
-                    return (Err(e), buf);
-                }
+    async fn observe_handshake(
+        &mut self,
+        observe: &mut impl FnMut(&[u8]) -> bool,
+    ) -> io::Result<()> {
+        while self.session.is_handshaking() {
+            while self.session.wants_write() && self.session.is_handshaking() {
+                self.write_io().await?;
             }
-
-            // now we need data, read something into rustls
-            match self.read_io().await {
-                Ok(0) => {
-                    return (
-                        Err(io::Error::new(
-                            io::ErrorKind::UnexpectedEof,
-                            "tls raw stream eof",
-                        )),
-                        buf,
-                    );
+            if self.session.wants_read() && self.session.is_handshaking() {
+                if self.read_io().await? == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "tls handshake eof",
+                    ));
                 }
-                Ok(_) => (),
-                Err(e) => {
-                    return (Err(e), buf);
+                if observe(self.rbuffer.recorded().unwrap_or_default()) {
+                    break;
                 }
-            };
+            }
         }
+        Ok(())
     }
 
-    pub async fn write<B: tokio_uring::buf::IoBuf>(&mut self, buf: B) -> BufResult<usize, B> {
-        let slice = unsafe { std::slice::from_raw_parts(buf.stable_ptr(), buf.bytes_init()) };
-
-        let size = match self.session.writer().write(slice) {
-            Ok(l) => l,
-            Err(e) => return (Err(e), buf),
-        };
-
-        if let Err(e) = self.session.writer().flush() {
-            return (Err(e), buf);
+    /// Runs the handshake, handing the socket back through [`HandshakeError`] if it fails.
+    ///
+    /// On success, every byte received from the peer during the handshake is returned along with the stream.
+    pub(crate) async fn try_handshake(mut self) -> Result<(Self, Vec<u8>), HandshakeError> {
+        self.rbuffer.start_recording();
+        let result = self.drive_handshake().await;
+        let received = self.rbuffer.take_recorded();
+        match result {
+            Ok(_) => Ok((self, received)),
+            Err(e) => Err(HandshakeError::new(self.io, received, e)),
         }
+    }
 
+    /// Writes what the session has queued after a failed handshake, usually a fatal alert. A failure to do so
+    /// is ignored, the error of the handshake is what matters to the caller.
+    async fn send_alert(&mut self) {
         while self.session.wants_write() {
-            match self.write_io().await {
-                Ok(0) => {
-                    break;
-                }
-                Ok(_) => (),
-                Err(e) => return (Err(e), buf),
+            if !matches!(self.write_io().await, Ok(n) if n > 0) {
+                break;
             }
         }
+    }
+
+    pub async fn read<B: tokio_uring::buf::IoBufMut>(&mut self, buf: B) -> BufResult<usize, B> {
+        read(
+            &self.io,
+            &mut &mut self.session,
+            &mut self.rbuffer,
+            &mut self.wbuffer,
+            buf,
+        )
+        .await
+    }
 
-        return (Ok(size), buf);
+    pub async fn write<B: tokio_uring::buf::IoBuf>(&mut self, buf: B) -> BufResult<usize, B> {
+        write(&self.io, &mut &mut self.session, &mut self.wbuffer, buf).await
     }
 
     pub async fn write_all<B: tokio_uring::buf::IoBuf>(&mut self, buf: B) -> BufResult<(), B> {
-        let slice = unsafe { std::slice::from_raw_parts(buf.stable_ptr(), buf.bytes_init()) };
+        write_all(&self.io, &mut &mut self.session, &mut self.wbuffer, buf).await
+    }
 
-        if let Err(e) = self.session.writer().write_all(slice) {
-            return (Err(e), buf);
-        }
+    /// Sends a close_notify alert and shuts down the write side of the socket.
+    ///
+    /// The stream can still be read from until the peer closes its side.
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        shutdown(&self.io, &mut &mut self.session, &mut self.wbuffer).await
+    }
+}
+
+// A model of how the two halves of a split stream share the session and the write buffer, without a socket or
+// io_uring, so it also runs under Miri. The interleavings are spelled out step by step, as they are the only
+// ones a single threaded runtime can produce: the halves only switch at an `.await`.
+#[cfg(test)]
+mod tests {
+    use super::{Session, WriteBuffer};
+    use crate::buffer::SyncWriteAdaptor;
+
+    use rustls::{
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig, ServerConnection,
+    };
+    use std::{cell::RefCell, rc::Rc, sync::Arc};
+    use tokio::sync::Mutex;
+
+    /// Resolves no certificate, so that a session can be created without any key material or crypto.
+    struct NoCert;
 
-        if let Err(e) = self.session.writer().flush() {
-            return (Err(e), buf);
+    impl ResolvesServerCert for NoCert {
+        fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+            None
         }
+    }
 
-        while self.session.wants_write() {
-            match self.write_io().await {
-                Ok(0) => {
-                    break;
-                }
-                Ok(_) => (),
-                Err(e) => return (Err(e), buf),
-            }
+    fn session() -> Rc<RefCell<ServerConnection>> {
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(NoCert));
+        Rc::new(RefCell::new(
+            ServerConnection::new(Arc::new(config)).unwrap(),
+        ))
+    }
+
+    /// What the read path does with ciphertext it got from the socket.
+    fn process(mut session: &RefCell<ServerConnection>, mut ciphertext: &[u8]) -> bool {
+        session.with(|s| {
+            s.read_tls(&mut ciphertext).unwrap();
+            s.process_new_packets().is_ok()
+        })
+    }
+
+    // `with` never lets a borrow escape, so each half can take the session whenever it runs.
+    #[test]
+    fn session_is_borrowed_only_inside_with() {
+        let session = session();
+        let (mut read_half, mut write_half) = (&*session, &*session);
+
+        assert!(read_half.with(|s| s.wants_read()));
+        assert!(session.try_borrow_mut().is_ok());
+
+        write_half.with(|s| s.send_close_notify());
+        assert!(session.try_borrow_mut().is_ok());
+
+        // A borrow held by the other half, which `with` never does, would be caught rather than aliased.
+        let held = session.borrow_mut();
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            read_half.with(|_| ())
+        }))
+        .is_err());
+        drop(held);
+        assert!(session.try_borrow_mut().is_ok());
+    }
+
+    // The read path leaves what it can't flush to a write in flight, whose flush loop sends it before it
+    // releases the write buffer.
+    #[test]
+    fn read_leaves_the_flush_to_an_in_flight_write() {
+        let session = session();
+        let wbuffer = Rc::new(Mutex::new(SyncWriteAdaptor::default()));
+
+        // The write half holds the buffer while it waits for the socket.
+        let mut in_flight = wbuffer.try_lock().unwrap();
+
+        // The read half takes in a record rustls rejects, which queues an alert. It can't get the buffer to
+        // flush it, and must not wait for it either.
+        assert!(!process(&session, &[0x17, 3, 3, 0, 1, 0]));
+        assert!(session.try_borrow_mut().is_ok());
+        let mut read_half = &*session;
+        assert!(read_half.with(|s| s.wants_write()));
+        assert!((&wbuffer).try_acquire().is_none());
+
+        // Back in the write half, the flush loop finds the alert before the buffer is released.
+        let mut write_half = &*session;
+        while write_half.with(|s| s.wants_write()) {
+            write_half.with(|s| s.write_tls(&mut *in_flight)).unwrap();
         }
+        let buffered = in_flight.buffered().unwrap();
+        assert_eq!(buffered[0], 0x15);
+        assert_eq!(&buffered[3..5], &[0, 2]);
+        drop(in_flight);
 
-        return (Ok(()), buf);
+        // Without a write in flight, the read half flushes itself.
+        let mut wbuffer = &wbuffer;
+        assert!(wbuffer.try_acquire().is_some());
     }
 }
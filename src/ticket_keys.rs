@@ -0,0 +1,317 @@
+use crate::{
+    fs::{read_file, write_file},
+    resumption::{ServerResumptionStats, ServerStats},
+    server::TlsAcceptor,
+};
+
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use rustls::server::ProducesTickets;
+use std::{
+    io::{self, Error, ErrorKind},
+    path::Path,
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{Duration, Instant},
+};
+
+/// Tickets are valid for 12 hours by default.
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+
+const KEY_NAME_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// A key in a key file, its name followed by its secret.
+const KEY_FILE_ENTRY_LEN: usize = KEY_NAME_LEN + KEY_LEN;
+
+/// A ticket encryption key.
+struct Key {
+    name: [u8; KEY_NAME_LEN],
+    secret: [u8; KEY_LEN],
+    aead: LessSafeKey,
+    /// When the key stopped encrypting new tickets.
+    retired: Option<Instant>,
+}
+
+impl Key {
+    fn new(entry: &[u8]) -> Self {
+        let (name, secret) = entry.split_at(KEY_NAME_LEN);
+        let unbound = UnboundKey::new(&aead::CHACHA20_POLY1305, secret)
+            .expect("bug: key length matches the algorithm");
+        Key {
+            name: name.try_into().unwrap(),
+            secret: secret.try_into().unwrap(),
+            aead: LessSafeKey::new(unbound),
+            retired: None,
+        }
+    }
+
+    fn generate(rng: &SystemRandom) -> io::Result<Self> {
+        let mut entry = [0u8; KEY_FILE_ENTRY_LEN];
+        rng.fill(&mut entry)
+            .map_err(|_| Error::other("failed to generate ticket key"))?;
+        Ok(Key::new(&entry))
+    }
+}
+
+/// Session ticket encryption keys (STEKs) that are rotated on a schedule, and can be loaded from disk to share
+/// them across restarts and instances.
+///
+/// The first key encrypts new tickets, all of them decrypt. A rotation puts a new key first, the previous ones
+/// stay around until the tickets they encrypted have expired. Tickets are encrypted with ChaCha20-Poly1305.
+/// Plug it in with [`TlsAcceptor::ticket_keys`].
+///
+/// A key file holds one or more 48 byte keys back to back, each a 16 byte name followed by a 32 byte secret,
+/// the first of them encrypts. It can be written by [`TicketKeys::save`], or by whatever distributes the keys
+/// of a fleet, in which case each instance follows it with [`TicketKeys::watch`]. A single key can be made with
+/// `head -c 48 /dev/urandom`.
+///
+/// The file doesn't record when keys were rotated out, their order does: when a file is loaded, every key but
+/// the first is taken as retired, from the time it was retired in this process if it was, or else from the
+/// load. Retired keys are dropped a [lifetime](TicketKeys::lifetime) later, so after a restart they can be
+/// kept up to one lifetime longer than needed, but never forever.
+pub struct TicketKeys {
+    keys: RwLock<Vec<Key>>,
+    lifetime: Duration,
+    rng: SystemRandom,
+    on_update: Option<Box<dyn Fn(io::Result<()>) + Send + Sync>>,
+    stats: ServerStats,
+}
+
+impl TicketKeys {
+    /// Creates a set with a single random key.
+    pub fn new() -> io::Result<Self> {
+        let rng = SystemRandom::new();
+        Ok(TicketKeys {
+            keys: RwLock::new(vec![Key::generate(&rng)?]),
+            lifetime: DEFAULT_LIFETIME,
+            rng,
+            on_update: None,
+            stats: ServerStats::default(),
+        })
+    }
+
+    /// Sets how long tickets can be used, 12 hours by default. This is also how long a key is kept for
+    /// decryption once it has been rotated out, keys should be rotated at least this often.
+    pub fn lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// Sets a callback that is told the outcome of every update made by [`TicketKeys::rotate_every`] or
+    /// [`TicketKeys::watch`].
+    pub fn on_update(mut self, f: impl Fn(io::Result<()>) + Send + Sync + 'static) -> Self {
+        self.on_update = Some(Box::new(f));
+        self
+    }
+
+    /// The number of keys that can decrypt tickets.
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> ServerResumptionStats {
+        self.stats.get()
+    }
+
+    /// Puts a new random key first, keeping the previous ones for decryption.
+    pub fn rotate(&self) -> io::Result<()> {
+        let key = Key::generate(&self.rng)?;
+        let now = Instant::now();
+
+        let mut keys = self.write();
+        for key in keys.iter_mut() {
+            key.retired.get_or_insert(now);
+        }
+        keys.retain(|k| k.retired.is_none_or(|at| now - at < self.lifetime));
+        keys.insert(0, key);
+        Ok(())
+    }
+
+    /// Replaces the keys with those of a key file, the first of them encrypting and the others retired.
+    pub async fn load(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let data = read_file(path).await?;
+        self.apply(&data)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    /// Writes the keys that can still decrypt tickets to a key file, readable by the owner only.
+    pub async fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let data = {
+            let keys = self.read();
+            let mut data = Vec::with_capacity(keys.len() * KEY_FILE_ENTRY_LEN);
+            for key in keys.iter().filter(|k| self.is_live(k)) {
+                data.extend_from_slice(&key.name);
+                data.extend_from_slice(&key.secret);
+            }
+            data
+        };
+        write_file(path.as_ref(), data).await
+    }
+
+    /// Rotates the keys at every interval, saving them to `path` if set, so they survive a restart.
+    ///
+    /// Never returns, it is meant to be spawned on the runtime.
+    pub async fn rotate_every(&self, interval: Duration, path: Option<&Path>) {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let mut result = self.rotate();
+            if let (Ok(()), Some(path)) = (&result, path) {
+                result = self.save(path).await;
+            }
+
+            if let Some(on_update) = &self.on_update {
+                on_update(result);
+            }
+        }
+    }
+
+    /// Loads the keys from `path` right away, then whenever its contents change, checking at every interval.
+    ///
+    /// This is for keys that are rotated by another process, e.g. one distributing them to a fleet, which has
+    /// to keep the previous keys in the file for as long as their tickets can be used. Never returns, it is
+    /// meant to be spawned on the runtime.
+    pub async fn watch(&self, path: impl AsRef<Path>, interval: Duration) {
+        let path = path.as_ref();
+        let mut seen: Option<Vec<u8>> = None;
+
+        loop {
+            let result = match read_file(path).await {
+                Ok(data) if seen.as_ref() != Some(&data) => {
+                    let result = self
+                        .apply(&data)
+                        .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)));
+                    // Remember the contents even if they were rejected, so a broken file is reported once.
+                    seen = Some(data);
+                    Some(result)
+                }
+                Ok(_) => None,
+                // The file is likely in the middle of being replaced, the next check will pick it up.
+                Err(_) if seen.is_some() => None,
+                Err(e) => Some(Err(e)),
+            };
+
+            if let (Some(result), Some(on_update)) = (result, &self.on_update) {
+                on_update(result);
+            }
+
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    fn apply(&self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() || !data.len().is_multiple_of(KEY_FILE_ENTRY_LEN) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "key file must hold one or more {} byte keys",
+                    KEY_FILE_ENTRY_LEN
+                ),
+            ));
+        }
+        let now = Instant::now();
+        let mut keys = self.write();
+        let loaded = data
+            .chunks(KEY_FILE_ENTRY_LEN)
+            .map(Key::new)
+            .enumerate()
+            .map(|(i, mut key)| {
+                if i > 0 {
+                    let known = keys
+                        .iter()
+                        .find(|k| k.name == key.name && k.secret == key.secret);
+                    key.retired = Some(known.and_then(|k| k.retired).unwrap_or(now));
+                }
+                key
+            })
+            .collect();
+        *keys = loaded;
+        Ok(())
+    }
+
+    fn is_live(&self, key: &Key) -> bool {
+        key.retired.is_none_or(|at| at.elapsed() < self.lifetime)
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Vec<Key>> {
+        // The lock is never held across anything that can panic, poisoning can be ignored.
+        self.keys.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Vec<Key>> {
+        self.keys.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl ProducesTickets for TicketKeys {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.lifetime.as_secs().min(u32::MAX as u64) as u32
+    }
+
+    /// Tickets are the key name, a random nonce, and the encrypted session followed by its tag.
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).ok()?;
+
+        let keys = self.read();
+        let key = keys.first()?;
+        let mut sealed = plain.to_vec();
+        key.aead
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(&key.name),
+                &mut sealed,
+            )
+            .ok()?;
+
+        let mut ticket = Vec::with_capacity(KEY_NAME_LEN + NONCE_LEN + sealed.len());
+        ticket.extend_from_slice(&key.name);
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(&sealed);
+        self.stats.issued();
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let plain = (|| {
+            let (name, rest) = cipher.split_at_checked(KEY_NAME_LEN)?;
+            let (nonce, sealed) = rest.split_at_checked(NONCE_LEN)?;
+
+            let keys = self.read();
+            let key = keys.iter().find(|k| k.name == name && self.is_live(k))?;
+            let mut sealed = sealed.to_vec();
+            let plain = key
+                .aead
+                .open_in_place(
+                    Nonce::try_assume_unique_for_key(nonce).ok()?,
+                    Aad::from(name),
+                    &mut sealed,
+                )
+                .ok()?;
+            Some(plain.to_vec())
+        })();
+        self.stats.looked_up(plain)
+    }
+}
+
+impl TlsAcceptor {
+    /// Encrypts the session tickets of the acceptor with `keys`, so they can be resumed after a restart or on
+    /// another instance that has the same keys.
+    ///
+    /// The acceptor gets a copy of its config with the keys set, as does any config it is later used with, see
+    /// [`TlsAcceptor::accept_lazy`] and [`ReloadableTlsAcceptor`](crate::ReloadableTlsAcceptor).
+    pub fn ticket_keys(self, keys: Arc<TicketKeys>) -> Self {
+        self.with_policy(|policy| policy.ticketer = Some(keys))
+    }
+}
@@ -0,0 +1,166 @@
+mod common;
+
+use common::Identity;
+use std::{io, sync::Arc, time::Duration};
+use tokio_uring_rustls::{AlpnDispatcher, TlsConnector};
+
+fn dispatcher() -> AlpnDispatcher {
+    AlpnDispatcher::new()
+        .protocol(b"h2", |mut stream| async move {
+            let _ = stream.write_all(b"h2".to_vec()).await;
+        })
+        .protocol(b"http/1.1", |mut stream| async move {
+            let _ = stream.write_all(b"http/1.1".to_vec()).await;
+        })
+}
+
+fn connector(identity: &Identity, protocol: &[u8]) -> TlsConnector {
+    let mut config = identity.client_config();
+    config.alpn_protocols = vec![protocol.to_vec()];
+    TlsConnector::from(Arc::new(config))
+}
+
+// The selector resolves the config asynchronously, from what it copied out of the ClientHello.
+#[test]
+fn serve_with_async_selector() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let acceptor = identity.acceptor();
+        let dispatcher = dispatcher();
+
+        for protocol in [&b"h2"[..], b"http/1.1"] {
+            let (server, client) = common::socket_pair().await;
+            let connector = connector(&identity, protocol);
+            let client = tokio_uring::spawn(async move {
+                let mut client = connector
+                    .connect("localhost".try_into().unwrap(), client)
+                    .await
+                    .unwrap();
+                common::read_exact(&mut client, 64).await
+            });
+
+            let config = acceptor.config();
+            dispatcher
+                .serve_with(&acceptor, server, |hello| {
+                    let name = hello.server_name().map(str::to_owned);
+                    async move {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        assert_eq!(name.as_deref(), Some("localhost"));
+                        Ok(config)
+                    }
+                })
+                .await
+                .unwrap();
+            assert_eq!(client.await.unwrap(), protocol);
+        }
+    });
+}
+
+// An error from the selector fails the accept before the handshake goes on.
+#[test]
+fn serve_with_selector_error() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (server, client) = common::socket_pair().await;
+        let connector = connector(&identity, b"h2");
+        let client = tokio_uring::spawn(async move {
+            connector
+                .connect("localhost".try_into().unwrap(), client)
+                .await
+        });
+
+        let err = dispatcher()
+            .serve_with(&identity.acceptor(), server, |_| async {
+                Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "unknown server name",
+                ))
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(client.await.unwrap().is_err());
+    });
+}
+
+/// Serves a client that offers `alpn`, returning the error of the server and the TLS error of the client.
+async fn rejected(alpn: Vec<Vec<u8>>) -> (io::Error, Option<rustls::Error>) {
+    let identity = Identity::new();
+    let (server, client) = common::socket_pair().await;
+    let mut config = identity.client_config();
+    config.alpn_protocols = alpn;
+    let client = tokio_uring::spawn(async move {
+        let err = TlsConnector::from(Arc::new(config))
+            .connect("localhost".try_into().unwrap(), client)
+            .await
+            .err()
+            .unwrap();
+        err.get_ref()
+            .and_then(|e| e.downcast_ref::<rustls::Error>())
+            .cloned()
+    });
+
+    let err = dispatcher()
+        .serve(&identity.acceptor(), server)
+        .await
+        .unwrap_err();
+    (err, client.await.unwrap())
+}
+
+// A client that doesn't use ALPN, or offers only protocols without a handler, gets a no_application_protocol
+// alert.
+#[test]
+fn unsupported_alpn_is_rejected_with_alert() {
+    tokio_uring::start(async {
+        for alpn in [vec![], vec![b"spdy/3".to_vec(), b"h3".to_vec()]] {
+            let (err, client) = rejected(alpn.clone()).await;
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", alpn);
+            assert_eq!(
+                client,
+                Some(rustls::Error::AlertReceived(
+                    rustls::AlertDescription::NoApplicationProtocol
+                )),
+                "{:?}",
+                alpn
+            );
+        }
+    });
+}
+
+// The alert is a plaintext record of its own, nothing else is sent before the socket is closed.
+#[test]
+fn alert_record_on_the_wire() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (server, client) = common::socket_pair().await;
+        let mut conn = rustls::ClientConnection::new(
+            Arc::new(identity.client_config()),
+            "localhost".try_into().unwrap(),
+        )
+        .unwrap();
+        let mut hello = Vec::new();
+        conn.write_tls(&mut hello).unwrap();
+
+        let client = tokio_uring::spawn(async move {
+            let (res, _) = client.write_all(hello).await;
+            res.unwrap();
+            let mut received = Vec::new();
+            loop {
+                let (res, buf) = client.read(vec![0u8; 64]).await;
+                match res.unwrap() {
+                    0 => return received,
+                    n => received.extend_from_slice(&buf[..n]),
+                }
+            }
+        });
+
+        dispatcher()
+            .serve(&identity.acceptor(), server)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            client.await.unwrap(),
+            [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x78]
+        );
+    });
+}
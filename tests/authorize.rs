@@ -0,0 +1,228 @@
+mod common;
+
+use common::Identity;
+use rustls::{
+    server::AllowAnyAuthenticatedClient, AlertDescription, CertificateError, ClientConfig,
+    RootCertStore, ServerConfig,
+};
+use std::{
+    future::ready,
+    io,
+    sync::{Arc, Mutex},
+};
+use tokio_uring::net::TcpStream;
+use tokio_uring_rustls::{
+    AlpnDispatcher, AuthorizingVerifier, PeerIdentity, ReloadableTlsAcceptor, TlsConnector,
+};
+
+/// A server and a client that authenticates to it with a certificate of its own.
+struct Mtls {
+    server: Identity,
+    client: Identity,
+}
+
+impl Mtls {
+    fn new() -> Self {
+        Mtls {
+            server: Identity::new(),
+            client: Identity::new(),
+        }
+    }
+
+    /// A server config that verifies the client certificate, then asks `authorize`.
+    fn server_config(
+        &self,
+        authorize: impl Fn(&PeerIdentity) -> bool + Send + Sync + 'static,
+    ) -> Arc<ServerConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&self.client.cert).unwrap();
+        let verifier =
+            AuthorizingVerifier::new(AllowAnyAuthenticatedClient::new(roots).boxed(), authorize);
+        Arc::new(
+            ServerConfig::builder()
+                .with_safe_defaults()
+                .with_client_cert_verifier(Arc::new(verifier))
+                .with_single_cert(vec![self.server.cert.clone()], self.server.key.clone())
+                .unwrap(),
+        )
+    }
+
+    fn client_config(&self) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots.add(&self.server.cert).unwrap();
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(vec![self.client.cert.clone()], self.client.key.clone())
+            .unwrap();
+        // So that an `AlpnDispatcher` takes the connection.
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        config
+    }
+}
+
+/// Connects and reads, returning the TLS error the client ends up with, if any.
+async fn client_error(config: ClientConfig, socket: TcpStream) -> Option<rustls::Error> {
+    let tls_error = |e: io::Error| {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<rustls::Error>())
+            .cloned()
+    };
+    // Over TLS 1.3 the client is done with its side of the handshake before the server looks at its
+    // certificate, the alert arrives with the first read.
+    let connected = TlsConnector::from(Arc::new(config))
+        .connect("localhost".try_into().unwrap(), socket)
+        .await;
+    let mut stream = match connected {
+        Ok(stream) => stream,
+        Err(e) => return tls_error(e),
+    };
+    let (res, _) = stream.read(vec![0u8; 64]).await;
+    res.err().and_then(tls_error)
+}
+
+/// Runs `accept` against a client, returning the error of the accept and the error of the client.
+macro_rules! rejected {
+    ($mtls:expr, |$server:ident| $accept:expr) => {{
+        let (server, client) = common::socket_pair().await;
+        let client = tokio_uring::spawn(client_error($mtls.client_config(), client));
+        let $server = server;
+        let err: io::Error = $accept;
+        (err, client.await.unwrap())
+    }};
+}
+
+fn is_application_failure(err: &io::Error) -> bool {
+    matches!(
+        err.get_ref()
+            .and_then(|e| e.downcast_ref::<rustls::Error>()),
+        Some(rustls::Error::InvalidCertificate(
+            CertificateError::ApplicationVerificationFailure
+        ))
+    )
+}
+
+// The check is part of the config, so every way of accepting a connection runs it, and rustls sends the
+// rejected client an access_denied alert.
+#[test]
+fn every_accept_path_sends_access_denied() {
+    tokio_uring::start(async {
+        let mtls = Mtls::new();
+        let config = mtls.server_config(|_| false);
+        let acceptor = tokio_uring_rustls::TlsAcceptor::from(config.clone());
+        let reloadable = ReloadableTlsAcceptor::from(config.clone());
+        let dispatcher = AlpnDispatcher::new().protocol(b"h2", |_| async {});
+
+        let results = vec![
+            (
+                "accept",
+                rejected!(mtls, |s| acceptor.accept(s).await.err().unwrap()),
+            ),
+            (
+                "accept_with_prefix",
+                rejected!(mtls, |s| acceptor
+                    .accept_with_prefix(s, &[])
+                    .await
+                    .err()
+                    .unwrap()),
+            ),
+            (
+                "accept_lazy",
+                rejected!(mtls, |s| {
+                    let start = acceptor.accept_lazy(s).await.unwrap();
+                    start.into_stream(config.clone()).await.err().unwrap()
+                }),
+            ),
+            (
+                "AlpnDispatcher::serve",
+                rejected!(mtls, |s| dispatcher
+                    .serve(&acceptor, s)
+                    .await
+                    .err()
+                    .unwrap()),
+            ),
+            (
+                "AlpnDispatcher::serve_with",
+                rejected!(mtls, |s| dispatcher
+                    .serve_with(&acceptor, s, |_| ready(Ok(config.clone())))
+                    .await
+                    .err()
+                    .unwrap()),
+            ),
+            (
+                "ReloadableTlsAcceptor::accept",
+                rejected!(mtls, |s| reloadable.accept(s).await.err().unwrap()),
+            ),
+        ];
+
+        for (path, (err, client)) in results {
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", path);
+            assert!(is_application_failure(&err), "{}: {}", path, err);
+            assert!(
+                matches!(
+                    client,
+                    Some(rustls::Error::AlertReceived(AlertDescription::AccessDenied))
+                ),
+                "{}: {:?}",
+                path,
+                client
+            );
+        }
+    });
+}
+
+// try_accept hands the socket back without sending anything, but fails the same way.
+#[test]
+fn try_accept_is_authorized() {
+    tokio_uring::start(async {
+        let mtls = Mtls::new();
+        let acceptor = tokio_uring_rustls::TlsAcceptor::from(mtls.server_config(|_| false));
+
+        let (err, _) = rejected!(mtls, |s| acceptor
+            .try_accept(s)
+            .await
+            .err()
+            .unwrap()
+            .into_parts()
+            .2);
+        assert!(is_application_failure(&err), "{}", err);
+    });
+}
+
+// The callback sees the identity of the verified client, and lets it through.
+#[test]
+fn authorized_client_is_accepted() {
+    tokio_uring::start(async {
+        let mtls = Mtls::new();
+        let seen = Arc::new(Mutex::new(None));
+        let config = {
+            let seen = seen.clone();
+            mtls.server_config(move |peer| {
+                *seen.lock().unwrap() = Some(peer.clone());
+                peer.dns_names() == ["localhost"]
+            })
+        };
+        let acceptor = tokio_uring_rustls::TlsAcceptor::from(config);
+
+        let (server, client) = common::socket_pair().await;
+        let connector = TlsConnector::from(Arc::new(mtls.client_config()));
+        let client = tokio_uring::spawn(async move {
+            let mut stream = connector
+                .connect("localhost".try_into().unwrap(), client)
+                .await
+                .unwrap();
+            let (res, _) = stream.write_all(b"ping".to_vec()).await;
+            res.unwrap();
+            stream
+        });
+
+        let mut stream = acceptor.accept(server).await.unwrap();
+        let (res, buf) = stream.read(vec![0u8; 64]).await;
+        assert_eq!(&buf[..res.unwrap()], b"ping");
+        assert_eq!(
+            stream.peer_identity().unwrap(),
+            seen.lock().unwrap().clone()
+        );
+        let _client = client.await.unwrap();
+    });
+}
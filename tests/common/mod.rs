@@ -0,0 +1,137 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, PrivateKey, RootCertStore, ServerConfig,
+    ServerConnection, StreamOwned, SupportedProtocolVersion,
+};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio_uring::net::{TcpListener, TcpStream};
+use tokio_uring_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+
+/// A self signed certificate for `localhost` with its private key.
+pub struct Identity {
+    pub cert: Certificate,
+    pub key: PrivateKey,
+}
+
+impl Identity {
+    pub fn new() -> Self {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        Identity {
+            cert: Certificate(cert.serialize_der().unwrap()),
+            key: PrivateKey(cert.serialize_private_key_der()),
+        }
+    }
+
+    pub fn server_config(&self) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![self.cert.clone()], self.key.clone())
+            .unwrap()
+    }
+
+    pub fn client_config(&self) -> ClientConfig {
+        self.client_config_with_versions(rustls::DEFAULT_VERSIONS)
+    }
+
+    pub fn client_config_with_versions(
+        &self,
+        versions: &[&'static SupportedProtocolVersion],
+    ) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots.add(&self.cert).unwrap();
+        ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(Arc::new(self.server_config()))
+    }
+
+    pub fn connector(&self) -> TlsConnector {
+        TlsConnector::from(Arc::new(self.client_config()))
+    }
+}
+
+/// A directory of its own for each test, removed when dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "tokio-uring-rustls-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A listener on a random port of the loopback interface.
+pub fn listener() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    (listener, addr)
+}
+
+/// Two connected sockets on the loopback interface, the accepted one first.
+pub async fn socket_pair() -> (TcpStream, TcpStream) {
+    let (listener, addr) = listener();
+    let client = tokio_uring::spawn(async move { TcpStream::connect(addr).await.unwrap() });
+    let (server, _) = listener.accept().await.unwrap();
+    (server, client.await.unwrap())
+}
+
+/// Both ends of a TLS connection on the loopback interface.
+pub async fn stream_pair(
+    identity: &Identity,
+) -> (TlsStream<ServerConnection>, TlsStream<ClientConnection>) {
+    let (server, client) = socket_pair().await;
+    let connector = identity.connector();
+    let client = tokio_uring::spawn(async move {
+        connector
+            .connect("localhost".try_into().unwrap(), client)
+            .await
+            .unwrap()
+    });
+    let server = identity.acceptor().accept(server).await.unwrap();
+    (server, client.await.unwrap())
+}
+
+/// A blocking client for a peer running on its own thread, the handshake runs on first use.
+pub fn blocking_client(
+    addr: SocketAddr,
+    config: ClientConfig,
+) -> StreamOwned<ClientConnection, std::net::TcpStream> {
+    let socket = std::net::TcpStream::connect(addr).unwrap();
+    let conn = ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+    StreamOwned::new(conn, socket)
+}
+
+/// Reads until `n` bytes were read or the stream ends, returning what was read.
+pub async fn read_exact(stream: &mut TlsStream<ClientConnection>, n: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(n);
+    while data.len() < n {
+        let (res, buf) = stream.read(vec![0u8; 64 * 1024]).await;
+        match res {
+            Ok(0) | Err(_) => break,
+            Ok(read) => data.extend_from_slice(&buf[..read]),
+        }
+    }
+    data
+}
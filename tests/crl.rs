@@ -0,0 +1,214 @@
+mod common;
+
+use common::{Identity, TempDir};
+use rcgen::{
+    date_time_ymd, BasicConstraints, Certificate, CertificateParams, CertificateRevocationList,
+    CertificateRevocationListParams, ExtendedKeyUsagePurpose, IsCa, KeyIdMethod, KeyUsagePurpose,
+    RevocationReason, RevokedCertParams, PKCS_ECDSA_P256_SHA256,
+};
+use rustls::{
+    AlertDescription, CertificateError, ClientConfig, PrivateKey, RootCertStore, ServerConfig,
+};
+use std::{io, path::PathBuf, sync::Arc};
+use tokio_uring_rustls::{CrlVerifier, TlsAcceptor, TlsConnector};
+
+/// A CA issuing client certificates, with a directory for its CRLs.
+struct Pki {
+    dir: TempDir,
+    ca: Certificate,
+    server: Identity,
+}
+
+impl Pki {
+    fn new(name: &str) -> Self {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        let ca = Certificate::from_params(params).unwrap();
+
+        let dir = TempDir::new(name);
+        std::fs::write(dir.0.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+        std::fs::create_dir(dir.0.join("crls")).unwrap();
+        Pki {
+            dir,
+            ca,
+            server: Identity::new(),
+        }
+    }
+
+    fn crl_path(&self) -> PathBuf {
+        self.dir.0.join("crls").join("ca.crl")
+    }
+
+    /// Writes a CRL that revokes the given serial numbers.
+    fn write_crl(&self, revoked: &[u64]) {
+        let crl = CertificateRevocationList::from_params(CertificateRevocationListParams {
+            this_update: date_time_ymd(2020, 1, 1),
+            next_update: date_time_ymd(2099, 1, 1),
+            crl_number: 1u64.into(),
+            issuing_distribution_point: None,
+            revoked_certs: revoked
+                .iter()
+                .map(|serial| RevokedCertParams {
+                    serial_number: (*serial).into(),
+                    revocation_time: date_time_ymd(2021, 1, 1),
+                    reason_code: Some(RevocationReason::KeyCompromise),
+                    invalidity_date: None,
+                })
+                .collect(),
+            alg: &PKCS_ECDSA_P256_SHA256,
+            key_identifier_method: KeyIdMethod::Sha256,
+        })
+        .unwrap();
+        std::fs::write(
+            self.crl_path(),
+            crl.serialize_pem_with_signer(&self.ca).unwrap(),
+        )
+        .unwrap();
+    }
+
+    async fn verifier(&self) -> Arc<CrlVerifier> {
+        Arc::new(
+            CrlVerifier::new(self.dir.0.join("ca.pem"), self.dir.0.join("crls"))
+                .await
+                .unwrap(),
+        )
+    }
+
+    fn acceptor(&self, verifier: Arc<CrlVerifier>) -> TlsAcceptor {
+        TlsAcceptor::from(Arc::new(
+            ServerConfig::builder()
+                .with_safe_defaults()
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(vec![self.server.cert.clone()], self.server.key.clone())
+                .unwrap(),
+        ))
+    }
+
+    /// A client config with a certificate issued by the CA.
+    fn client_config(&self, serial: u64) -> ClientConfig {
+        let mut params = CertificateParams::new(vec!["client".to_string()]);
+        params.serial_number = Some(serial.into());
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let cert = Certificate::from_params(params).unwrap();
+        let chain = vec![rustls::Certificate(
+            cert.serialize_der_with_signer(&self.ca).unwrap(),
+        )];
+
+        let mut roots = RootCertStore::empty();
+        roots.add(&self.server.cert).unwrap();
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(chain, PrivateKey(cert.serialize_private_key_der()))
+            .unwrap()
+    }
+}
+
+/// Connects a client with the certificate of `serial`, returning the error of the accept and the TLS error the
+/// client ends up with, if any.
+async fn connect(
+    pki: &Pki,
+    acceptor: &TlsAcceptor,
+    serial: u64,
+) -> Result<(), (io::Error, Option<rustls::Error>)> {
+    let (server, client) = common::socket_pair().await;
+    let connector = TlsConnector::from(Arc::new(pki.client_config(serial)));
+    let client = tokio_uring::spawn(async move {
+        // Over TLS 1.3 the server looks at the certificate after the client is done, the alert arrives with
+        // the first read.
+        let mut stream = connector
+            .connect("localhost".try_into().unwrap(), client)
+            .await?;
+        let (res, _) = stream.read(vec![0u8; 64]).await;
+        res.map(|_| ())
+    });
+
+    match acceptor.accept(server).await {
+        Ok(mut stream) => {
+            stream.shutdown().await.unwrap();
+            client.await.unwrap().unwrap();
+            Ok(())
+        }
+        Err(err) => {
+            let client = client.await.unwrap().err().and_then(|e| {
+                e.get_ref()
+                    .and_then(|e| e.downcast_ref::<rustls::Error>())
+                    .cloned()
+            });
+            Err((err, client))
+        }
+    }
+}
+
+fn assert_revoked(result: Result<(), (io::Error, Option<rustls::Error>)>) {
+    let (err, client) = result.unwrap_err();
+    assert!(
+        matches!(
+            err.get_ref()
+                .and_then(|e| e.downcast_ref::<rustls::Error>()),
+            Some(rustls::Error::InvalidCertificate(CertificateError::Revoked))
+        ),
+        "{}",
+        err
+    );
+    assert_eq!(
+        client,
+        Some(rustls::Error::AlertReceived(
+            AlertDescription::CertificateRevoked
+        ))
+    );
+}
+
+// A certificate on the CRL is rejected, one that isn't is accepted.
+#[test]
+fn revoked_certificate_is_rejected() {
+    tokio_uring::start(async {
+        let pki = Pki::new("crl-revoked");
+        pki.write_crl(&[2]);
+        let verifier = pki.verifier().await;
+        let acceptor = pki.acceptor(verifier.clone());
+
+        connect(&pki, &acceptor, 1).await.unwrap();
+        assert_revoked(connect(&pki, &acceptor, 2).await);
+
+        let crls = verifier.crls();
+        assert_eq!(crls.len(), 1);
+        assert_eq!(crls[0].path(), pki.crl_path());
+        assert_eq!(crls[0].revoked_count(), 1);
+    });
+}
+
+// A reload that fails keeps the lists in use, a later good one replaces them.
+#[test]
+fn failed_reload_keeps_previous_lists() {
+    tokio_uring::start(async {
+        let pki = Pki::new("crl-reload");
+        pki.write_crl(&[2]);
+        let verifier = pki.verifier().await;
+        let acceptor = pki.acceptor(verifier.clone());
+
+        std::fs::write(pki.crl_path(), "not a crl").unwrap();
+        let err = verifier.reload().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            err.to_string()
+                .contains(&pki.crl_path().display().to_string()),
+            "{}",
+            err
+        );
+
+        std::fs::remove_file(pki.crl_path()).unwrap();
+        let err = verifier.reload().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        assert_eq!(verifier.crls()[0].revoked_count(), 1);
+        connect(&pki, &acceptor, 1).await.unwrap();
+        assert_revoked(connect(&pki, &acceptor, 2).await);
+
+        pki.write_crl(&[1]);
+        verifier.reload().await.unwrap();
+        assert_revoked(connect(&pki, &acceptor, 1).await);
+        connect(&pki, &acceptor, 2).await.unwrap();
+    });
+}
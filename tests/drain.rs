@@ -0,0 +1,93 @@
+mod common;
+
+use common::Identity;
+use std::{io, time::Duration};
+use tokio::time::timeout;
+use tokio_uring_rustls::Drain;
+
+// The handler is dropped while its read is in flight, the close_notify still goes out.
+#[test]
+fn force_close_during_read() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (server, mut client) = common::stream_pair(&identity).await;
+        let drain = Drain::new();
+
+        let served = {
+            let drain = drain.clone();
+            tokio_uring::spawn(async move {
+                drain
+                    .serve(server, |stream| {
+                        Box::pin(async move {
+                            let _ = stream.read(vec![0u8; 1024]).await;
+                        })
+                    })
+                    .await
+            })
+        };
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drain.shutdown(Duration::from_millis(50)).await;
+        assert!(served.await.unwrap());
+
+        let (res, _) = client.read(vec![0u8; 64]).await;
+        assert_eq!(res.unwrap(), 0);
+    });
+}
+
+// The handler is dropped while its write is stuck on a peer that isn't reading. The stream can't send the
+// alert anymore, closing it must fail cleanly rather than panic.
+#[test]
+fn force_close_during_write() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (server, mut client) = common::stream_pair(&identity).await;
+        let drain = Drain::new();
+
+        let served = {
+            let drain = drain.clone();
+            tokio_uring::spawn(async move {
+                drain
+                    .serve(server, |stream| {
+                        Box::pin(async move {
+                            loop {
+                                let (res, _) = stream.write_all(vec![0u8; 64 * 1024]).await;
+                                if res.is_err() {
+                                    return;
+                                }
+                            }
+                        })
+                    })
+                    .await
+            })
+        };
+
+        // Let the socket buffers fill up.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drain.shutdown(Duration::from_millis(50)).await;
+        assert!(served.await.unwrap());
+        assert_eq!(drain.active(), 0);
+
+        // The peer gets whatever made it out, then the connection ends without a close_notify.
+        let end = timeout(Duration::from_secs(10), async {
+            loop {
+                match client.read(vec![0u8; 64 * 1024]).await.0 {
+                    Ok(0) => return Ok(0),
+                    Ok(_) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        })
+        .await
+        .expect("connection never ended");
+        let err = end.unwrap_err();
+        assert!(
+            matches!(
+                err.kind(),
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData
+            ),
+            "{:?}",
+            err
+        );
+    });
+}
@@ -0,0 +1,149 @@
+mod common;
+
+use common::Identity;
+use rustls::{version, ProtocolVersion, ServerConfig, SupportedProtocolVersion};
+use std::{
+    io::ErrorKind,
+    mem,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+    sync::Arc,
+    thread,
+};
+use tokio_uring::net::TcpStream;
+use tokio_uring_rustls::{ExportedStream, ResumedStream, TlsAcceptor, TlsConnector};
+
+fn migrate(versions: &[&'static SupportedProtocolVersion], expected: ProtocolVersion) {
+    let identity = Identity::new();
+    let mut config: ServerConfig = identity.server_config();
+    config.enable_secret_extraction = true;
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let connector = TlsConnector::from(Arc::new(identity.client_config_with_versions(versions)));
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (old_process, new_process) = UnixStream::pair().unwrap();
+
+    // The old runtime serves the first request, then hands the connection over.
+    let old = thread::spawn(move || {
+        tokio_uring::start(async move {
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = acceptor.accept(TcpStream::from_std(socket)).await.unwrap();
+
+            let (res, buf) = stream.read(vec![0u8; 1024]).await;
+            assert_eq!(&buf[..res.unwrap()], b"first request");
+            let (res, _) = stream.write_all(b"from the old runtime".to_vec()).await;
+            res.unwrap();
+
+            let exported = stream.export().await.unwrap();
+            assert_eq!(exported.state.protocol_version(), expected);
+            exported.send(&old_process).unwrap();
+        })
+    });
+
+    // The new runtime continues where the old one stopped.
+    let new = thread::spawn(move || {
+        let exported = ExportedStream::recv(&new_process).unwrap();
+        tokio_uring::start(async move {
+            let mut stream = ResumedStream::new(exported);
+
+            let (res, buf) = stream.read(vec![0u8; 1024]).await;
+            assert_eq!(&buf[..res.unwrap()], b"second request");
+            let (res, _) = stream.write_all(b"from the new runtime".to_vec()).await;
+            res.unwrap();
+            stream.shutdown().await.unwrap();
+        })
+    });
+
+    tokio_uring::start(async move {
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector
+            .connect("localhost".try_into().unwrap(), socket)
+            .await
+            .unwrap();
+
+        for (request, response) in [
+            ("first request", "from the old runtime"),
+            ("second request", "from the new runtime"),
+        ] {
+            let (res, _) = stream.write_all(request.as_bytes().to_vec()).await;
+            res.unwrap();
+            assert_eq!(
+                common::read_exact(&mut stream, response.len()).await,
+                response.as_bytes()
+            );
+        }
+
+        // The new runtime closed the connection cleanly.
+        let (res, _) = stream.read(vec![0u8; 64]).await;
+        assert_eq!(res.unwrap(), 0);
+    });
+
+    old.join().unwrap();
+    new.join().unwrap();
+}
+
+#[test]
+fn migrate_tls13_connection_between_runtimes() {
+    migrate(&[&version::TLS13], ProtocolVersion::TLSv1_3);
+}
+
+#[test]
+fn migrate_tls12_connection_between_runtimes() {
+    migrate(&[&version::TLS12], ProtocolVersion::TLSv1_2);
+}
+
+/// Sends `data` along with `fds` in a single message.
+fn send_fds(channel: &UnixStream, fds: &[RawFd], data: &[u8]) {
+    unsafe {
+        let mut cmsg_buffer = [0u64; 16];
+        let fds_len = mem::size_of_val(fds) as u32;
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buffer.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = libc::CMSG_SPACE(fds_len) as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+
+        assert_eq!(
+            libc::sendmsg(channel.as_raw_fd(), &msg, 0),
+            data.len() as isize
+        );
+    }
+}
+
+#[test]
+fn recv_rejects_oversized_state() {
+    let (sender, receiver) = UnixStream::pair().unwrap();
+    let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    send_fds(&sender, &[socket.as_raw_fd()], &u64::MAX.to_be_bytes());
+
+    let err = ExportedStream::recv(&receiver).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err.to_string().contains("too large"), "{}", err);
+}
+
+#[test]
+fn recv_rejects_truncated_control_message() {
+    let (sender, receiver) = UnixStream::pair().unwrap();
+    let sockets: Vec<_> = (0..8)
+        .map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap())
+        .collect();
+    let fds: Vec<_> = sockets.iter().map(|s| s.as_raw_fd()).collect();
+    send_fds(&sender, &fds, &64u64.to_be_bytes());
+
+    let err = ExportedStream::recv(&receiver).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err.to_string().contains("truncated"), "{}", err);
+}
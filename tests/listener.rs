@@ -0,0 +1,133 @@
+mod common;
+
+use common::Identity;
+use rustls::ClientConnection;
+use std::{
+    cell::RefCell,
+    io,
+    net::SocketAddr,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+use tokio_uring::net::TcpStream;
+use tokio_uring_rustls::{TlsConnector, TlsListener, TlsStream};
+
+/// The failed handshakes reported to `on_error`, with the time they were reported at.
+type Errors = Rc<RefCell<Vec<(SocketAddr, io::ErrorKind, Instant)>>>;
+
+fn listener(identity: &Identity) -> (TlsListener, SocketAddr, Errors) {
+    let (listener, addr) = common::listener();
+    let errors = Errors::default();
+    let listener = TlsListener::new(listener, identity.acceptor()).on_error({
+        let errors = errors.clone();
+        move |addr, err| errors.borrow_mut().push((addr, err.kind(), Instant::now()))
+    });
+    (listener, addr, errors)
+}
+
+/// A client that sends something that is not a ClientHello, and waits for the server to answer it.
+async fn bad_client(addr: SocketAddr) {
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let (res, _) = socket.write_all(b"GET / HTTP/1.1\r\n\r\n".to_vec()).await;
+    res.unwrap();
+    let _ = socket.read(vec![0u8; 64]).await;
+}
+
+/// A client that completes the handshake. The connection has to be kept open until the server is done with its
+/// side, closing it with the server's session tickets unread resets it.
+async fn good_client(connector: &TlsConnector, addr: SocketAddr) -> TlsStream<ClientConnection> {
+    let socket = TcpStream::connect(addr).await.unwrap();
+    connector
+        .connect("localhost".try_into().unwrap(), socket)
+        .await
+        .unwrap()
+}
+
+// A client that never sends its ClientHello is dropped once the handshake timeout passes.
+#[test]
+fn stalled_client_is_dropped_after_timeout() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (listener, addr, errors) = listener(&identity);
+        let mut listener = listener.handshake_timeout(Duration::from_millis(200));
+        let _accept = tokio_uring::spawn(async move { listener.accept().await.map(|_| ()) });
+
+        let start = Instant::now();
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let (res, _) = tokio::time::timeout(Duration::from_secs(5), socket.read(vec![0u8; 64]))
+            .await
+            .expect("the server did not close the connection");
+        assert_eq!(res.unwrap(), 0);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        let errors = errors.borrow();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].1, io::ErrorKind::TimedOut);
+        assert!(errors[0].0.ip().is_loopback());
+    });
+}
+
+// No more than `max_handshakes` clients are handshaked at once, the next ones wait for a slot to free up. With
+// stalled clients, each wave of them takes a full handshake timeout.
+#[test]
+fn max_handshakes_bounds_concurrent_handshakes() {
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (listener, addr, errors) = listener(&identity);
+        let mut listener = listener.max_handshakes(2).handshake_timeout(TIMEOUT);
+        let _accept = tokio_uring::spawn(async move { listener.accept().await.map(|_| ()) });
+
+        let start = Instant::now();
+        let mut stalled = Vec::new();
+        for _ in 0..4 {
+            stalled.push(TcpStream::connect(addr).await.unwrap());
+        }
+        while errors.borrow().len() < 4 {
+            assert!(
+                start.elapsed() < TIMEOUT * 10,
+                "stalled clients were not dropped"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let errors = errors.borrow();
+        let mut elapsed: Vec<_> = errors.iter().map(|(_, _, at)| *at - start).collect();
+        elapsed.sort();
+        assert!(elapsed[1] < TIMEOUT * 2, "{:?}", elapsed);
+        assert!(elapsed[2] >= TIMEOUT * 2, "{:?}", elapsed);
+        assert!(elapsed[3] >= TIMEOUT * 2, "{:?}", elapsed);
+    });
+}
+
+// A failed handshake is reported to on_error and doesn't stop accept from yielding the connections after it.
+#[test]
+fn accept_yields_good_connections_after_a_bad_one() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (mut listener, addr, errors) = listener(&identity);
+
+        let connector = identity.connector();
+        let clients = tokio_uring::spawn(async move {
+            bad_client(addr).await;
+            let first = good_client(&connector, addr).await;
+            bad_client(addr).await;
+            let second = good_client(&connector, addr).await;
+            (first, second)
+        });
+
+        for _ in 0..2 {
+            let (_stream, peer) = listener.accept().await.unwrap();
+            assert!(peer.ip().is_loopback());
+        }
+        clients.await.unwrap();
+
+        let errors = errors.borrow();
+        assert_eq!(errors.len(), 2);
+        for (peer, kind, _) in errors.iter() {
+            assert!(peer.ip().is_loopback());
+            assert_eq!(*kind, io::ErrorKind::InvalidData);
+        }
+    });
+}
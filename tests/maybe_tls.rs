@@ -0,0 +1,81 @@
+mod common;
+
+use common::Identity;
+use std::time::Duration;
+use tokio_uring_rustls::MaybeTlsStream;
+
+// A client waiting for the server to speak first is taken as plaintext once the timeout passes, and what it
+// sends afterwards is all read.
+#[test]
+fn silent_client_falls_back_to_plaintext() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let acceptor = identity
+            .acceptor()
+            .maybe_tls_timeout(Duration::from_millis(50));
+        let (server, client) = common::socket_pair().await;
+
+        let mut stream = acceptor.accept_maybe_tls(server).await.unwrap();
+        match &stream {
+            MaybeTlsStream::Plain { prefix, .. } => assert!(prefix.is_empty()),
+            MaybeTlsStream::Tls(_) => panic!("detected TLS without any bytes"),
+        }
+
+        let (res, _) = stream.write_all(b"220 ready\r\n".to_vec()).await;
+        res.unwrap();
+        let (res, buf) = client.read(vec![0u8; 64]).await;
+        assert_eq!(&buf[..res.unwrap()], b"220 ready\r\n");
+
+        let (res, _) = client.write_all(b"HELO\r\n".to_vec()).await;
+        res.unwrap();
+        let (res, buf) = stream.read(vec![0u8; 64]).await;
+        assert_eq!(&buf[..res.unwrap()], b"HELO\r\n");
+    });
+}
+
+#[test]
+fn tls_is_detected_within_timeout() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let acceptor = identity
+            .acceptor()
+            .maybe_tls_timeout(Duration::from_secs(5));
+        let connector = identity.connector();
+        let (server, client) = common::socket_pair().await;
+
+        let client = tokio_uring::spawn(async move {
+            let mut stream = connector
+                .connect("localhost".try_into().unwrap(), client)
+                .await
+                .unwrap();
+            let (res, _) = stream.write_all(b"ping".to_vec()).await;
+            res.unwrap();
+            stream
+        });
+
+        let mut stream = acceptor.accept_maybe_tls(server).await.unwrap();
+        assert!(stream.is_tls());
+        let (res, buf) = stream.read(vec![0u8; 64]).await;
+        assert_eq!(&buf[..res.unwrap()], b"ping");
+        let _client = client.await.unwrap();
+    });
+}
+
+#[test]
+fn plaintext_is_detected_within_timeout() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let acceptor = identity
+            .acceptor()
+            .maybe_tls_timeout(Duration::from_secs(5));
+        let (server, client) = common::socket_pair().await;
+
+        let (res, _) = client.write_all(b"GET / HTTP/1.1\r\n".to_vec()).await;
+        res.unwrap();
+        let stream = acceptor.accept_maybe_tls(server).await.unwrap();
+        match stream {
+            MaybeTlsStream::Plain { prefix, .. } => assert_eq!(prefix, b"GET / HTTP/1.1\r\n"),
+            MaybeTlsStream::Tls(_) => panic!("plaintext taken as TLS"),
+        }
+    });
+}
@@ -0,0 +1,178 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::{sync::mpsc, time::timeout};
+use tokio_uring_rustls::{OcspCertStatus, OcspEvent, OcspResolver};
+
+const SERIAL: u64 = 42;
+
+const GOOD: &[u8] = &[0x80, 0x00];
+
+/// Encodes a DER element.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    match content.len() {
+        n if n < 0x80 => out.push(n as u8),
+        n if n <= 0xff => out.extend_from_slice(&[0x81, n as u8]),
+        n => out.extend_from_slice(&[0x82, (n >> 8) as u8, n as u8]),
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn time(value: &str) -> Vec<u8> {
+    der(0x18, value.as_bytes())
+}
+
+/// The status of a certificate revoked at the start of 2020.
+fn revoked() -> Vec<u8> {
+    der(0xa1, &time("20200101000000Z"))
+}
+
+/// An unsigned OCSP response for the certificate with [`SERIAL`], signatures being up to the clients.
+fn response(status: &[u8], next_update: &str) -> Vec<u8> {
+    let cert_id = [
+        der(0x30, &der(0x06, &[0x2b, 0x0e, 0x03, 0x02, 0x1a])),
+        der(0x04, &[0; 20]),
+        der(0x04, &[0; 20]),
+        der(0x02, &[SERIAL as u8]),
+    ]
+    .concat();
+    let single = [
+        der(0x30, &cert_id),
+        status.to_vec(),
+        time("20200101000000Z"),
+        der(0xa0, &time(next_update)),
+    ]
+    .concat();
+    let data = [
+        der(0xa2, &der(0x04, &[0; 20])),
+        time("20200101000000Z"),
+        der(0x30, &der(0x30, &single)),
+    ]
+    .concat();
+    let basic = der(0x30, &der(0x30, &data));
+    let bytes = [
+        der(
+            0x06,
+            &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01],
+        ),
+        der(0x04, &basic),
+    ]
+    .concat();
+    der(
+        0x30,
+        &[der(0x0a, &[0]), der(0xa0, &der(0x30, &bytes))].concat(),
+    )
+}
+
+/// A directory of its own for each test, removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "tokio-uring-rustls-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]);
+        params.serial_number = Some(SERIAL.into());
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+        TempDir(dir)
+    }
+
+    fn write_response(&self, response: &[u8]) {
+        // Written aside and renamed, so the resolver never sees a partial file.
+        std::fs::write(self.0.join("cert.ocsp.tmp"), response).unwrap();
+        std::fs::rename(self.0.join("cert.ocsp.tmp"), self.0.join("cert.ocsp")).unwrap();
+    }
+
+    async fn resolver(&self) -> std::io::Result<OcspResolver> {
+        OcspResolver::new(
+            self.0.join("cert.pem"),
+            self.0.join("key.pem"),
+            self.0.join("cert.ocsp"),
+        )
+        .await
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+async fn next_event(received: &mut mpsc::UnboundedReceiver<OcspEvent>) -> OcspEvent {
+    timeout(Duration::from_secs(5), received.recv())
+        .await
+        .expect("no event")
+        .unwrap()
+}
+
+#[test]
+fn new_rejects_unusable_response() {
+    tokio_uring::start(async {
+        let dir = TempDir::new("ocsp-new");
+
+        dir.write_response(&response(&revoked(), "20991231000000Z"));
+        let err = dir.resolver().await.err().unwrap();
+        assert!(err.to_string().contains("Revoked"), "{}", err);
+
+        dir.write_response(&response(GOOD, "20200102000000Z"));
+        let err = dir.resolver().await.err().unwrap();
+        assert!(err.to_string().contains("nextUpdate"), "{}", err);
+
+        dir.write_response(&response(GOOD, "20991231000000Z"));
+        let resolver = dir.resolver().await.unwrap();
+        assert_eq!(resolver.response().status(), OcspCertStatus::Good);
+        assert!(resolver.is_stapled());
+    });
+}
+
+// A revoked or expired response on disk is reported, and the previous good one stays stapled.
+#[test]
+fn reload_keeps_good_response() {
+    tokio_uring::start(async {
+        let dir = TempDir::new("ocsp-reload");
+        dir.write_response(&response(GOOD, "20991231000000Z"));
+
+        let (events, mut received) = mpsc::unbounded_channel();
+        let resolver = Arc::new(
+            dir.resolver()
+                .await
+                .unwrap()
+                .interval(Duration::from_millis(20))
+                .on_event(move |event| {
+                    let _ = events.send(event);
+                }),
+        );
+        let good = resolver.response();
+        let run = {
+            let resolver = resolver.clone();
+            tokio_uring::spawn(async move { resolver.run().await })
+        };
+
+        for (status, next_update, expected) in [
+            (&revoked()[..], "20991231000000Z", "Revoked"),
+            (GOOD, "20200102000000Z", "nextUpdate"),
+        ] {
+            dir.write_response(&response(status, next_update));
+            match next_event(&mut received).await {
+                OcspEvent::Failed(e) => assert!(e.to_string().contains(expected), "{}", e),
+                event => panic!("unexpected event {:?}", event),
+            }
+            assert_eq!(resolver.response(), good);
+            assert!(resolver.is_stapled());
+        }
+
+        dir.write_response(&response(GOOD, "20981231000000Z"));
+        match next_event(&mut received).await {
+            OcspEvent::Updated(response) => assert_ne!(response, good),
+            event => panic!("unexpected event {:?}", event),
+        }
+        run.abort();
+    });
+}
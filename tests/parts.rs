@@ -0,0 +1,81 @@
+mod common;
+
+use common::Identity;
+use rustls::ServerConnection;
+use std::{os::fd::AsRawFd, path::PathBuf, thread, time::Duration};
+use tokio_uring_rustls::TlsStream;
+
+fn is_open(fd: i32) -> bool {
+    PathBuf::from(format!("/proc/self/fd/{}", fd)).exists()
+}
+
+// A stream taken apart on one runtime carries on on another thread's runtime, with the plaintext rustls had
+// buffered but not handed out yet. Only the duplicated descriptor lives on.
+#[test]
+fn parts_move_to_another_runtime() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (server, client) = common::socket_pair().await;
+        let original = server.as_raw_fd();
+
+        let connector = identity.connector();
+        let client = tokio_uring::spawn(async move {
+            connector
+                .connect("localhost".try_into().unwrap(), client)
+                .await
+                .unwrap()
+        });
+        let mut server = identity.acceptor().accept(server).await.unwrap();
+        let mut client = client.await.unwrap();
+
+        let (res, _) = client.write_all(b"hello world".to_vec()).await;
+        res.unwrap();
+        let (res, buf) = server.read(vec![0u8; 5]).await;
+        assert_eq!(&buf[..res.unwrap()], b"hello");
+
+        let parts = server.into_parts().unwrap();
+        let duplicate = parts.socket.as_raw_fd();
+        assert_ne!(duplicate, original);
+
+        // The runtime closes the original descriptor in the background.
+        let mut waited = 0;
+        while is_open(original) {
+            assert!(waited < 100, "the original descriptor was not closed");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            waited += 1;
+        }
+        assert!(is_open(duplicate));
+
+        // Sent ahead, so that the other thread finds it in the socket.
+        let (res, _) = client.write_all(b", more".to_vec()).await;
+        res.unwrap();
+
+        let other = thread::spawn(move || {
+            tokio_uring::start(async move {
+                let mut server = TlsStream::<ServerConnection>::from_parts(parts);
+                let mut data = Vec::new();
+                while data.len() < 12 {
+                    let (res, buf) = server.read(vec![0u8; 64]).await;
+                    let n = res.unwrap();
+                    assert_ne!(n, 0);
+                    data.extend_from_slice(&buf[..n]);
+                }
+                let (res, _) = server.write_all(b"from the other side".to_vec()).await;
+                res.unwrap();
+                (data, server.into_parts().unwrap())
+            })
+        });
+        let (data, parts) = other.join().unwrap();
+        assert_eq!(data, b" world, more");
+        assert_eq!(
+            parts.session.server_name(),
+            Some("localhost"),
+            "the session made it through"
+        );
+
+        assert_eq!(
+            common::read_exact(&mut client, 19).await,
+            b"from the other side"
+        );
+    });
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use common::TempDir;
+use std::{io, path::Path};
+use tokio_uring_rustls::{
+    pem::{self, PemError, PemErrorKind},
+    TlsAcceptor,
+};
+
+/// The `PemError` of a failed load, checking that its message names `path`.
+fn pem_error<'a>(err: &'a io::Error, path: &Path) -> &'a PemErrorKind {
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+    assert!(
+        err.to_string().contains(&path.display().to_string()),
+        "{}",
+        err
+    );
+    let err = err.get_ref().unwrap().downcast_ref::<PemError>().unwrap();
+    assert_eq!(err.path(), Some(path));
+    err.kind()
+}
+
+#[test]
+fn empty() {
+    tokio_uring::start(async {
+        let dir = TempDir::new("pem-empty");
+        let path = dir.0.join("cert.pem");
+        std::fs::write(&path, "\n").unwrap();
+
+        let err = pem::load_certs(&path).await.unwrap_err();
+        assert!(matches!(pem_error(&err, &path), PemErrorKind::Empty));
+    });
+}
+
+#[test]
+fn wrong_item() {
+    tokio_uring::start(async {
+        let dir = TempDir::new("pem-wrong-item");
+        let path = dir.0.join("cert.pem");
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(&path, cert.serialize_private_key_pem()).unwrap();
+
+        let err = pem::load_certs(&path).await.unwrap_err();
+        match pem_error(&err, &path) {
+            PemErrorKind::WrongItem { expected, found } => {
+                assert_eq!(*expected, "certificate");
+                assert_eq!(found, &["PKCS#8 private key"]);
+            }
+            kind => panic!("{:?}", kind),
+        }
+    });
+}
+
+#[test]
+fn malformed() {
+    tokio_uring::start(async {
+        let dir = TempDir::new("pem-malformed");
+        let path = dir.0.join("cert.pem");
+        std::fs::write(
+            &path,
+            "-----BEGIN CERTIFICATE-----\n!!!!\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+
+        let err = pem::load_certs(&path).await.unwrap_err();
+        assert!(matches!(pem_error(&err, &path), PemErrorKind::Malformed(_)));
+    });
+}
+
+#[test]
+fn unsupported_key() {
+    tokio_uring::start(async {
+        let dir = TempDir::new("pem-unsupported-key");
+        let path = dir.0.join("key.der");
+        // Without PEM markers, the file is taken as a DER encoded PKCS#8 key.
+        std::fs::write(&path, b"not a key").unwrap();
+
+        let err = pem::load_private_key(&path).await.unwrap_err();
+        assert!(matches!(
+            pem_error(&err, &path),
+            PemErrorKind::UnsupportedKey
+        ));
+    });
+}
+
+#[test]
+fn key_mismatch() {
+    tokio_uring::start(async {
+        let dir = TempDir::new("pem-key-mismatch");
+        let cert_path = dir.0.join("cert.pem");
+        let key_path = dir.0.join("key.pem");
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_path, other.serialize_private_key_pem()).unwrap();
+
+        let err = TlsAcceptor::from_pem_files(&cert_path, &key_path)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            pem_error(&err, &key_path),
+            PemErrorKind::KeyMismatch
+        ));
+
+        // The matching key loads.
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        TlsAcceptor::from_pem_files(&cert_path, &key_path)
+            .await
+            .unwrap();
+    });
+}
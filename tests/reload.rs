@@ -0,0 +1,96 @@
+mod common;
+
+use common::TempDir;
+use rustls::{Certificate, ClientConfig, RootCertStore};
+use std::sync::Arc;
+use tokio_uring_rustls::{
+    CertReloader, FileSessionStore, ReloadableTlsAcceptor, TlsAcceptor, TlsConnector,
+};
+
+/// Writes a new self signed certificate and key as PEM, returning the DER encoded certificate.
+fn write_cert(dir: &TempDir) -> Certificate {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    // Every serialization signs anew, the certificate is read back from what was written.
+    let pem = cert.serialize_pem().unwrap();
+    std::fs::write(dir.0.join("cert.pem"), &pem).unwrap();
+    std::fs::write(dir.0.join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+    Certificate(
+        rustls_pemfile::certs(&mut pem.as_bytes())
+            .unwrap()
+            .remove(0),
+    )
+}
+
+/// Connects through `acceptor`, returning the certificate the server presented.
+async fn served_cert(acceptor: &TlsAcceptor, roots: &[&Certificate]) -> Certificate {
+    let mut store = RootCertStore::empty();
+    for root in roots {
+        store.add(root).unwrap();
+    }
+    let connector = TlsConnector::from(Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(store)
+            .with_no_client_auth(),
+    ));
+
+    let (server, client) = common::socket_pair().await;
+    let client = tokio_uring::spawn(async move {
+        connector
+            .connect("localhost".try_into().unwrap(), client)
+            .await
+            .unwrap()
+    });
+    let _server = acceptor.accept(server).await.unwrap();
+    let parts = client.await.unwrap().into_parts().unwrap();
+    parts.session.peer_certificates().unwrap()[0].clone()
+}
+
+// The key is swapped inside the reloader, the config of the acceptor stays the same.
+#[test]
+fn cert_reloader_swaps_key_in_place() {
+    tokio_uring::start(async {
+        let dir = TempDir::new("cert-reloader");
+        let first = write_cert(&dir);
+
+        let reloader = Arc::new(
+            CertReloader::new(dir.0.join("cert.pem"), dir.0.join("key.pem"))
+                .await
+                .unwrap(),
+        );
+        let acceptor = common::Identity::new()
+            .acceptor()
+            .cert_reloader(reloader.clone());
+        let config = acceptor.config();
+        assert_eq!(served_cert(&acceptor, &[&first]).await, first);
+
+        let second = write_cert(&dir);
+        reloader.reload().await.unwrap();
+        assert!(Arc::ptr_eq(&config, &acceptor.config()));
+        assert_eq!(served_cert(&acceptor, &[&first, &second]).await, second);
+
+        // A broken file keeps the current certificate.
+        std::fs::write(dir.0.join("key.pem"), "not a key").unwrap();
+        assert!(reloader.reload().await.is_err());
+        assert_eq!(served_cert(&acceptor, &[&first, &second]).await, second);
+    });
+}
+
+// A config set on a reloadable acceptor picks up the settings of the acceptor it was created from.
+#[test]
+fn set_config_keeps_acceptor_settings() {
+    tokio_uring::start(async {
+        let dir = TempDir::new("set-config");
+        let identity = common::Identity::new();
+        let store = Arc::new(FileSessionStore::new(dir.0.join("sessions")));
+        let reloadable =
+            ReloadableTlsAcceptor::from(identity.acceptor().session_store(store.clone()));
+
+        let previous = reloadable.set_config(Arc::new(identity.server_config()));
+        assert!(!Arc::ptr_eq(&previous, &reloadable.config()));
+
+        // Without ticket keys, the TLS 1.3 tickets sent after the handshake are kept in the store.
+        served_cert(&reloadable.acceptor(), &[&identity.cert]).await;
+        assert!(!store.is_empty());
+    });
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use common::Identity;
+use rustls::{client::ClientSessionStore, NamedGroup, ServerName};
+use std::{sync::Arc, time::Duration};
+use tokio_uring_rustls::{ClientSessionCache, ResumptionStats, TlsAcceptor, TlsConnector};
+
+/// Runs a connection through `connector` and `acceptor`, reading a little data so TLS 1.3 tickets are taken in.
+async fn connect_once(connector: &TlsConnector, acceptor: &TlsAcceptor) {
+    let (server, client) = common::socket_pair().await;
+    let connector = connector.clone();
+    let client = tokio_uring::spawn(async move {
+        let mut client = connector
+            .connect("localhost".try_into().unwrap(), client)
+            .await
+            .unwrap();
+        common::read_exact(&mut client, 2).await
+    });
+    let mut server = acceptor.accept(server).await.unwrap();
+    let (res, _) = server.write_all(b"hi".to_vec()).await;
+    res.unwrap();
+    assert_eq!(client.await.unwrap(), b"hi");
+}
+
+fn name(host: &str) -> ServerName {
+    host.try_into().unwrap()
+}
+
+// The second connection to a server resumes the session of the first, and the stats tell them apart, with both
+// protocol versions.
+#[test]
+fn stats_tell_resumed_handshakes() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let acceptor = identity.acceptor();
+
+        for version in [&rustls::version::TLS13, &rustls::version::TLS12] {
+            let cache = Arc::new(ClientSessionCache::new());
+            let connector =
+                TlsConnector::from(Arc::new(identity.client_config_with_versions(&[version])))
+                    .session_cache(cache.clone());
+
+            connect_once(&connector, &acceptor).await;
+            connect_once(&connector, &acceptor).await;
+
+            assert_eq!(
+                cache.stats(),
+                ResumptionStats {
+                    offered: 1,
+                    resumed: 1,
+                    full: 1,
+                },
+                "{:?}",
+                version
+            );
+        }
+    });
+}
+
+// At capacity, the least recently used server name makes room for a new one, a lookup counting as a use.
+#[test]
+fn evicts_least_recently_used() {
+    let cache = ClientSessionCache::new().capacity(2);
+    cache.set_kx_hint(&name("a.example"), NamedGroup::X25519);
+    cache.set_kx_hint(&name("b.example"), NamedGroup::X25519);
+    assert_eq!(cache.kx_hint(&name("a.example")), Some(NamedGroup::X25519));
+
+    cache.set_kx_hint(&name("c.example"), NamedGroup::X25519);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.kx_hint(&name("b.example")), None);
+    assert_eq!(cache.kx_hint(&name("a.example")), Some(NamedGroup::X25519));
+    assert_eq!(cache.kx_hint(&name("c.example")), Some(NamedGroup::X25519));
+
+    // A lookup of an evicted name doesn't bring it back, nor push out another one.
+    assert_eq!(cache.len(), 2);
+}
+
+// A session older than the maximum age is not offered, with both protocol versions.
+#[test]
+fn sessions_expire_after_max_age() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let acceptor = identity.acceptor();
+
+        for version in [&rustls::version::TLS13, &rustls::version::TLS12] {
+            let cache = Arc::new(ClientSessionCache::new().max_age(Duration::from_millis(100)));
+            let connector =
+                TlsConnector::from(Arc::new(identity.client_config_with_versions(&[version])))
+                    .session_cache(cache.clone());
+
+            connect_once(&connector, &acceptor).await;
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            connect_once(&connector, &acceptor).await;
+
+            assert_eq!(
+                cache.stats(),
+                ResumptionStats {
+                    offered: 0,
+                    resumed: 0,
+                    full: 2,
+                },
+                "{:?}",
+                version
+            );
+        }
+    });
+}
+
+// A session that is offered but that the server doesn't know counts as offered and full, not resumed.
+#[test]
+fn stats_count_offers_the_server_turns_down() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let cache = Arc::new(ClientSessionCache::new());
+        let connector = identity.connector().session_cache(cache.clone());
+
+        connect_once(&connector, &identity.acceptor()).await;
+        // A new acceptor has new ticket keys and an empty session store.
+        connect_once(&connector, &identity.acceptor()).await;
+        connect_once(&connector, &identity.acceptor()).await;
+
+        assert_eq!(
+            cache.stats(),
+            ResumptionStats {
+                offered: 2,
+                resumed: 0,
+                full: 3,
+            }
+        );
+    });
+}
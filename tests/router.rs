@@ -0,0 +1,192 @@
+mod common;
+
+use common::Identity;
+use rustls::ClientConnection;
+use std::{io, net::SocketAddr, sync::Arc};
+use tokio_uring::net::TcpStream;
+use tokio_uring_rustls::{PeekedClientHello, Route, SniRouter, TlsConnector};
+
+fn backend(port: u16) -> Route {
+    Route::Passthrough(SocketAddr::from(([127, 0, 0, 1], port)))
+}
+
+/// The port of the backend `name` is routed to, if any.
+fn routed_to(router: &SniRouter, name: Option<&str>) -> Option<u16> {
+    match router.find(name) {
+        Some(Route::Passthrough(addr)) => Some(addr.port()),
+        Some(Route::Terminate(_)) => panic!("unexpected terminate route for {:?}", name),
+        None => None,
+    }
+}
+
+// A wildcard covers a single level of names below its parent, and an exact route takes precedence over it.
+#[test]
+fn wildcard_matches_one_level() {
+    let router = SniRouter::new()
+        .route("*.example.com", backend(1))
+        .route("www.example.com", backend(2))
+        .route("Example.COM", backend(3));
+
+    assert_eq!(routed_to(&router, Some("a.example.com")), Some(1));
+    assert_eq!(routed_to(&router, Some("A.EXAMPLE.com")), Some(1));
+    assert_eq!(routed_to(&router, Some("www.example.com")), Some(2));
+    assert_eq!(routed_to(&router, Some("example.com")), Some(3));
+    assert_eq!(routed_to(&router, Some("a.b.example.com")), None);
+    assert_eq!(routed_to(&router, Some("example.org")), None);
+    assert_eq!(routed_to(&router, Some("aexample.com")), None);
+}
+
+// Connections without SNI, or with a name that has no route, take the fallback.
+#[test]
+fn fallback_for_missing_or_unknown_name() {
+    let router = SniRouter::new().route("*.example.com", backend(1));
+    assert_eq!(routed_to(&router, None), None);
+    assert_eq!(routed_to(&router, Some("a.b.example.com")), None);
+
+    let router = router.fallback(backend(9));
+    assert_eq!(routed_to(&router, None), Some(9));
+    assert_eq!(routed_to(&router, Some("a.b.example.com")), Some(9));
+    assert_eq!(routed_to(&router, Some("unknown.test")), Some(9));
+    assert_eq!(routed_to(&router, Some("a.example.com")), Some(1));
+}
+
+// End to end, a client that sends no SNI is terminated by the fallback, and one with an unknown name is
+// rejected when there is no fallback.
+#[test]
+fn accept_takes_fallback_or_rejects() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let router = SniRouter::new()
+            .route("*.example.com", backend(1))
+            .fallback(Route::Terminate(identity.acceptor()));
+
+        let mut config = identity.client_config();
+        config.enable_sni = false;
+        let (server, client) = common::socket_pair().await;
+        let client = tokio_uring::spawn(async move {
+            let mut client = TlsConnector::from(Arc::new(config))
+                .connect("localhost".try_into().unwrap(), client)
+                .await
+                .unwrap();
+            common::read_exact(&mut client, 2).await
+        });
+        let mut stream = router.accept(server).await.unwrap().unwrap();
+        let (res, _) = stream.write_all(b"hi".to_vec()).await;
+        res.unwrap();
+        assert_eq!(client.await.unwrap(), b"hi");
+
+        let router = SniRouter::new().route("*.example.com", backend(1));
+        let (server, client) = common::socket_pair().await;
+        let connector = identity.connector();
+        let client = tokio_uring::spawn(async move {
+            let _ = connector
+                .connect("unknown.test".try_into().unwrap(), client)
+                .await;
+        });
+        let err = router.accept(server).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "no route for server name unknown.test");
+        client.await.unwrap();
+    });
+}
+
+// Terminating runs the handshake from the bytes read while peeking, the client sees a normal connection.
+#[test]
+fn terminate_completes_handshake_from_peeked_bytes() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (server, client) = common::socket_pair().await;
+
+        let mut config = identity.client_config();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let client = tokio_uring::spawn(async move {
+            let mut client = TlsConnector::from(Arc::new(config))
+                .connect("localhost".try_into().unwrap(), client)
+                .await
+                .unwrap();
+            let (res, _) = client.write_all(b"ping".to_vec()).await;
+            res.unwrap();
+            common::read_exact(&mut client, 4).await
+        });
+
+        let hello = PeekedClientHello::read(server).await.unwrap();
+        assert_eq!(hello.server_name(), Some("localhost"));
+        assert_eq!(
+            hello.alpn().collect::<Vec<_>>(),
+            [&b"h2"[..], &b"http/1.1"[..]]
+        );
+        assert_eq!(hello.peeked()[0], 0x16);
+
+        let mut stream = hello.terminate(&identity.acceptor()).await.unwrap();
+        let (res, buf) = stream.read(vec![0u8; 64]).await;
+        assert_eq!(&buf[..res.unwrap()], b"ping");
+        let (res, _) = stream.write_all(b"pong".to_vec()).await;
+        res.unwrap();
+        assert_eq!(client.await.unwrap(), b"pong");
+    });
+}
+
+/// Reads from `socket` until the peer closes its side.
+async fn read_to_end(socket: &TcpStream) -> Vec<u8> {
+    let mut data = Vec::new();
+    loop {
+        let (res, buf) = socket.read(vec![0u8; 4096]).await;
+        match res.unwrap() {
+            0 => return data,
+            n => data.extend_from_slice(&buf[..n]),
+        }
+    }
+}
+
+// Passing a connection through sends the backend exactly the bytes the client sent, starting with the recorded
+// ClientHello, and relays the backend's answer.
+#[test]
+fn splice_replays_client_hello() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+
+        // The ClientHello the client sends, produced up front so that it is known byte for byte.
+        let mut conn = ClientConnection::new(
+            Arc::new(identity.client_config()),
+            "localhost".try_into().unwrap(),
+        )
+        .unwrap();
+        let mut sent = Vec::new();
+        conn.write_tls(&mut sent).unwrap();
+        let client_hello = sent.clone();
+        sent.extend_from_slice(b"more from the client");
+
+        let (backend_listener, backend_addr) = common::listener();
+        let backend = tokio_uring::spawn(async move {
+            let (socket, _) = backend_listener.accept().await.unwrap();
+            let received = read_to_end(&socket).await;
+            let (res, _) = socket.write_all(b"from the backend".to_vec()).await;
+            res.unwrap();
+            received
+        });
+
+        let (server, client) = common::socket_pair().await;
+        let client = {
+            let sent = sent.clone();
+            tokio_uring::spawn(async move {
+                let (res, _) = client.write_all(sent).await;
+                res.unwrap();
+                client.shutdown(std::net::Shutdown::Write).unwrap();
+                read_to_end(&client).await
+            })
+        };
+
+        let hello = PeekedClientHello::read(server).await.unwrap();
+        assert_eq!(hello.server_name(), Some("localhost"));
+        assert!(sent.starts_with(hello.peeked()));
+        assert!(hello.peeked().starts_with(&client_hello));
+
+        let upstream = TcpStream::connect(backend_addr).await.unwrap();
+        let (up, down) = hello.splice(&upstream).await.unwrap();
+        assert_eq!(up, sent.len() as u64);
+        assert_eq!(down, 16);
+
+        assert_eq!(backend.await.unwrap(), sent);
+        assert_eq!(client.await.unwrap(), b"from the backend");
+    });
+}
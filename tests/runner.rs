@@ -0,0 +1,35 @@
+mod common;
+
+use common::Identity;
+use std::{collections::HashSet, io::Read};
+use tokio_uring_rustls::TlsServer;
+
+// Every thread binds the same port, which only works with SO_REUSEPORT set on the listeners, and the kernel
+// spreads the connections across them.
+#[test]
+fn threads_share_the_port() {
+    let identity = Identity::new();
+    let server = TlsServer::new("127.0.0.1:0".parse().unwrap(), identity.acceptor())
+        .threads(4)
+        .start(|mut stream, _| async move {
+            let name = std::thread::current().name().unwrap().to_string();
+            let (res, _) = stream.write_all(name.into_bytes()).await;
+            res.unwrap();
+            stream.shutdown().await.unwrap();
+        })
+        .unwrap();
+
+    let mut served_by = HashSet::new();
+    for _ in 0..32 {
+        let mut client = common::blocking_client(server.local_addr(), identity.client_config());
+        let mut name = String::new();
+        client.read_to_string(&mut name).unwrap();
+        served_by.insert(name);
+    }
+
+    assert!(served_by.len() > 1, "served by {:?}", served_by);
+    assert!(served_by.iter().all(|name| name.starts_with("tls-server-")));
+
+    server.shutdown();
+    server.join();
+}
@@ -0,0 +1,108 @@
+mod common;
+
+use common::TempDir;
+use rustls::server::StoresServerSessions;
+use std::io;
+use tokio_uring_rustls::FileSessionStore;
+
+// Sessions saved by one store are loaded by another one sharing the file.
+#[test]
+fn save_and_load_round_trip() {
+    tokio_uring::start(async {
+        let dir = TempDir::new("session-store-round-trip");
+        let path = dir.0.join("sessions");
+
+        let first = FileSessionStore::new(&path);
+        // A missing file is an empty one.
+        first.load().await.unwrap();
+        assert!(first.put(b"one".to_vec(), b"first session".to_vec()));
+        assert!(first.put(b"two".to_vec(), vec![0; 70000]));
+        first.save().await.unwrap();
+
+        let second = FileSessionStore::new(&path);
+        second.load().await.unwrap();
+        assert_eq!(second.len(), 2);
+        assert_eq!(second.get(b"one").unwrap(), b"first session");
+        assert_eq!(second.get(b"two").unwrap(), vec![0; 70000]);
+        assert_eq!(second.get(b"three"), None);
+    });
+}
+
+// A session taken, i.e. used up by a resumption, stays gone: reloading the file before the next save doesn't
+// bring it back, and stores loading the file after that save don't see it.
+#[test]
+fn taken_sessions_stay_taken() {
+    tokio_uring::start(async {
+        let dir = TempDir::new("session-store-taken");
+        let path = dir.0.join("sessions");
+
+        let first = FileSessionStore::new(&path);
+        first.put(b"one".to_vec(), b"1".to_vec());
+        first.put(b"two".to_vec(), b"2".to_vec());
+        first.save().await.unwrap();
+
+        let second = FileSessionStore::new(&path);
+        second.load().await.unwrap();
+        assert_eq!(second.take(b"one").unwrap(), b"1");
+        second.load().await.unwrap();
+        assert_eq!(second.get(b"one"), None);
+        second.save().await.unwrap();
+
+        let third = FileSessionStore::new(&path);
+        third.load().await.unwrap();
+        assert_eq!(third.len(), 1);
+        assert_eq!(third.get(b"one"), None);
+        assert_eq!(third.get(b"two").unwrap(), b"2");
+
+        // Once saved, putting the session again is allowed to bring it back.
+        second.put(b"one".to_vec(), b"1 again".to_vec());
+        second.save().await.unwrap();
+        third.load().await.unwrap();
+        assert_eq!(third.get(b"one").unwrap(), b"1 again");
+    });
+}
+
+// A file that isn't a session file, or was cut short, is rejected without touching the sessions in memory.
+#[test]
+fn invalid_files_are_rejected() {
+    tokio_uring::start(async {
+        let dir = TempDir::new("session-store-invalid");
+        let path = dir.0.join("sessions");
+
+        let store = FileSessionStore::new(&path);
+        store.put(b"one".to_vec(), b"first session".to_vec());
+        store.save().await.unwrap();
+        let saved = std::fs::read(&path).unwrap();
+
+        let mut bad_magic = saved.clone();
+        bad_magic[0] = b'X';
+        let mut bad_format = saved.clone();
+        bad_format[4] = 2;
+        let mut files = vec![bad_magic, bad_format, Vec::new()];
+        // Every cut but the one right after the header, which is a file without sessions.
+        files.extend(
+            (1..saved.len())
+                .filter(|&n| n != 5)
+                .map(|n| saved[..n].to_vec()),
+        );
+
+        for data in files {
+            std::fs::write(&path, &data).unwrap();
+            let other = FileSessionStore::new(&path);
+            other.put(b"mine".to_vec(), b"kept".to_vec());
+            let err = other.load().await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", data);
+            assert_eq!(
+                err.to_string(),
+                format!("{}: invalid session file", path.display())
+            );
+            assert_eq!(other.len(), 1);
+            assert_eq!(other.get(b"mine").unwrap(), b"kept");
+        }
+
+        std::fs::write(&path, &saved[..5]).unwrap();
+        let other = FileSessionStore::new(&path);
+        other.load().await.unwrap();
+        assert!(other.is_empty());
+    });
+}
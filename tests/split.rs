@@ -0,0 +1,275 @@
+mod common;
+
+use common::Identity;
+use std::{io::Read, thread, time::Duration};
+use tokio::time::timeout;
+use tokio_uring_rustls::{split, ReuniteError};
+
+const CHUNK: usize = 16 * 1024;
+
+/// Writes `chunks` chunks through `write`, each filled with its index.
+macro_rules! write_chunks {
+    ($half:expr, $chunks:expr) => {
+        for i in 0..$chunks {
+            let (res, _) = $half.write_all(vec![i as u8; CHUNK]).await;
+            res.unwrap();
+        }
+    };
+}
+
+/// Reads from `half` until `n` bytes have been read, checking that every chunk holds its index.
+macro_rules! read_chunks {
+    ($half:expr, $n:expr) => {{
+        let mut read = 0;
+        while read < $n {
+            let (res, buf) = $half.read(vec![0u8; CHUNK]).await;
+            let len = res.unwrap();
+            assert_ne!(len, 0, "unexpected eof after {} bytes", read);
+            for (i, byte) in buf[..len].iter().enumerate() {
+                assert_eq!(*byte, ((read + i) / CHUNK) as u8);
+            }
+            read += len;
+        }
+        read
+    }};
+}
+
+// Both ends write far more than the socket buffers can hold before reading anything. This only completes if the
+// reads and writes of each end make progress at the same time.
+#[test]
+fn concurrent_read_and_write() {
+    const CHUNKS: usize = 1024;
+
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (server, client) = common::stream_pair(&identity).await;
+        let (mut server_read, mut server_write) = split(server);
+        let (mut client_read, mut client_write) = split(client);
+
+        let tasks = [
+            tokio_uring::spawn(async move {
+                write_chunks!(server_write, CHUNKS);
+                0
+            }),
+            tokio_uring::spawn(async move {
+                write_chunks!(client_write, CHUNKS);
+                0
+            }),
+            tokio_uring::spawn(async move { read_chunks!(server_read, CHUNKS * CHUNK) }),
+            tokio_uring::spawn(async move { read_chunks!(client_read, CHUNKS * CHUNK) }),
+        ];
+
+        for task in tasks {
+            let read = timeout(Duration::from_secs(30), task)
+                .await
+                .expect("split stream deadlocked")
+                .unwrap();
+            assert!(read == 0 || read == CHUNKS * CHUNK);
+        }
+    });
+}
+
+// The read half fails on a corrupted record while the write half is stuck in a write, as the peer isn't reading.
+// The alert rustls queues in response can't be flushed by the read half then, the write half must send it along
+// with its own records rather than lose it or deadlock.
+#[test]
+fn read_path_flush_races_pending_write() {
+    const CHUNKS: usize = 2048;
+
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (listener, addr) = common::listener();
+
+        let config = identity.client_config();
+        let peer = thread::spawn(move || {
+            let mut client = common::blocking_client(addr, config);
+            while client.conn.is_handshaking() {
+                client.conn.complete_io(&mut client.sock).unwrap();
+            }
+            // Let the server's writes back up, then send an application data record that can't be decrypted.
+            thread::sleep(Duration::from_millis(200));
+            let mut record = vec![23, 3, 3, 0, 64];
+            record.extend_from_slice(&[0x55; 64]);
+            std::io::Write::write_all(&mut client.sock, &record).unwrap();
+
+            thread::sleep(Duration::from_millis(300));
+            let mut data = Vec::new();
+            let err = client.read_to_end(&mut data).unwrap_err();
+            (data.len(), err)
+        });
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let stream = identity.acceptor().accept(socket).await.unwrap();
+        let (mut read_half, mut write_half) = split(stream);
+
+        // Records written after the alert make the peer reset the connection, the writer stops there.
+        let writer = tokio_uring::spawn(async move {
+            for i in 0..CHUNKS {
+                let (res, _) = write_half.write_all(vec![i as u8; CHUNK]).await;
+                if res.is_err() {
+                    break;
+                }
+            }
+            write_half
+        });
+
+        let (res, _) = read_half.read(vec![0u8; CHUNK]).await;
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+        let write_half = timeout(Duration::from_secs(30), writer)
+            .await
+            .expect("write half never finished")
+            .unwrap();
+        assert!(read_half.is_pair_of(&write_half));
+        drop((read_half, write_half));
+
+        let (read, err) = peer.join().unwrap();
+        assert!(read > 0, "the write half was not stuck in a write");
+        let err = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<rustls::Error>())
+            .unwrap();
+        assert!(
+            matches!(
+                err,
+                rustls::Error::AlertReceived(rustls::AlertDescription::BadRecordMac)
+            ),
+            "{:?}",
+            err
+        );
+    });
+}
+
+// The socket stays open until both halves are gone, whichever goes first, and a half can still be used after
+// the other one was dropped.
+#[test]
+fn drop_order() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+
+        for write_first in [true, false] {
+            let (server, mut client) = common::stream_pair(&identity).await;
+            let (mut read_half, mut write_half) = split(server);
+
+            if write_first {
+                drop(write_half);
+
+                let (res, _) = client.write_all(b"still open".to_vec()).await;
+                res.unwrap();
+                let (res, buf) = read_half.read(vec![0u8; 64]).await;
+                assert_eq!(&buf[..res.unwrap()], b"still open");
+
+                drop(read_half);
+            } else {
+                drop(read_half);
+
+                let (res, _) = write_half.write_all(b"still open".to_vec()).await;
+                res.unwrap();
+                assert_eq!(common::read_exact(&mut client, 10).await, b"still open");
+
+                drop(write_half);
+            }
+
+            // Neither half sent a close_notify, the peer sees the socket close.
+            let (res, _) = client.read(vec![0u8; 64]).await;
+            assert_eq!(
+                res.unwrap_err().kind(),
+                std::io::ErrorKind::UnexpectedEof,
+                "write_first: {}",
+                write_first
+            );
+        }
+    });
+}
+
+// Cancelling a read that is in flight leaves the write half usable.
+#[test]
+fn drop_with_read_in_flight() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (server, mut client) = common::stream_pair(&identity).await;
+        let (mut read_half, mut write_half) = split(server);
+
+        let reader = tokio_uring::spawn(async move {
+            let _ = read_half.read(vec![0u8; 64]).await;
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        reader.abort();
+        assert!(reader.await.unwrap_err().is_cancelled());
+
+        let (res, _) = write_half.write_all(b"after cancel".to_vec()).await;
+        res.unwrap();
+        assert_eq!(common::read_exact(&mut client, 12).await, b"after cancel");
+    });
+}
+
+#[test]
+fn halves_are_debug() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (server, _client) = common::stream_pair(&identity).await;
+        let (read_half, write_half) = split(server);
+        assert!(format!("{:?}", read_half).starts_with("ReadHalf"));
+        assert!(format!("{:?}", write_half).starts_with("WriteHalf"));
+    });
+}
+
+// A reunited stream picks up where the halves left off, including plaintext the read half has not handed out
+// yet.
+#[test]
+fn reunite_keeps_the_stream_usable() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (server, mut client) = common::stream_pair(&identity).await;
+        let (mut read_half, mut write_half) = split(server);
+
+        let (res, _) = client.write_all(b"before reunite".to_vec()).await;
+        res.unwrap();
+        let (res, buf) = read_half.read(vec![0u8; 6]).await;
+        assert_eq!(&buf[..res.unwrap()], b"before");
+        let (res, _) = write_half.write_all(b"from the half".to_vec()).await;
+        res.unwrap();
+        assert_eq!(common::read_exact(&mut client, 13).await, b"from the half");
+
+        let mut server = read_half.reunite(write_half).unwrap();
+
+        let (res, buf) = server.read(vec![0u8; 64]).await;
+        assert_eq!(&buf[..res.unwrap()], b" reunite");
+        let (res, _) = server.write_all(b"reunited".to_vec()).await;
+        res.unwrap();
+        assert_eq!(common::read_exact(&mut client, 8).await, b"reunited");
+    });
+}
+
+// Halves of two different streams are handed back untouched, each still working with its own stream.
+#[test]
+fn reunite_rejects_halves_of_different_streams() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (server_a, mut client_a) = common::stream_pair(&identity).await;
+        let (server_b, mut client_b) = common::stream_pair(&identity).await;
+        let (read_a, write_a) = split(server_a);
+        let (read_b, write_b) = split(server_b);
+
+        assert!(!read_a.is_pair_of(&write_b));
+        let err = read_a.reunite(write_b).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "tried to reunite halves that are not from the same stream"
+        );
+        let ReuniteError(mut read_a, mut write_b) = err;
+
+        let (res, _) = client_a.write_all(b"to a".to_vec()).await;
+        res.unwrap();
+        let (res, buf) = read_a.read(vec![0u8; 64]).await;
+        assert_eq!(&buf[..res.unwrap()], b"to a");
+        let (res, _) = write_b.write_all(b"from b".to_vec()).await;
+        res.unwrap();
+        assert_eq!(common::read_exact(&mut client_b, 6).await, b"from b");
+
+        // The same goes the other way around, and the right pairs still reunite.
+        let ReuniteError(read_b, write_a) = write_a.reunite(read_b).err().unwrap();
+        read_a.reunite(write_a).unwrap();
+        write_b.reunite(read_b).unwrap();
+    });
+}
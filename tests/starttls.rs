@@ -0,0 +1,319 @@
+mod common;
+
+use common::Identity;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    sync::Arc,
+    thread,
+};
+use tokio_uring_rustls::{starttls, TlsStream};
+
+/// Reads a line off the socket one byte at a time, so that nothing after it is consumed.
+fn read_line(socket: &mut TcpStream) -> String {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    while !line.ends_with(b"\r\n") {
+        socket.read_exact(&mut byte).unwrap();
+        line.push(byte[0]);
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).unwrap()
+}
+
+fn expect_line(socket: &mut TcpStream, expected: &str) {
+    assert_eq!(read_line(socket), expected);
+}
+
+/// Runs a mock server on its own thread for one connection. `script` drives the plaintext exchange and returns
+/// whether it agreed to the upgrade, in which case the server completes the handshake and answers `ping` with
+/// `pong`.
+fn mock_server(
+    config: Arc<ServerConfig>,
+    script: impl FnOnce(&mut TcpStream) -> bool + Send + 'static,
+) -> (std::net::SocketAddr, thread::JoinHandle<()>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        if !script(&mut socket) {
+            return;
+        }
+        let mut tls = StreamOwned::new(ServerConnection::new(config).unwrap(), socket);
+        let mut ping = [0u8; 4];
+        tls.read_exact(&mut ping).unwrap();
+        assert_eq!(&ping, b"ping");
+        tls.write_all(b"pong").unwrap();
+    });
+    (addr, server)
+}
+
+/// Connects to `addr` with the negotiator `connect`, and exchanges `ping` and `pong` over the upgraded stream.
+macro_rules! upgrade {
+    ($identity:expr, $addr:expr, |$connector:ident, $name:ident, $socket:ident| $connect:expr) => {{
+        let $connector = $identity.connector();
+        let $name: rustls::ServerName = "localhost".try_into().unwrap();
+        let $socket = tokio_uring::net::TcpStream::connect($addr).await.unwrap();
+        let result: io::Result<TlsStream<rustls::ClientConnection>> = $connect.await;
+        match result {
+            Ok(mut stream) => {
+                let (res, _) = stream.write_all(b"ping".to_vec()).await;
+                res.unwrap();
+                assert_eq!(common::read_exact(&mut stream, 4).await, b"pong");
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }};
+}
+
+fn assert_refused(result: io::Result<()>, protocol: &str) {
+    let err = result.unwrap_err();
+    let expected = format!("{} server refused starttls", protocol);
+    assert!(err.to_string().starts_with(&expected), "{}", err);
+}
+
+#[test]
+fn smtp() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+
+        for agree in [true, false] {
+            let (addr, server) = mock_server(Arc::new(identity.server_config()), move |s| {
+                s.write_all(b"220 mx.example.org ESMTP\r\n").unwrap();
+                expect_line(s, "EHLO client.example.org");
+                s.write_all(b"250-mx.example.org\r\n250-PIPELINING\r\n250 STARTTLS\r\n")
+                    .unwrap();
+                expect_line(s, "STARTTLS");
+                if agree {
+                    s.write_all(b"220 2.0.0 Ready to start TLS\r\n").unwrap();
+                } else {
+                    s.write_all(b"454 4.7.0 TLS not available\r\n").unwrap();
+                }
+                agree
+            });
+
+            let result = upgrade!(identity, addr, |connector, name, socket| {
+                starttls::smtp::connect(&connector, name, socket, "client.example.org")
+            });
+            if agree {
+                result.unwrap();
+            } else {
+                assert_refused(result, "smtp");
+            }
+            server.join().unwrap();
+        }
+    });
+}
+
+#[test]
+fn smtp_without_starttls_capability() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let (addr, server) = mock_server(Arc::new(identity.server_config()), |s| {
+            s.write_all(b"220 mx.example.org ESMTP\r\n").unwrap();
+            expect_line(s, "EHLO client.example.org");
+            s.write_all(b"250-mx.example.org\r\n250 8BITMIME\r\n")
+                .unwrap();
+            false
+        });
+
+        let result = upgrade!(identity, addr, |connector, name, socket| {
+            starttls::smtp::connect(&connector, name, socket, "client.example.org")
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Unsupported);
+        server.join().unwrap();
+    });
+}
+
+#[test]
+fn imap() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+
+        for agree in [true, false] {
+            let (addr, server) = mock_server(Arc::new(identity.server_config()), move |s| {
+                s.write_all(b"* OK [CAPABILITY IMAP4rev1 STARTTLS] ready\r\n")
+                    .unwrap();
+                expect_line(s, "a001 STARTTLS");
+                if agree {
+                    s.write_all(b"* CAPABILITY IMAP4rev1 STARTTLS\r\na001 OK Begin TLS\r\n")
+                        .unwrap();
+                } else {
+                    s.write_all(b"a001 NO TLS not available\r\n").unwrap();
+                }
+                agree
+            });
+
+            let result = upgrade!(identity, addr, |connector, name, socket| {
+                starttls::imap::connect(&connector, name, socket)
+            });
+            if agree {
+                result.unwrap();
+            } else {
+                assert_refused(result, "imap");
+            }
+            server.join().unwrap();
+        }
+    });
+}
+
+#[test]
+fn pop3() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+
+        for agree in [true, false] {
+            let (addr, server) = mock_server(Arc::new(identity.server_config()), move |s| {
+                s.write_all(b"+OK POP3 ready\r\n").unwrap();
+                expect_line(s, "STLS");
+                if agree {
+                    s.write_all(b"+OK Begin TLS\r\n").unwrap();
+                } else {
+                    s.write_all(b"-ERR TLS not available\r\n").unwrap();
+                }
+                agree
+            });
+
+            let result = upgrade!(identity, addr, |connector, name, socket| {
+                starttls::pop3::connect(&connector, name, socket)
+            });
+            if agree {
+                result.unwrap();
+            } else {
+                assert_refused(result, "pop3");
+            }
+            server.join().unwrap();
+        }
+    });
+}
+
+#[test]
+fn postgres() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+
+        for agree in [true, false] {
+            let (addr, server) = mock_server(Arc::new(identity.server_config()), move |s| {
+                let mut request = [0u8; 8];
+                s.read_exact(&mut request).unwrap();
+                assert_eq!(request, [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f]);
+                s.write_all(if agree { b"S" } else { b"N" }).unwrap();
+                agree
+            });
+
+            let result = upgrade!(identity, addr, |connector, name, socket| {
+                starttls::postgres::connect(&connector, name, socket)
+            });
+            if agree {
+                result.unwrap();
+            } else {
+                assert_refused(result, "postgres");
+            }
+            server.join().unwrap();
+        }
+    });
+}
+
+/// Appends a BER element with a short definite length.
+fn ber(out: &mut Vec<u8>, tag: u8, content: &[u8]) {
+    out.push(tag);
+    out.push(content.len() as u8);
+    out.extend_from_slice(content);
+}
+
+#[test]
+fn ldap() {
+    const OID: &[u8] = b"1.3.6.1.4.1.1466.20037";
+
+    tokio_uring::start(async {
+        let identity = Identity::new();
+
+        for agree in [true, false] {
+            let (addr, server) = mock_server(Arc::new(identity.server_config()), move |s| {
+                let mut expected = Vec::new();
+                ber(&mut expected, 0x80, OID);
+                let mut msg = vec![0x02, 0x01, 0x01];
+                ber(&mut msg, 0x77, &expected);
+                let mut request = Vec::new();
+                ber(&mut request, 0x30, &msg);
+
+                let mut received = vec![0u8; request.len()];
+                s.read_exact(&mut received).unwrap();
+                assert_eq!(received, request);
+
+                let mut op = Vec::new();
+                if agree {
+                    ber(&mut op, 0x0a, &[0]);
+                    ber(&mut op, 0x04, b"");
+                    ber(&mut op, 0x04, b"");
+                    ber(&mut op, 0x8a, OID);
+                } else {
+                    ber(&mut op, 0x0a, &[2]);
+                    ber(&mut op, 0x04, b"");
+                    ber(&mut op, 0x04, b"TLS not available");
+                }
+                let mut msg = vec![0x02, 0x01, 0x01];
+                ber(&mut msg, 0x78, &op);
+                let mut response = Vec::new();
+                ber(&mut response, 0x30, &msg);
+                s.write_all(&response).unwrap();
+                agree
+            });
+
+            let result = upgrade!(identity, addr, |connector, name, socket| {
+                starttls::ldap::connect(&connector, name, socket)
+            });
+            if agree {
+                result.unwrap();
+            } else {
+                let err = result.unwrap_err().to_string();
+                assert!(err.contains("result code 2 TLS not available"), "{}", err);
+            }
+            server.join().unwrap();
+        }
+    });
+}
+
+// The server side of every protocol upgrades a connection from its client side.
+#[test]
+fn accept_from_connect() {
+    tokio_uring::start(async {
+        let identity = Identity::new();
+        let acceptor = identity.acceptor();
+
+        for protocol in ["smtp", "imap", "pop3", "postgres", "ldap"] {
+            let (server, client) = common::socket_pair().await;
+            let connector = identity.connector();
+            let client = tokio_uring::spawn(async move {
+                let name: rustls::ServerName = "localhost".try_into().unwrap();
+                let mut stream = match protocol {
+                    "smtp" => starttls::smtp::connect(&connector, name, client, "client").await,
+                    "imap" => starttls::imap::connect(&connector, name, client).await,
+                    "pop3" => starttls::pop3::connect(&connector, name, client).await,
+                    "postgres" => starttls::postgres::connect(&connector, name, client).await,
+                    _ => starttls::ldap::connect(&connector, name, client).await,
+                }
+                .unwrap();
+                let (res, _) = stream.write_all(b"ping".to_vec()).await;
+                res.unwrap();
+                // Closing with the session tickets of the server unread would reset the connection.
+                stream
+            });
+
+            let mut stream = match protocol {
+                "smtp" => starttls::smtp::accept(&acceptor, server, "localhost").await,
+                "imap" => starttls::imap::accept(&acceptor, server).await,
+                "pop3" => starttls::pop3::accept(&acceptor, server).await,
+                "postgres" => starttls::postgres::accept(&acceptor, server).await,
+                _ => starttls::ldap::accept(&acceptor, server).await,
+            }
+            .unwrap();
+            let _client = client.await.unwrap();
+
+            let (res, buf) = stream.read(vec![0u8; 16]).await;
+            assert_eq!(&buf[..res.unwrap()], b"ping", "{}", protocol);
+        }
+    });
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use common::TempDir;
+use rustls::server::ProducesTickets;
+use std::time::Duration;
+use tokio_uring_rustls::TicketKeys;
+
+#[test]
+fn loaded_keys_retire_in_file_order() {
+    tokio_uring::start(async {
+        let dir = TempDir::new("ticket-keys-retire");
+        let path = dir.0.join("keys");
+        let lifetime = Duration::from_millis(300);
+
+        let keys = TicketKeys::new().unwrap().lifetime(lifetime);
+        let ticket = keys.encrypt(b"session").unwrap();
+        keys.rotate().unwrap();
+        keys.save(&path).await.unwrap();
+
+        let loaded = TicketKeys::new().unwrap().lifetime(lifetime);
+        loaded.load(&path).await.unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.decrypt(&ticket).as_deref(), Some(&b"session"[..]));
+
+        // The second key of the file was rotated out, it expires a lifetime after the load.
+        tokio::time::sleep(lifetime + Duration::from_millis(100)).await;
+        assert_eq!(loaded.decrypt(&ticket), None);
+        loaded.rotate().unwrap();
+        assert_eq!(loaded.len(), 2);
+    });
+}
+
+#[test]
+fn concurrent_saves_dont_collide() {
+    tokio_uring::start(async {
+        let dir = TempDir::new("ticket-keys-save");
+        let path = dir.0.join("keys");
+
+        let saves: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                tokio_uring::spawn(async move {
+                    let keys = TicketKeys::new().unwrap();
+                    keys.save(&path).await
+                })
+            })
+            .collect();
+        for save in saves {
+            save.await.unwrap().unwrap();
+        }
+
+        // Only the key file is left, every temporary file was renamed over it.
+        let entries: Vec<_> = std::fs::read_dir(&dir.0).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(std::fs::read(&path).unwrap().len(), 48);
+    });
+}